@@ -7,9 +7,40 @@ use ra_ssr::{MatchFinder, SsrError, SsrRule};
 // Feature: Structural Seach and Replace
 //
 // Search and replace with named wildcards that will match any expression, type, path, pattern or item.
+// A search pattern ending in `;` matches a whole statement, while one without only matches the
+// expression, leaving any semicolon in place.
+// A bare name such as `None` matches both where it's used as a value and where it's used in a pattern.
 // The syntax for a structural search replace command is `<search_pattern> ==>> <replace_pattern>`.
+// The `==>>` needs whitespace on either side, so it can still appear within a pattern, as in `m!(x==>>y)`.
 // A `$<name>` placeholder in the search pattern will match any AST node and `$<name>` will reference it in the replacement.
+// A placeholder that appears more than once in the search pattern must match the same code each time,
+// so `$a == $a` matches `x == x` but not `x == y`.
 // Within a macro call, a placeholder will match up until whatever token follows the placeholder.
+// Write `$$` for a literal `$` in either the search pattern or the replacement.
+// A `$<name>:*` placeholder matches zero or more sibling nodes, such as all the arms of a `match`.
+// A placeholder can be restricted to a kind of syntax, as in `macro_rules`, by writing `$<name>:<kind>`
+// where kind is one of `expr`, `ident`, `path`, `ty`, `pat`, `literal` or `block`. e.g. `log($msg:literal)`.
+// A `block` placeholder on its own within braces matches the whole block, as in `fn $n() { $body:block }`.
+// A placeholder that is all of an attribute, as in `#[$attr] fn $n() {}`, matches whatever is within the brackets.
+// In the replacement, `$<name>:upper`, `$<name>:lower` or `$<name>:pascal` changes the case of the
+// placeholder's text, e.g. `$field:pascal` turns `foo_bar` into `FooBar`.
+// In a search pattern, `.**` matches zero or more method calls, so `$r.**.unwrap()` matches any
+// chain of calls that ends in `.unwrap()`.
+// Alternative search patterns can be separated by `|`, e.g. `foo($a) | bar($a) ==>> baz($a)`. Each
+// must define the same placeholders. Wrap a bitwise or in parentheses to search for it instead.
+// Placeholders can be constrained by writing `${<name>:<constraint>}`. e.g. `${a:lifetime}` matches a
+// lifetime or label, `${i:index}` matches a tuple index such as the `0` in `x.0` and `${l:any_loop}`
+// matches a `loop`, `while` or `for` loop. `${e:is_option}` and `${e:is_result}` only match expressions
+// of those types, where the type is known. `${a:not_contains(bar())}` matches anything that doesn't
+// contain a match of the pattern `bar()`, while `${a:not(Ok($_))}` matches anything that isn't itself a match.
+// The anonymous placeholder `$_` matches anything, and each use of it can match different code.
+// `${f:is_snake_case}` and `${t:is_upper_camel_case}` only match identifiers written in that case.
+// A function pattern matches functions with `const` or `async` qualifiers that it doesn't mention,
+// and keeps them. Use `$qualifiers` in the replacement to say where they go.
+// In a use declaration such as `use foo::$x`, `$x` matches everything after `foo::`, e.g. `bar::Baz`
+// or `{a, b}`.
+// `$<name>~<pattern>` only matches code whose text matches the pattern, e.g. `$o.$m~^get_()`. Put
+// other patterns in a string, as in `$m~"(?i)^get_"`. Full regex syntax needs the `regex` feature.
 // Available via the command `rust-analyzer.ssr`.
 //
 // ```rust
@@ -42,7 +73,7 @@ pub fn parse_search_replace(
     for &root in db.local_roots().iter() {
         let sr = db.source_root(root);
         for file_id in sr.iter() {
-            if let Some(edit) = match_finder.edits_for_file(file_id) {
+            if let Some(edit) = match_finder.edits_for_file(file_id)? {
                 edits.push(SourceFileEdit { file_id, edit });
             }
         }
@@ -0,0 +1,101 @@
+//! A builder for assembling an `SsrRule` from its parts, e.g. from the fields of a form, rather than
+//! by parsing a single string containing the whole rule.
+
+use crate::parsing::{build_rule, Constraint, PatternOptions};
+use crate::{SsrError, SsrRule};
+use ra_syntax::SmolStr;
+
+/// A constraint on what a placeholder can match. Each corresponds to one that can be written in a
+/// pattern as `${name:constraint}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlaceholderConstraint {
+    /// Matches a lifetime or label. Written as `lifetime`.
+    Lifetime,
+    /// Matches a numeric tuple index. Written as `index`.
+    TupleIndex,
+    /// Matches a `loop`, `while` or `for` loop. Written as `any_loop`.
+    AnyLoop,
+    /// Matches an expression whose type is `Option`. Written as `is_option`.
+    IsOption,
+    /// Matches an expression whose type is `Result`. Written as `is_result`.
+    IsResult,
+    /// Matches code that doesn't contain a match of the given search pattern. Written as
+    /// `not_contains(pattern)`.
+    NotContains(String),
+    /// Matches code that isn't itself a match of the given search pattern. Written as
+    /// `not(pattern)`.
+    Not(String),
+    /// Matches an identifier written in snake_case. Written as `is_snake_case`.
+    IsSnakeCase,
+    /// Matches an identifier written in UpperCamelCase. Written as `is_upper_camel_case`.
+    IsUpperCamelCase,
+}
+
+/// Builds an `SsrRule`. e.g.
+/// `SsrRuleBuilder::new().search("foo($a)").replace("bar($a)").build()`.
+#[derive(Debug, Clone, Default)]
+pub struct SsrRuleBuilder {
+    search: Option<String>,
+    replace: Option<String>,
+    constraints: Vec<(SmolStr, PlaceholderConstraint)>,
+}
+
+impl SsrRuleBuilder {
+    pub fn new() -> SsrRuleBuilder {
+        SsrRuleBuilder::default()
+    }
+
+    /// Sets the search pattern, e.g. `foo($a)`.
+    pub fn search(mut self, pattern: &str) -> SsrRuleBuilder {
+        self.search = Some(pattern.to_owned());
+        self
+    }
+
+    /// Sets the replacement template, e.g. `bar($a)`.
+    pub fn replace(mut self, template: &str) -> SsrRuleBuilder {
+        self.replace = Some(template.to_owned());
+        self
+    }
+
+    /// Adds `constraint` to the placeholder called `placeholder`, which is given without its `$`.
+    pub fn constraint(
+        mut self,
+        placeholder: &str,
+        constraint: PlaceholderConstraint,
+    ) -> SsrRuleBuilder {
+        self.constraints.push((SmolStr::new(placeholder), constraint));
+        self
+    }
+
+    /// Parses and validates the rule.
+    pub fn build(&self) -> Result<SsrRule, SsrError> {
+        let search = self
+            .search
+            .as_deref()
+            .ok_or_else(|| SsrError::invalid_rule("No search pattern was given"))?;
+        let replace = self
+            .replace
+            .as_deref()
+            .ok_or_else(|| SsrError::invalid_rule("No replacement template was given"))?;
+        let mut constraints = Vec::new();
+        for (name, constraint) in &self.constraints {
+            let constraint = match constraint {
+                PlaceholderConstraint::Lifetime => Constraint::Lifetime,
+                PlaceholderConstraint::TupleIndex => Constraint::TupleIndex,
+                PlaceholderConstraint::AnyLoop => Constraint::AnyLoop,
+                PlaceholderConstraint::IsOption => Constraint::IsOption,
+                PlaceholderConstraint::IsResult => Constraint::IsResult,
+                PlaceholderConstraint::NotContains(pattern) => {
+                    Constraint::NotContains(pattern.parse()?)
+                }
+                PlaceholderConstraint::Not(pattern) => Constraint::Not(pattern.parse()?),
+                PlaceholderConstraint::IsSnakeCase => Constraint::IsSnakeCase,
+                PlaceholderConstraint::IsUpperCamelCase => Constraint::IsUpperCamelCase,
+            };
+            constraints.push((name.clone(), constraint));
+        }
+        let (search, replace) = (search.trim(), replace.trim());
+        let options = PatternOptions::default();
+        build_rule((search, 0.into()), (replace, 0.into()), &constraints, &options)
+    }
+}
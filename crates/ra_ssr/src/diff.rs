@@ -0,0 +1,44 @@
+//! Compares two sets of matches, e.g. those found by an old and a new version of a rule. This is
+//! intended for checking that a change to a rule only changes what it matches in the expected ways.
+
+use crate::replacing::render_replace;
+use crate::{ReplaceOptions, SsrMatches};
+use ra_syntax::TextRange;
+use rustc_hash::FxHashMap;
+
+/// The differences between two sets of matches. Ranges are listed in the order that their matches
+/// appear in the match sets.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MatchDiff {
+    /// Ranges that were matched by the new set, but not by the old one.
+    pub added: Vec<TextRange>,
+    /// Ranges that were matched by the old set, but not by the new one.
+    pub removed: Vec<TextRange>,
+    /// Ranges that were matched by both, but whose replacements differ.
+    pub changed: Vec<TextRange>,
+}
+
+/// Returns the differences between `old` and `new`. Only top-level matches are compared. Matches
+/// nested within placeholders are accounted for by comparing the replacements.
+pub fn diff_matches(old: &SsrMatches, new: &SsrMatches) -> MatchDiff {
+    let options = ReplaceOptions::default();
+    let old_by_range: FxHashMap<TextRange, _> = old.matches.iter().map(|m| (m.range, m)).collect();
+    let new_by_range: FxHashMap<TextRange, _> = new.matches.iter().map(|m| (m.range, m)).collect();
+    let mut diff = MatchDiff::default();
+    for m in &new.matches {
+        match old_by_range.get(&m.range) {
+            Some(old_match) => {
+                if render_replace(old_match, &options).ok() != render_replace(m, &options).ok() {
+                    diff.changed.push(m.range);
+                }
+            }
+            None => diff.added.push(m.range),
+        }
+    }
+    for m in &old.matches {
+        if !new_by_range.contains_key(&m.range) {
+            diff.removed.push(m.range);
+        }
+    }
+    diff
+}
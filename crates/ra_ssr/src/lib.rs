@@ -3,30 +3,55 @@
 //! Allows searching the AST for code that matches one or more patterns and then replacing that code
 //! based on a template.
 
+mod builder;
+mod diff;
+mod lint;
 mod matching;
 mod parsing;
+mod preview;
 mod replacing;
+mod suggest;
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "proc-macro2")]
+mod token_stream;
+
+pub use crate::builder::{PlaceholderConstraint, SsrRuleBuilder};
+pub use crate::diff::{diff_matches, MatchDiff};
+pub use crate::lint::SsrWarning;
+pub use crate::matching::{Match, MatchOptions, MatchTrace, MismatchKind, DEFAULT_MAX_DEPTH};
+pub use crate::parsing::PatternOptions;
+pub use crate::preview::unified_diff;
+pub use crate::replacing::{CommentHandling, PlaceholderWhitespace, ReplaceOptions};
+pub use crate::suggest::suggest_pattern;
+#[cfg(feature = "proc-macro2")]
+pub use crate::token_stream::apply_to_token_stream;
 
-use crate::matching::Match;
 use hir::Semantics;
-use ra_db::{FileId, FileRange};
-use ra_syntax::{AstNode, SmolStr, SyntaxNode};
+use ra_db::{FileId, FileRange, SourceDatabaseExt, VfsPath};
+use ra_syntax::{
+    ast, AstNode, SmolStr, SyntaxElement, SyntaxKind, SyntaxNode, TextRange, TextSize,
+};
 use ra_text_edit::TextEdit;
 use rustc_hash::FxHashMap;
+use std::cell::RefCell;
 
 // A structured search replace rule. Create by calling `parse` on a str.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SsrRule {
     /// A structured pattern that we're searching for.
     pattern: SsrPattern,
+    /// Further patterns, separated from the first by `|` in the rule, any of which may match
+    /// instead. All of them define the same placeholders as `pattern`.
+    alternatives: Vec<SsrPattern>,
     /// What we'll replace it with.
     template: parsing::SsrTemplate,
 }
 
-#[derive(Debug)]
-struct SsrPattern {
+/// A search pattern on its own, without a replacement template. Create by calling `parse` on a str.
+/// Use with `matches` to search for code without replacing it.
+#[derive(Debug, Clone)]
+pub struct SsrPattern {
     raw: parsing::RawSearchPattern,
     /// Placeholders keyed by the stand-in ident that we use in Rust source code.
     placeholders_by_stand_in: FxHashMap<SmolStr, parsing::Placeholder>,
@@ -38,14 +63,532 @@ struct SsrPattern {
     item: Option<SyntaxNode>,
     path: Option<SyntaxNode>,
     pattern: Option<SyntaxNode>,
+    variant: Option<SyntaxNode>,
+    stmt: Option<SyntaxNode>,
+    attr: Option<SyntaxNode>,
+    /// If true, the pattern isn't parsed as Rust code at all and only matches a flat sequence of
+    /// tokens within a macro call. See `SsrRule::parse_tokens`.
+    tokens_only: bool,
+    /// If true, whitespace in the pattern must line up with whitespace in the code. See
+    /// `PatternOptions::exact_whitespace`.
+    exact_whitespace: bool,
+}
+
+impl SsrRule {
+    /// Returns whether this rule is the same as `other` apart from the names of placeholders and
+    /// whitespace. e.g. `foo($a) ==>> bar($a)` is alpha-equivalent to `foo($x) ==>> bar($x)`.
+    pub fn is_alpha_equivalent(&self, other: &SsrRule) -> bool {
+        parsing::rules_alpha_equivalent(self, other)
+    }
+
+    /// Returns the identifiers and keywords, other than placeholders, that any code matching this
+    /// rule must contain. A file that doesn't contain all of them can't have any matches, so can be
+    /// skipped without being parsed.
+    pub fn required_literals(&self) -> Vec<SmolStr> {
+        let mut literals = self.pattern.raw.literals();
+        for alternative in &self.alternatives {
+            let alternative_literals = alternative.raw.literals();
+            literals.retain(|literal| alternative_literals.contains(literal));
+        }
+        literals
+    }
+
+    /// Returns the names of the search pattern's placeholders that the replacement template doesn't
+    /// use, in the order that they first appear. This is advisory: an unused placeholder may be a
+    /// mistake, or may just be a wildcard.
+    pub fn unused_pattern_placeholders(&self) -> Vec<SmolStr> {
+        let mut unused: Vec<SmolStr> = Vec::new();
+        for element in &self.pattern.raw.tokens {
+            if let parsing::PatternElement::Placeholder(placeholder) = element {
+                let used = self.template.tokens.iter().any(|e| {
+                    matches!(e, parsing::PatternElement::Placeholder(p) if p.ident == placeholder.ident)
+                });
+                if !used && !placeholder.anonymous && !unused.contains(&placeholder.ident) {
+                    unused.push(placeholder.ident.clone());
+                }
+            }
+        }
+        unused
+    }
 }
 
+impl PartialEq for SsrPattern {
+    fn eq(&self, other: &SsrPattern) -> bool {
+        // Everything else is derived from `raw`.
+        self.raw == other.raw && self.exact_whitespace == other.exact_whitespace
+    }
+}
+
+impl Eq for SsrPattern {}
+
+/// The kinds of syntax fragment that a search pattern can be interpreted as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternKind {
+    Expr,
+    TypeRef,
+    Item,
+    Path,
+    Pattern,
+    /// An enum variant definition.
+    Variant,
+    /// A `let` or expression statement, including its trailing semicolon. Expression patterns
+    /// never match statements, so `foo()` will match the expression in `foo();`, leaving the
+    /// semicolon alone, while `foo();` matches the whole statement.
+    Statement,
+    /// An outer attribute such as `#[cfg(feature = "x")]`.
+    Attribute,
+}
+
+/// An error from parsing a rule or applying it. The error's `kind` says what sort of problem it
+/// was, while its message, which is what `Display` shows, describes it.
 #[derive(Debug, PartialEq)]
-pub struct SsrError(String);
+pub struct SsrError {
+    kind: SsrErrorKind,
+    message: String,
+    /// The part of the rule's text that the error relates to, if known.
+    range: Option<TextRange>,
+}
+
+/// What sort of problem an `SsrError` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsrErrorKind {
+    /// The rule has no `==>>` delimiter, or none with whitespace on either side.
+    MissingDelimiter,
+    /// The rule has more than one `==>>` delimiter.
+    MultipleDelimiters,
+    /// The search pattern or replacement template couldn't be split into tokens.
+    TokenizeFailed,
+    /// The search pattern or replacement template isn't valid Rust code.
+    InvalidPattern,
+    /// A placeholder appears more than once where that isn't allowed.
+    DuplicatePlaceholder,
+    /// A placeholder is used, e.g. in the replacement template, without being defined by the
+    /// search pattern.
+    UndefinedPlaceholder,
+    /// Some code was nested more deeply than `MatchOptions::max_depth`, so wasn't searched.
+    TooDeep,
+    /// `assert_match_count` found a different number of matches than expected.
+    MatchCountMismatch,
+    /// Any other problem with the text of a rule, e.g. an unsupported placeholder constraint.
+    InvalidRule,
+    /// Any other problem, e.g. an internal error while applying a rule.
+    Other,
+}
 
 #[derive(Debug, Default)]
 pub struct SsrMatches {
     matches: Vec<Match>,
+    /// Problems that stopped part of the code from being searched.
+    errors: Vec<SsrError>,
+}
+
+impl Match {
+    /// The range of the code that was matched.
+    pub fn range(&self) -> TextRange {
+        self.range
+    }
+
+    /// The text of the code that was matched.
+    pub fn matched_text(&self) -> String {
+        // Token-only patterns match part of a token tree.
+        if self.matched_node.kind() == SyntaxKind::TOKEN_TREE {
+            if let Some(text) = self.text_in_range(self.range) {
+                return text;
+            }
+        }
+        self.matched_node.text().to_string()
+    }
+
+    /// Whether this match is within the arguments of a macro call that we couldn't expand, and so
+    /// instead parsed as a list of expressions. See `MatchOptions::match_in_macro_arguments`.
+    pub fn is_in_macro_arguments(&self) -> bool {
+        self.in_macro_arguments
+    }
+
+    /// The names of the `mod` blocks that lexically enclose the match, outermost first. e.g. for a
+    /// match within `mod a { mod b { ... } }`, this is `["a", "b"]`.
+    pub fn module_path(&self) -> &[SmolStr] {
+        &self.module_path
+    }
+
+    /// The text of the code that each placeholder matched, keyed by the placeholder's name.
+    pub fn placeholder_text(&self) -> FxHashMap<String, String> {
+        self.placeholder_values
+            .iter()
+            .map(|(matching::Var(name), value)| {
+                let text = match &value.node {
+                    Some(node) => node.text().to_string(),
+                    None => self.text_in_range(value.range.range).unwrap_or_default(),
+                };
+                (name.clone(), text)
+            })
+            .collect()
+    }
+
+    /// Returns the text of the matched node that is within `range`, if `range` is within it.
+    pub(crate) fn text_in_range(&self, range: TextRange) -> Option<String> {
+        let range = range.checked_sub(self.matched_node.text_range().start())?;
+        let text = self.matched_node.text().to_string();
+        text.get(usize::from(range.start())..usize::from(range.end())).map(str::to_owned)
+    }
+}
+
+impl SsrMatches {
+    /// Problems that stopped part of the code from being searched, e.g. code that was nested more
+    /// deeply than `MatchOptions::max_depth`. Matches in the rest of the code are still found.
+    pub fn errors(&self) -> &[SsrError] {
+        &self.errors
+    }
+
+    /// Drops matches that aren't within the module `path`, e.g. `util` or `crate::util::fmt`, or a
+    /// module within it. Modules are only determined lexically, from the `mod` blocks enclosing
+    /// each match, with the file itself being `crate`. See `Match::module_path`.
+    pub fn retain_within_module(&mut self, path: &str) {
+        let prefix: Vec<&str> = path.split("::").map(str::trim).filter(|s| *s != "crate").collect();
+        self.matches.retain(|m| {
+            m.module_path.len() >= prefix.len()
+                && m.module_path.iter().zip(&prefix).all(|(a, b)| a.as_str() == *b)
+        });
+    }
+
+    /// Drops top-level matches that are within a placeholder of another top-level match. Those get
+    /// replaced while rendering the other match's replacement, via its inner matches, so also
+    /// replacing them at the top level would edit code that had already been rewritten.
+    fn remove_matches_in_placeholders(&mut self) {
+        // The ranges of placeholders of matches in macro arguments aren't within the file.
+        let bindings: Vec<(usize, TextRange)> = self
+            .matches
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| !m.in_macro_arguments)
+            .flat_map(|(i, m)| m.placeholder_values.values().map(move |p| (i, p.range.range)))
+            .collect();
+        let mut index = 0;
+        self.matches.retain(|m| {
+            let nested =
+                bindings.iter().any(|&(i, range)| i != index && range.contains_range(m.range));
+            index += 1;
+            !nested
+        });
+    }
+
+    /// Returns `self` with any nested matches removed and made into top-level matches.
+    pub(crate) fn flattened(self) -> SsrMatches {
+        let mut out = SsrMatches::default();
+        self.flatten_into(&mut out);
+        out
+    }
+
+    fn flatten_into(self, out: &mut SsrMatches) {
+        out.errors.extend(self.errors);
+        for mut m in self.matches {
+            for p in m.placeholder_values.values_mut() {
+                std::mem::replace(&mut p.inner_matches, SsrMatches::default()).flatten_into(out);
+            }
+            out.matches.push(m);
+        }
+    }
+}
+
+/// Returns the matches of `pattern` within `node`, including those nested within other matches, in
+/// order of where they start. `node` is searched as part of the tree it's in, so ranges of the
+/// matches are within that tree, as for `node.text_range()`. This is for when only the matches
+/// themselves are wanted, so no replacement template is needed.
+pub fn matches(pattern: &SsrPattern, node: &SyntaxNode) -> impl Iterator<Item = Match> {
+    let rule = SsrRule {
+        pattern: pattern.clone(),
+        alternatives: Vec::new(),
+        template: parsing::SsrTemplate { tokens: Vec::new() },
+    };
+    let mut matches = with_node_in_db(vec![rule], node, |match_finder, node| {
+        match_finder.find_matches_in_node(node).flattened().matches
+    });
+    matches.sort_by_key(|m| m.range.start());
+    matches.into_iter()
+}
+
+/// Finds the matches of `rule` in each of `files`, which must be in `db`. Each file's node would
+/// usually be the root of its syntax tree, but can be any node within it, in which case only that
+/// node is searched. The file is searched as part of `db`, so name resolution sees the whole
+/// workspace, just as it does for a `MatchFinder`. Files are searched one after another.
+pub fn search_files(
+    db: &ra_ide_db::RootDatabase,
+    rule: &SsrRule,
+    files: &[(FileId, SyntaxNode)],
+) -> FxHashMap<FileId, SsrMatches> {
+    let mut match_finder = MatchFinder::new(db);
+    match_finder.add_rule(rule.clone());
+    files
+        .iter()
+        .map(|(file_id, node)| {
+            // `node` may not have come from our `Semantics`, so we look up the same node in the tree
+            // that it parsed.
+            let file = match_finder.sema.parse(*file_id);
+            let matches = match find_node(file.syntax(), node.text_range(), node.kind()) {
+                Some(node) => match_finder.find_matches_in_node(&node),
+                None => match_finder.find_matches_in_node(file.syntax()),
+            };
+            (*file_id, matches)
+        })
+        .collect()
+}
+
+/// Parses `rule` and applies it to `source`, returning the rewritten source. Intended for fuzzing
+/// and other situations where arbitrary input needs to be handled. Any problem with the rule, or
+/// internal error while applying it, is returned as an `SsrError`. A panic would be a bug, so isn't
+/// caught, leaving a fuzzer to find it.
+pub fn fuzz_apply(rule: &str, source: &str) -> Result<String, SsrError> {
+    apply_to_source(rule.parse()?, source)
+}
+
+/// Applies `rule` to each of `items` in turn, yielding the rewritten text of each item. Items are
+/// only rewritten as they're requested. Since the items have already been parsed, this doesn't
+/// avoid having the whole file in memory, but each item is searched on its own, in a single
+/// database whose only file is replaced by each item in turn. So the rule can't use any
+/// information from outside the item.
+pub fn apply_to_items<'a>(
+    rule: &'a SsrRule,
+    items: impl IntoIterator<Item = ast::ModuleItem> + 'a,
+) -> impl Iterator<Item = Result<String, SsrError>> + 'a {
+    let (mut db, file_id) = single_file_db("");
+    items.into_iter().map(move |item| {
+        let source = item.syntax().to_string();
+        let mut change = ra_ide_db::change::AnalysisChange::new();
+        change.change_file(file_id, Some(std::sync::Arc::new(source.clone())));
+        db.apply_change(change);
+        replace_in_file(&db, file_id, rule, &source).map(|(result, _)| result)
+    })
+}
+
+/// Finds the matches of `rule` within `node`, including those within the placeholders of other
+/// matches, and, for each that is a `match` expression, returns the text of each arm's pattern and
+/// body. `rule` would typically be something like `match $e { $arms:* }`. The match arms are
+/// returned in the order they appear. As with `matches`, `node` is searched as part of the tree
+/// it's in.
+pub fn collect_match_arms(rule: &SsrRule, node: &SyntaxNode) -> Vec<(String, String)> {
+    let mut matches = with_node_in_db(vec![rule.clone()], node, |match_finder, node| {
+        match_finder.find_matches_in_node(node).flattened().matches
+    });
+    matches.sort_by_key(|m| m.range.start());
+    let mut arms = Vec::new();
+    for m in matches {
+        if let Some(arm_list) =
+            ast::MatchExpr::cast(m.matched_node).and_then(|match_expr| match_expr.match_arm_list())
+        {
+            for arm in arm_list.arms() {
+                let pat = arm.pat().map(|p| p.syntax().to_string()).unwrap_or_default();
+                let body = arm.expr().map(|e| e.syntax().to_string()).unwrap_or_default();
+                arms.push((pat, body));
+            }
+        }
+    }
+    arms
+}
+
+/// Calls `f` with each match of `rule` within `node`, in source order, including matches within the
+/// placeholders of other matches. Each match is passed to `f` as soon as it's found and then
+/// dropped, so matches don't accumulate in memory. Nested matches are passed to `f` on their own,
+/// after the match containing them, rather than as part of it. As with `matches`, `node` is
+/// searched as part of the tree it's in.
+pub fn for_each_match(rule: &SsrRule, node: &SyntaxNode, mut f: impl FnMut(&Match)) {
+    with_node_in_db(vec![rule.clone()], node, |match_finder, node| {
+        match_finder.stream_nested_matches = true;
+        let depth = node.ancestors().count() - 1;
+        // The same code can be reached more than once, e.g. through both a macro expansion and a
+        // placeholder, so we skip matches of code that has already been passed to `f`. Matches are
+        // found in source order, so we only need to remember those that are still open.
+        let mut open_ranges: Vec<TextRange> = Vec::new();
+        match_finder.find_matches(node, &None, None, depth, &mut |m| {
+            open_ranges.retain(|range| range.end() > m.range.start());
+            if !open_ranges.contains(&m.range) {
+                open_ranges.push(m.range);
+                f(&m);
+            }
+        });
+    });
+}
+
+/// Attempts to match `rule` against `node` alone, with default options, recording where matching
+/// diverged if it doesn't match. For working out why some code that was expected to match didn't.
+pub fn trace_match(rule: &SsrRule, node: &SyntaxNode) -> MatchTrace {
+    let root = node.ancestors().last().unwrap_or_else(|| node.clone());
+    let (db, file_id) = single_file_db(&root.to_string());
+    let sema = Semantics::new(&db);
+    let file = sema.parse(file_id);
+    let candidate = match file
+        .syntax()
+        .descendants()
+        .find(|n| n.text_range() == node.text_range() && n.kind() == node.kind())
+    {
+        Some(candidate) => candidate,
+        None => {
+            return MatchTrace::Diverged {
+                kind: MismatchKind::Other,
+                reason: "Couldn't find the node after re-parsing its file".to_owned(),
+            }
+        }
+    };
+    match matching::get_match(true, rule, &candidate, &None, &sema, &MatchOptions::default()) {
+        Ok(_) => MatchTrace::Matched,
+        Err(e) => MatchTrace::Diverged { kind: e.kind, reason: e.reason.unwrap_or_default() },
+    }
+}
+
+/// Returns the number of matches of `rule` within `node`, including those within the placeholders of
+/// other matches, as `for_each_match` finds them.
+pub fn count_matches(rule: &SsrRule, node: &SyntaxNode) -> usize {
+    let mut count = 0;
+    for_each_match(rule, node, |_| count += 1);
+    count
+}
+
+/// Parses `rule`, then checks that it has `expected` matches in `source`, as counted by
+/// `count_matches`. For tests of rules. Fails with an error describing the mismatch, or if `rule`
+/// doesn't parse.
+pub fn assert_match_count(rule: &str, source: &str, expected: usize) -> Result<(), SsrError> {
+    let parsed: SsrRule = rule.parse()?;
+    let file = ast::SourceFile::parse(source).tree();
+    let actual = count_matches(&parsed, file.syntax());
+    if actual != expected {
+        return Err(SsrError {
+            kind: SsrErrorKind::MatchCountMismatch,
+            message: format!("Expected {} matches of `{}`, but found {}", expected, rule, actual),
+            range: None,
+        });
+    }
+    Ok(())
+}
+
+/// A set of rules that are all searched for in a single pass over the code. Parse from a str with
+/// one rule per line. Empty lines and lines starting with `//` are ignored. Where matches of
+/// different rules overlap, the match of the rule listed first is used.
+#[derive(Debug, Clone)]
+pub struct SsrRules(pub Vec<SsrRule>);
+
+/// A sequence of rules, each of which is applied to the output of the one before it.
+#[derive(Debug, Clone)]
+pub struct RulePipeline(pub Vec<SsrRule>);
+
+impl RulePipeline {
+    /// Applies each rule in turn to `source`, re-parsing the output of each stage before passing
+    /// it to the next. Fails if any stage fails, or turns code without syntax errors into code
+    /// with them. The error reports which stage, numbered from 1, failed. An error from the stage
+    /// itself keeps its kind and range.
+    pub fn apply(&self, source: &str) -> Result<String, SsrError> {
+        let mut source = source.to_owned();
+        for (index, rule) in self.0.iter().enumerate() {
+            let stage_error = |e: SsrError| SsrError {
+                message: format!("Stage {} failed: {}", index + 1, e.message),
+                ..e
+            };
+            let had_errors = !ast::SourceFile::parse(&source).errors().is_empty();
+            let output = apply_to_source(rule.clone(), &source).map_err(stage_error)?;
+            if !had_errors && !ast::SourceFile::parse(&output).errors().is_empty() {
+                return Err(stage_error(SsrError::new("output has syntax errors")));
+            }
+            source = output;
+        }
+        Ok(source)
+    }
+}
+
+/// Returns `source` with each match of `rule` replaced. `source` is treated as the only file in its
+/// crate. If a replacement can't be rendered, `source` is returned unchanged. Use `try_replace` to
+/// find out why.
+pub fn replace(rule: &SsrRule, source: &str) -> String {
+    try_replace(rule, source).map_or_else(|_| source.to_owned(), |(result, _)| result)
+}
+
+/// Like `replace`, but fails if a replacement can't be rendered, and also returns the number of
+/// replacements made. Matches within the placeholders of other matches are replaced as part of the
+/// outer match, so aren't counted separately.
+pub fn try_replace(rule: &SsrRule, source: &str) -> Result<(String, usize), SsrError> {
+    let (db, file_id) = single_file_db(source);
+    replace_in_file(&db, file_id, rule, source)
+}
+
+/// Like `try_replace`, but for `file_id`, whose text in `db` is `source`.
+fn replace_in_file(
+    db: &ra_ide_db::RootDatabase,
+    file_id: FileId,
+    rule: &SsrRule,
+    source: &str,
+) -> Result<(String, usize), SsrError> {
+    let mut match_finder = MatchFinder::new(db);
+    match_finder.add_rule(rule.clone());
+    let mut result = source.to_owned();
+    let mut count = 0;
+    if let Some(edit) = match_finder.edits_for_file(file_id)? {
+        count = edit.len();
+        edit.apply(&mut result);
+    }
+    Ok((result, count))
+}
+
+/// Applies `rule` to `source`, which is treated as the only file in its crate.
+fn apply_to_source(rule: SsrRule, source: &str) -> Result<String, SsrError> {
+    try_replace(&rule, source).map(|(result, _)| result)
+}
+
+/// Parses the whole tree that `node` is part of into a database of its own, then calls `f` with a
+/// finder for `rules` and the node within the new tree that corresponds to `node`. This way `node`
+/// is searched in its original context, e.g. a call expression is still within its function. If
+/// `node` can't be found again, e.g. because its tree was built by hand rather than parsed from a
+/// file, `f` is given the root of a file containing just the text of `node`.
+fn with_node_in_db<T>(
+    rules: Vec<SsrRule>,
+    node: &SyntaxNode,
+    f: impl FnOnce(&mut MatchFinder, &SyntaxNode) -> T,
+) -> T {
+    let root = node.ancestors().last().unwrap_or_else(|| node.clone());
+    let (db, file_id) = single_file_db(&root.to_string());
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.rules = rules;
+    let file = match_finder.sema.parse(file_id);
+    if let Some(found) = find_node(file.syntax(), node.text_range(), node.kind()) {
+        return f(&mut match_finder, &found);
+    }
+    let (db, file_id) = single_file_db(&node.to_string());
+    let mut node_finder = MatchFinder::new(&db);
+    node_finder.rules = std::mem::take(&mut match_finder.rules);
+    let file = node_finder.sema.parse(file_id);
+    f(&mut node_finder, file.syntax())
+}
+
+/// Returns the node within `root` that has the given `range` and `kind`, if there is one. This only
+/// walks up from the smallest element covering `range`, rather than over the whole tree.
+fn find_node(root: &SyntaxNode, range: TextRange, kind: SyntaxKind) -> Option<SyntaxNode> {
+    if !root.text_range().contains_range(range) {
+        return None;
+    }
+    let start = match root.covering_element(range) {
+        SyntaxElement::Node(node) => node,
+        SyntaxElement::Token(token) => token.parent(),
+    };
+    start.ancestors().find(|n| n.text_range() == range && n.kind() == kind)
+}
+
+/// Returns a database containing just `source`, as the root of its own crate.
+fn single_file_db(source: &str) -> (ra_ide_db::RootDatabase, FileId) {
+    let mut db = ra_ide_db::RootDatabase::default();
+    let file_id = FileId(0);
+    let mut file_set = ra_db::FileSet::default();
+    file_set.insert(file_id, VfsPath::new_virtual_path("/main.rs".to_owned()));
+    let mut crate_graph = ra_db::CrateGraph::default();
+    crate_graph.add_crate_root(
+        file_id,
+        ra_db::Edition::Edition2018,
+        None,
+        Default::default(),
+        ra_db::Env::default(),
+        Default::default(),
+    );
+    let mut change = ra_ide_db::change::AnalysisChange::new();
+    change.set_roots(vec![ra_db::SourceRoot::new_local(file_set)]);
+    change.change_file(file_id, Some(std::sync::Arc::new(source.to_owned())));
+    change.set_crate_graph(crate_graph);
+    db.apply_change(change);
+    (db, file_id)
 }
 
 /// Searches a crate for pattern matches and possibly replaces them with something else.
@@ -53,69 +596,510 @@ pub struct MatchFinder<'db> {
     /// Our source of information about the user's code.
     sema: Semantics<'db, ra_ide_db::RootDatabase>,
     rules: Vec<SsrRule>,
+    match_options: MatchOptions,
+    replace_options: ReplaceOptions,
+    /// Errors found while searching, which are moved into the `SsrMatches` that we return.
+    search_errors: RefCell<Vec<SsrError>>,
+    /// If true, matches within the placeholders of a match are passed to the callback of
+    /// `find_matches` after it, rather than being kept in its `inner_matches`.
+    stream_nested_matches: bool,
 }
 
 impl<'db> MatchFinder<'db> {
     pub fn new(db: &'db ra_ide_db::RootDatabase) -> MatchFinder<'db> {
-        MatchFinder { sema: Semantics::new(db), rules: Vec::new() }
+        MatchFinder {
+            sema: Semantics::new(db),
+            rules: Vec::new(),
+            match_options: MatchOptions::default(),
+            replace_options: ReplaceOptions::default(),
+            search_errors: RefCell::default(),
+            stream_nested_matches: false,
+        }
     }
 
     pub fn add_rule(&mut self, rule: SsrRule) {
         self.rules.push(rule);
     }
 
-    pub fn edits_for_file(&self, file_id: FileId) -> Option<TextEdit> {
+    /// Adds each of `rules`, in order.
+    pub fn add_rules(&mut self, rules: SsrRules) {
+        self.rules.extend(rules.0);
+    }
+
+    /// Sets the options that control what our rules will match.
+    pub fn set_match_options(&mut self, options: MatchOptions) {
+        self.match_options = options;
+    }
+
+    /// Sets the options used when rendering replacements in `edits_for_file`.
+    pub fn set_replace_options(&mut self, options: ReplaceOptions) {
+        self.replace_options = options;
+    }
+
+    /// Returns an edit that applies our rules to `file_id`, or None if none of them matched. An
+    /// error is only returned if something goes wrong internally while rendering a replacement.
+    pub fn edits_for_file(&self, file_id: FileId) -> Result<Option<TextEdit>, SsrError> {
+        let matches = self.find_matches_in_file(file_id);
+        if matches.matches.is_empty() {
+            Ok(None)
+        } else {
+            replacing::matches_to_edit(&matches, &self.replace_options).map(Some)
+        }
+    }
+
+    /// Like `edits_for_file`, but the text substituted for each placeholder in a replacement can be
+    /// computed by `substitute`, which is given the placeholder's name and the match. If it returns
+    /// None, the placeholder is replaced by what it matched as usual. e.g. this could replace a
+    /// placeholder that matched a literal number of degrees with the equivalent in radians.
+    pub fn edits_for_file_with(
+        &self,
+        file_id: FileId,
+        mut substitute: impl FnMut(&str, &Match) -> Option<String>,
+    ) -> Result<Option<TextEdit>, SsrError> {
         let matches = self.find_matches_in_file(file_id);
         if matches.matches.is_empty() {
-            None
+            Ok(None)
         } else {
-            Some(replacing::matches_to_edit(&matches))
+            replacing::matches_to_edit_with(&matches, &self.replace_options, &mut substitute)
+                .map(Some)
+        }
+    }
+
+    /// Checks that the replacement for each of `matches` is valid Rust code, before any of them are
+    /// applied. Returns the range of each match whose replacement is invalid, together with the
+    /// reason.
+    pub fn validate_all_replacements(&self, matches: &SsrMatches) -> Vec<(TextRange, SsrError)> {
+        replacing::validate_replacements(matches, &self.replace_options)
+    }
+
+    /// Like `find_matches_in_file`, but only interprets our search patterns as `kind`. Nodes of
+    /// other kinds are still searched for nested matches, but are never matched themselves.
+    pub fn find_matches_of_kind(&self, file_id: FileId, kind: PatternKind) -> SsrMatches {
+        let file = self.sema.parse(file_id);
+        let mut matches = SsrMatches::default();
+        self.find_matches(file.syntax(), &None, Some(kind), 0, &mut |m| matches.matches.push(m));
+        matches.remove_matches_in_placeholders();
+        matches.errors = self.search_errors.take();
+        matches
+    }
+
+    /// Finds matches in each of `files` that has a path accepted by `filter`. Files rejected by
+    /// `filter` are skipped without being parsed.
+    pub fn match_files(
+        &self,
+        files: impl IntoIterator<Item = FileId>,
+        filter: impl Fn(&VfsPath) -> bool,
+    ) -> SsrMatches {
+        let db = self.sema.db;
+        let mut matches = SsrMatches::default();
+        for file_id in files {
+            let source_root = db.source_root(db.file_source_root(file_id));
+            if let Some(path) = source_root.path_for_file(&file_id) {
+                if filter(path) {
+                    let file_matches = self.find_matches_in_file(file_id);
+                    matches.matches.extend(file_matches.matches);
+                    matches.errors.extend(file_matches.errors);
+                }
+            }
+        }
+        matches
+    }
+
+    /// Returns the matches in `file_id`, given `matches`, which were found in it before `edit` was
+    /// applied. The edit must already have been applied to our database. Rather than searching the
+    /// whole file again, matches that the edit didn't touch are moved to where their code now is and
+    /// checked again, and only the code around each part of the edit is searched for new matches.
+    pub fn update_matches(
+        &self,
+        file_id: FileId,
+        matches: SsrMatches,
+        edit: &TextEdit,
+    ) -> SsrMatches {
+        let file = self.sema.parse(file_id);
+        let root = file.syntax();
+        // The parts of the file's new text that we need to search again.
+        let mut dirty: Vec<TextRange> = edit
+            .iter()
+            .map(|indel| {
+                TextRange::at(
+                    offset_after_edit(edit, indel.delete.start()),
+                    TextSize::of(&indel.insert),
+                )
+            })
+            .collect();
+        let mut moved = Vec::new();
+        for m in matches.matches {
+            let range = TextRange::new(
+                offset_after_edit(edit, m.range.start()),
+                offset_after_edit(edit, m.range.end()),
+            );
+            // Matches from macro expansions don't have a node of their own in the file.
+            let from_file = m.matched_node.text_range() == m.range;
+            let node = find_node(root, range, m.matched_node.kind());
+            match node {
+                Some(node) if from_file && !edit_touches(edit, m.range) => moved.push(node),
+                _ => dirty.push(range),
+            }
+        }
+        // We search from the nearest enclosing item, or an ancestor of it that matches, so that
+        // we find matches that are larger than what was changed.
+        let mut search_roots: Vec<SyntaxNode> = Vec::new();
+        for range in dirty {
+            let start = match root.covering_element(range) {
+                SyntaxElement::Node(node) => node,
+                SyntaxElement::Token(token) => token.parent(),
+            };
+            let item = start
+                .ancestors()
+                .find(|n| ast::ModuleItem::can_cast(n.kind()))
+                .unwrap_or_else(|| root.clone());
+            let search_root = item
+                .ancestors()
+                .filter(|a| {
+                    self.rules.iter().any(|rule| {
+                        matching::get_match(false, rule, a, &None, &self.sema, &self.match_options)
+                            .is_ok()
+                    })
+                })
+                .last()
+                .unwrap_or(item);
+            search_roots.push(search_root);
+        }
+        let contains =
+            |outer: &SyntaxNode, inner: TextRange| outer.text_range().contains_range(inner);
+        let search_roots: Vec<&SyntaxNode> = search_roots
+            .iter()
+            .enumerate()
+            .filter(|(i, node)| {
+                !search_roots.iter().enumerate().any(|(j, other)| {
+                    let range = node.text_range();
+                    contains(other, range) && (other.text_range() != range || j < *i)
+                })
+            })
+            .map(|(_, node)| node)
+            .collect();
+        let mut result = SsrMatches::default();
+        // As for a full search, `depth` counts from the root of the file.
+        let depth = |node: &SyntaxNode| node.ancestors().count() - 1;
+        for node in moved {
+            if !search_roots.iter().any(|root| contains(root, node.text_range())) {
+                self.find_matches(&node, &None, None, depth(&node), &mut |m| {
+                    result.matches.push(m)
+                });
+            }
         }
+        for node in search_roots {
+            self.find_matches(node, &None, None, depth(node), &mut |m| result.matches.push(m));
+        }
+        result.matches.sort_by_key(|m| m.range.start());
+        result.remove_matches_in_placeholders();
+        result.errors = self.search_errors.take();
+        result
     }
 
     fn find_matches_in_file(&self, file_id: FileId) -> SsrMatches {
         let file = self.sema.parse(file_id);
-        let code = file.syntax();
+        self.find_matches_in_node(file.syntax())
+    }
+
+    /// Returns the matches within `code`, which must be from a file in our database.
+    fn find_matches_in_node(&self, code: &SyntaxNode) -> SsrMatches {
+        let depth = code.ancestors().count() - 1;
         let mut matches = SsrMatches::default();
-        self.find_matches(code, &None, &mut matches);
+        self.find_matches(code, &None, None, depth, &mut |m| matches.matches.push(m));
+        matches.remove_matches_in_placeholders();
+        matches.errors = self.search_errors.take();
         matches
     }
 
+    /// Searches `code` and its descendants, passing each match found to `on_match`. `depth` is how
+    /// far `code` is below where the search started. Code that is too deep isn't searched, so that
+    /// we don't overflow the stack.
     fn find_matches(
         &self,
         code: &SyntaxNode,
         restrict_range: &Option<FileRange>,
-        matches_out: &mut SsrMatches,
+        restrict_kind: Option<PatternKind>,
+        depth: usize,
+        on_match: &mut dyn FnMut(Match),
     ) {
-        for rule in &self.rules {
-            if let Ok(mut m) = matching::get_match(false, rule, &code, restrict_range, &self.sema) {
+        if depth > self.match_options.max_depth() {
+            self.push_too_deep_error(code, "searched");
+            return;
+        }
+        if restrict_kind.is_some() && restrict_kind != PatternKind::for_syntax_kind(code.kind()) {
+            self.find_matches_in_children(code, restrict_range, restrict_kind, depth, on_match);
+            return;
+        }
+        for (rule_index, rule) in self.rules.iter().enumerate() {
+            let result = matching::get_match(
+                false,
+                rule,
+                &code,
+                restrict_range,
+                &self.sema,
+                &self.match_options,
+            );
+            if matches!(&result, Err(e) if e.kind == MismatchKind::TooDeep) {
+                self.push_too_deep_error(code, "fully matched");
+            }
+            if let Ok(mut m) = result {
+                m.rule_index = rule_index;
+                if self.stream_nested_matches {
+                    // Matches within our placeholders come straight after us, in source order.
+                    let mut placeholder_nodes: Vec<SyntaxNode> = m
+                        .placeholder_values
+                        .values()
+                        .flat_map(|p| p.node.iter().chain(&p.nodes))
+                        .cloned()
+                        .collect();
+                    placeholder_nodes.sort_by_key(|n| n.text_range().start());
+                    on_match(m);
+                    for placeholder_node in &placeholder_nodes {
+                        self.find_matches_in_placeholder(
+                            code,
+                            placeholder_node,
+                            restrict_range,
+                            restrict_kind,
+                            depth,
+                            on_match,
+                        );
+                    }
+                    return;
+                }
                 // Continue searching in each of our placeholders.
                 for placeholder_value in m.placeholder_values.values_mut() {
-                    if let Some(placeholder_node) = &placeholder_value.node {
-                        // Don't search our placeholder if it's the entire matched node, otherwise we'd
-                        // find the same match over and over until we got a stack overflow.
-                        if placeholder_node != code {
-                            self.find_matches(
-                                placeholder_node,
-                                restrict_range,
-                                &mut placeholder_value.inner_matches,
-                            );
-                        }
+                    let inner_matches = &mut placeholder_value.inner_matches.matches;
+                    for placeholder_node in
+                        placeholder_value.node.iter().chain(&placeholder_value.nodes)
+                    {
+                        self.find_matches_in_placeholder(
+                            code,
+                            placeholder_node,
+                            restrict_range,
+                            restrict_kind,
+                            depth,
+                            &mut |m| inner_matches.push(m),
+                        );
                     }
                 }
-                matches_out.matches.push(m);
+                on_match(m);
                 return;
             }
         }
+        if code.kind() == SyntaxKind::TOKEN_TREE {
+            for (rule_index, rule) in self.rules.iter().enumerate() {
+                if rule.pattern.tokens_only {
+                    for mut m in matching::get_token_matches(rule, code, &self.sema) {
+                        m.rule_index = rule_index;
+                        on_match(m);
+                    }
+                }
+            }
+        }
+        self.find_matches_in_children(code, restrict_range, restrict_kind, depth, on_match);
+    }
+
+    /// Records an error for `code` being nested more deeply than `MatchOptions::max_depth`.
+    /// `unfinished` says what wasn't done to it, e.g. "searched".
+    fn push_too_deep_error(&self, code: &SyntaxNode, unfinished: &str) {
+        self.search_errors.borrow_mut().push(SsrError {
+            kind: SsrErrorKind::TooDeep,
+            message: format!(
+                "Code is nested more than {} levels deep, so wasn't {}",
+                self.match_options.max_depth(),
+                unfinished
+            ),
+            range: Some(self.sema.original_range(code).range),
+        });
+    }
+
+    /// Searches `placeholder_node`, which a placeholder of a match of `code` is bound to.
+    fn find_matches_in_placeholder(
+        &self,
+        code: &SyntaxNode,
+        placeholder_node: &SyntaxNode,
+        restrict_range: &Option<FileRange>,
+        restrict_kind: Option<PatternKind>,
+        depth: usize,
+        on_match: &mut dyn FnMut(Match),
+    ) {
+        // Don't search our placeholder if it's the entire matched node, otherwise we'd find the
+        // same match over and over until we got a stack overflow.
+        if placeholder_node != code {
+            let placeholder_depth =
+                depth + placeholder_node.ancestors().take_while(|a| a != code).count();
+            self.find_matches(
+                placeholder_node,
+                restrict_range,
+                restrict_kind,
+                placeholder_depth,
+                on_match,
+            );
+        }
+    }
+
+    /// Searches the children of `code` and, if `code` is a macro call, its expansion.
+    fn find_matches_in_children(
+        &self,
+        code: &SyntaxNode,
+        restrict_range: &Option<FileRange>,
+        restrict_kind: Option<PatternKind>,
+        depth: usize,
+        on_match: &mut dyn FnMut(Match),
+    ) {
+        if let Some(macro_call) = ast::MacroCall::cast(code.clone()) {
+            if let (Some(expanded), Some(tt)) =
+                (self.sema.expand(&macro_call), macro_call.token_tree())
+            {
+                // When matching within a macro expansion, we only want to allow matches of nodes
+                // that originated entirely from within the token tree of the macro call, not ones
+                // that came from the macro definition.
+                let tt_range = Some(self.sema.original_range(tt.syntax()));
+                self.find_matches(&expanded, &tt_range, restrict_kind, depth + 1, on_match);
+            } else if self.match_options.match_in_macro_arguments {
+                self.find_matches_in_macro_arguments(&macro_call, restrict_kind, depth, on_match);
+            }
+        }
         for child in code.children() {
-            self.find_matches(&child, restrict_range, matches_out);
+            self.find_matches(&child, restrict_range, restrict_kind, depth + 1, on_match);
+        }
+    }
+
+    /// Searches the arguments of `macro_call`, if it's one of `EXPRESSION_LIST_MACROS`, by parsing
+    /// them as the arguments of a function call. Since the parsed arguments aren't part of the file,
+    /// we search them in a database of their own, then move the matches to where the arguments are.
+    fn find_matches_in_macro_arguments(
+        &self,
+        macro_call: &ast::MacroCall,
+        restrict_kind: Option<PatternKind>,
+        depth: usize,
+        on_match: &mut dyn FnMut(Match),
+    ) {
+        let name = macro_call.path().and_then(|p| p.segment()).and_then(|s| s.name_ref());
+        if !matches!(&name, Some(name) if EXPRESSION_LIST_MACROS.contains(&name.text().as_str())) {
+            return;
+        }
+        let tt = match macro_call.token_tree() {
+            Some(tt) => tt.syntax().clone(),
+            None => return,
+        };
+        let tt_text = tt.text().to_string();
+        // Strip the delimiters, which could be any kind of bracket.
+        let arguments = match tt_text.get(1..tt_text.len().saturating_sub(1)) {
+            Some(arguments) => arguments,
+            None => return,
+        };
+        let prefix = "fn __ssr_macro_arguments() { __ssr_arguments(";
+        let source = format!("{}{}); }}", prefix, arguments);
+        // Arguments that don't parse, e.g. `vec![0; n]`, would produce misleading matches.
+        if !ast::SourceFile::parse(&source).errors().is_empty() {
+            return;
+        }
+        let (db, file_id) = single_file_db(&source);
+        let mut match_finder = MatchFinder::new(&db);
+        // Token-only rules will already have been matched against the macro call's token tree.
+        match_finder.rules =
+            self.rules.iter().filter(|r| !r.pattern.tokens_only).cloned().collect();
+        match_finder.match_options = self.match_options.clone();
+        let arguments_range = TextRange::at(TextSize::of(prefix), TextSize::of(arguments));
+        let arguments_start = self.sema.original_range(&tt).range.start() + TextSize::of("(");
+        let module_path = matching::lexical_module_path(macro_call.syntax());
+        let file = match_finder.sema.parse(file_id);
+        match_finder.find_matches(file.syntax(), &None, restrict_kind, depth, &mut |mut m| {
+            if !arguments_range.contains_range(m.range) {
+                return;
+            }
+            // Only the match's own range is moved, since everything else about it, such as the
+            // ranges of its placeholders, needs to agree with its nodes.
+            m.range = m.range - arguments_range.start() + arguments_start;
+            m.in_macro_arguments = true;
+            m.module_path = module_path.clone();
+            on_match(m);
+        });
+        self.search_errors.borrow_mut().extend(match_finder.search_errors.take());
+    }
+}
+
+/// Macros whose arguments are a comma-separated list of expressions, which we can search when we
+/// can't expand them.
+const EXPRESSION_LIST_MACROS: &[&str] = &[
+    "format_args",
+    "format",
+    "print",
+    "println",
+    "eprint",
+    "eprintln",
+    "write",
+    "writeln",
+    "panic",
+    "vec",
+    "assert",
+    "assert_eq",
+    "assert_ne",
+    "debug_assert",
+    "debug_assert_eq",
+    "debug_assert_ne",
+];
+
+/// Returns where `offset` is after `edit` is applied. Offsets within text that the edit replaced are
+/// taken to be at the end of the replacement.
+fn offset_after_edit(edit: &TextEdit, offset: TextSize) -> TextSize {
+    let mut result = offset;
+    for indel in edit.iter() {
+        if indel.delete.start() >= offset {
+            break;
         }
+        if offset < indel.delete.end() {
+            return result - (offset - indel.delete.start()) + TextSize::of(&indel.insert);
+        }
+        result += TextSize::of(&indel.insert);
+        result -= indel.delete.len();
+    }
+    result
+}
+
+/// Returns whether `edit` changes any of the text within `range`. Insertions at either end of the
+/// range don't count.
+fn edit_touches(edit: &TextEdit, range: TextRange) -> bool {
+    edit.iter()
+        .any(|indel| indel.delete.start() < range.end() && range.start() < indel.delete.end())
+}
+
+impl SsrError {
+    /// What sort of problem this is.
+    pub fn kind(&self) -> SsrErrorKind {
+        self.kind
+    }
+
+    /// The message describing the error, without the "Parse error: " prefix that `Display` adds to
+    /// errors in the text of a rule.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The range within the text of the rule of whatever caused the error, if known. e.g. for a
+    /// placeholder name that's used twice, the second use. For rules built with `SsrRuleBuilder`,
+    /// the range is within the search pattern or replacement template, as appropriate.
+    pub fn range(&self) -> Option<TextRange> {
+        self.range
     }
 }
 
 impl std::fmt::Display for SsrError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Parse error: {}", self.0)
+        match self.kind {
+            SsrErrorKind::MissingDelimiter
+            | SsrErrorKind::MultipleDelimiters
+            | SsrErrorKind::TokenizeFailed
+            | SsrErrorKind::InvalidPattern
+            | SsrErrorKind::DuplicatePlaceholder
+            | SsrErrorKind::UndefinedPlaceholder
+            | SsrErrorKind::InvalidRule => write!(f, "Parse error: {}", self.message()),
+            SsrErrorKind::TooDeep | SsrErrorKind::MatchCountMismatch | SsrErrorKind::Other => {
+                write!(f, "{}", self.message())
+            }
+        }
     }
 }
 
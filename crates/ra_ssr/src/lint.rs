@@ -0,0 +1,225 @@
+//! Checks rules for mistakes that don't stop them from being used, but that probably mean they
+//! won't do what was intended, e.g. a rule whose replacement is the same as its search pattern.
+
+use crate::parsing::{
+    replace_chain_wildcards, Constraint, PatternElement, PatternOptions, Placeholder,
+    PlaceholderKind, RawSearchPattern,
+};
+use crate::{SsrPattern, SsrRule};
+use ra_syntax::{SmolStr, SyntaxKind, SyntaxToken};
+use std::fmt;
+
+/// Something suspicious about a rule, found by `SsrRule::lint`. Unlike an `SsrError`, a warning
+/// doesn't stop the rule from being used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsrWarning {
+    /// The replacement template is the same as the search pattern, so the rule changes nothing.
+    NoOp,
+    /// The placeholder has constraints that no code can satisfy at once, e.g.
+    /// `${a:is_option:is_result}`, so the rule never matches.
+    ImpossibleConstraints { placeholder: SmolStr },
+    /// The placeholder is only used in the template where what it matches doesn't fit, e.g. a
+    /// `$t:ty` used as an expression, so replacing is likely to produce invalid code.
+    IncompatiblePlaceholder { placeholder: SmolStr },
+}
+
+impl fmt::Display for SsrWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SsrWarning::NoOp => {
+                write!(f, "Replacement is the same as the search pattern, so nothing will change")
+            }
+            SsrWarning::ImpossibleConstraints { placeholder } => write!(
+                f,
+                "Constraints on `${}` can't all be met, so the rule will never match",
+                placeholder
+            ),
+            SsrWarning::IncompatiblePlaceholder { placeholder } => write!(
+                f,
+                "`${}` is used in the replacement where what it matches doesn't fit",
+                placeholder
+            ),
+        }
+    }
+}
+
+impl SsrRule {
+    /// Returns warnings about anything suspicious in this rule, such as a replacement that's the
+    /// same as the search pattern. The rule can still be used regardless.
+    pub fn lint(&self) -> Vec<SsrWarning> {
+        let mut warnings = Vec::new();
+        let patterns = || std::iter::once(&self.pattern).chain(&self.alternatives);
+        if patterns().all(|pattern| same_tokens(&pattern.raw.tokens, &self.template.tokens)) {
+            warnings.push(SsrWarning::NoOp);
+        }
+        let mut placeholders: Vec<&Placeholder> = Vec::new();
+        for pattern in patterns() {
+            for element in &pattern.raw.tokens {
+                if let PatternElement::Placeholder(placeholder) = element {
+                    if !placeholders.iter().any(|p| p.ident == placeholder.ident) {
+                        placeholders.push(placeholder);
+                    }
+                }
+            }
+        }
+        for placeholder in &placeholders {
+            if has_impossible_constraints(placeholder) {
+                warnings.push(SsrWarning::ImpossibleConstraints {
+                    placeholder: placeholder.ident.clone(),
+                });
+            }
+        }
+        if !self.pattern.tokens_only {
+            let raw =
+                RawSearchPattern { tokens: replace_chain_wildcards(self.template.tokens.clone()) };
+            if let Ok(template) = SsrPattern::from_raw(raw, &PatternOptions::default()) {
+                for placeholder in &placeholders {
+                    if !fits_template(placeholder, &template) {
+                        warnings.push(SsrWarning::IncompatiblePlaceholder {
+                            placeholder: placeholder.ident.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        warnings
+    }
+}
+
+/// Returns whether `a` and `b` are the same, ignoring whitespace. Placeholders are compared by name
+/// and by any transform applied to them, since e.g. `$a:upper` changes what `$a` matched.
+fn same_tokens(a: &[PatternElement], b: &[PatternElement]) -> bool {
+    let significant = |elements: &[PatternElement]| -> Vec<String> {
+        elements
+            .iter()
+            .filter_map(|element| match element {
+                PatternElement::Token(token) if token.kind == SyntaxKind::WHITESPACE => None,
+                PatternElement::Token(token) => Some(token.text.to_string()),
+                PatternElement::Placeholder(placeholder) => match &placeholder.transform {
+                    Some(transform) => Some(format!("${}:{}", placeholder.ident, transform)),
+                    None => Some(format!("${}", placeholder.ident)),
+                },
+            })
+            .collect()
+    };
+    significant(a) == significant(b)
+}
+
+/// Returns whether `placeholder` has two constraints that exclude each other.
+fn has_impossible_constraints(placeholder: &Placeholder) -> bool {
+    const EXCLUSIVE: &[(Constraint, Constraint)] = &[
+        (Constraint::Lifetime, Constraint::TupleIndex),
+        (Constraint::Lifetime, Constraint::AnyLoop),
+        (Constraint::TupleIndex, Constraint::AnyLoop),
+        (Constraint::IsOption, Constraint::IsResult),
+        (Constraint::IsSnakeCase, Constraint::IsUpperCamelCase),
+    ];
+    let constraints = &placeholder.constraints;
+    let lifetime_with_kind =
+        placeholder.kind.is_some() && constraints.contains(&Constraint::Lifetime);
+    lifetime_with_kind
+        || EXCLUSIVE.iter().any(|(a, b)| constraints.contains(a) && constraints.contains(b))
+}
+
+/// Where a placeholder appears within a template.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Position {
+    Expr,
+    Type,
+    Pat,
+    /// Part of a longer path, e.g. the `$a` in `$a::new()`.
+    Path,
+    /// A name, such as that of a method or field.
+    Name,
+    Lifetime,
+}
+
+/// Returns false if `placeholder` has a known kind and each use of it in `template` is somewhere
+/// that kind of code can't go, however the template is parsed. Returns true if we can't tell.
+fn fits_template(placeholder: &Placeholder, template: &SsrPattern) -> bool {
+    let fits: fn(Position) -> bool = if placeholder.constraints.contains(&Constraint::Lifetime) {
+        |position| position == Position::Lifetime
+    } else {
+        match placeholder.kind {
+            Some(PlaceholderKind::Expr) | Some(PlaceholderKind::Block) => {
+                |position| position == Position::Expr
+            }
+            Some(PlaceholderKind::Literal) => {
+                |position| matches!(position, Position::Expr | Position::Pat)
+            }
+            Some(PlaceholderKind::Ty) => {
+                |position| matches!(position, Position::Type | Position::Path)
+            }
+            Some(PlaceholderKind::Pat) => |position| position == Position::Pat,
+            Some(PlaceholderKind::Path) => {
+                |position| position != Position::Name && position != Position::Lifetime
+            }
+            Some(PlaceholderKind::Ident) => |position| position != Position::Lifetime,
+            None => return true,
+        }
+    };
+    let trees = [
+        &template.expr,
+        &template.type_ref,
+        &template.item,
+        &template.path,
+        &template.pattern,
+        &template.variant,
+        &template.stmt,
+        &template.attr,
+    ];
+    let mut used = false;
+    for tree in trees.iter().filter_map(|tree| tree.as_ref()) {
+        for token in tree.descendants_with_tokens().filter_map(|e| e.into_token()) {
+            let is_use = matches!(
+                template.placeholders_by_stand_in.get(token.text().as_str()),
+                Some(p) if p.ident == placeholder.ident
+            );
+            if !is_use {
+                continue;
+            }
+            used = true;
+            match position(&token) {
+                Some(position) if !fits(position) => {}
+                _ => return true,
+            }
+        }
+    }
+    !used
+}
+
+/// Returns the position of the stand-in `token` within its tree, if it's one we recognise.
+fn position(token: &SyntaxToken) -> Option<Position> {
+    if token.kind() == SyntaxKind::LIFETIME {
+        return Some(Position::Lifetime);
+    }
+    let parent = token.parent();
+    match parent.kind() {
+        SyntaxKind::NAME => {
+            if parent.parent().map(|p| p.kind()) == Some(SyntaxKind::BIND_PAT) {
+                Some(Position::Pat)
+            } else {
+                Some(Position::Name)
+            }
+        }
+        SyntaxKind::NAME_REF => {
+            let segment = parent.parent()?;
+            if segment.kind() != SyntaxKind::PATH_SEGMENT {
+                return Some(Position::Name);
+            }
+            let path = segment.parent()?;
+            let in_longer_path = path.children().any(|c| c.kind() == SyntaxKind::PATH)
+                || path.parent()?.kind() == SyntaxKind::PATH;
+            if in_longer_path {
+                return Some(Position::Path);
+            }
+            match path.parent()?.kind() {
+                SyntaxKind::PATH_EXPR => Some(Position::Expr),
+                SyntaxKind::PATH_TYPE => Some(Position::Type),
+                SyntaxKind::PATH_PAT => Some(Position::Pat),
+                _ => Some(Position::Path),
+            }
+        }
+        _ => None,
+    }
+}
@@ -2,14 +2,18 @@
 //! process of matching, placeholder values are recorded.
 
 use crate::{
-    parsing::{Placeholder, SsrTemplate},
-    SsrMatches, SsrPattern, SsrRule,
+    parsing::{
+        parse_fragment, single_ident, Constraint, PatternElement, Placeholder, PlaceholderKind,
+        SsrTemplate, CHAIN_WILDCARD_STAND_IN,
+    },
+    PatternKind, SsrMatches, SsrPattern, SsrRule,
 };
-use hir::Semantics;
+use hir::{HirDisplay, Semantics};
 use ra_db::FileRange;
-use ra_syntax::ast::{AstNode, AstToken};
+use ra_syntax::ast::{AstNode, AstToken, NameOwner};
 use ra_syntax::{
-    ast, SyntaxElement, SyntaxElementChildren, SyntaxKind, SyntaxNode, SyntaxToken, TextRange,
+    ast, SmolStr, SyntaxElement, SyntaxElementChildren, SyntaxKind, SyntaxNode, SyntaxToken,
+    TextRange, T,
 };
 use rustc_hash::FxHashMap;
 use std::{cell::Cell, iter::Peekable};
@@ -17,13 +21,17 @@ use std::{cell::Cell, iter::Peekable};
 // Creates a match error. If we're currently attempting to match some code that we thought we were
 // going to match, as indicated by the --debug-snippet flag, then populate the reason field.
 macro_rules! match_error {
+    (kind = $kind:expr, $($args:tt)+) => {{
+        MatchFailed { kind: $kind, ..match_error!($($args)+) }
+    }};
     ($e:expr) => {{
             MatchFailed {
                 reason: if recording_match_fail_reasons() {
                     Some(format!("{}", $e))
                 } else {
                     None
-                }
+                },
+                kind: MismatchKind::Other,
             }
     }};
     ($fmt:expr, $($arg:tt)+) => {{
@@ -32,7 +40,8 @@ macro_rules! match_error {
                 Some(format!($fmt, $($arg)+))
             } else {
                 None
-            }
+            },
+            kind: MismatchKind::Other,
         }
     }};
 }
@@ -44,14 +53,27 @@ macro_rules! fail_match {
 
 /// Information about a match that was found.
 #[derive(Debug)]
-pub(crate) struct Match {
+pub struct Match {
     pub(crate) range: TextRange,
     pub(crate) matched_node: SyntaxNode,
     pub(crate) placeholder_values: FxHashMap<Var, PlaceholderMatch>,
     pub(crate) ignored_comments: Vec<ast::Comment>,
+    /// Qualifiers such as `const` on the matched function that the pattern didn't mention. These
+    /// are added to the replacement, either before its `fn` or where it uses `$qualifiers`.
+    pub(crate) implicit_qualifiers: Vec<SyntaxToken>,
+    /// Attributes on the matched item that the pattern didn't mention, when matching with
+    /// `MatchOptions::required_attribute`. These are added to the start of the replacement.
+    pub(crate) implicit_attributes: Vec<ast::Attr>,
     // A copy of the template for the rule that produced this match. We store this on the match for
     // if/when we do replacement.
     pub(crate) template: SsrTemplate,
+    /// The index, within the rules being searched for, of the rule that produced this match.
+    pub(crate) rule_index: usize,
+    /// The names of the `mod` blocks that lexically enclose the match, outermost first.
+    pub(crate) module_path: Vec<SmolStr>,
+    /// Whether the match was found by parsing the arguments of a macro call that we couldn't
+    /// expand. Only `range` is within the file.
+    pub(crate) in_macro_arguments: bool,
 }
 
 /// Represents a `$var` in an SSR query.
@@ -64,9 +86,15 @@ pub(crate) struct PlaceholderMatch {
     /// The node that the placeholder matched to. If set, then we'll search for further matches
     /// within this node. It isn't set when we match tokens within a macro call's token tree.
     pub(crate) node: Option<SyntaxNode>,
+    /// The nodes matched by a multi-placeholder. We'll search for further matches within each of
+    /// these.
+    pub(crate) nodes: Vec<SyntaxNode>,
     pub(crate) range: FileRange,
     /// More matches, found within `node`.
     pub(crate) inner_matches: SsrMatches,
+    /// If set, the text to use for this placeholder in the replacement, rather than the code that
+    /// it matched. Used when we bind a placeholder to an inferred type.
+    pub(crate) inferred_text: Option<String>,
 }
 
 #[derive(Debug)]
@@ -80,8 +108,109 @@ pub(crate) struct MatchFailed {
     /// The reason why we failed to match. Only present when debug_active true in call to
     /// `get_match`.
     pub(crate) reason: Option<String>,
+    /// What sort of difference between the pattern and the code caused the failure.
+    pub(crate) kind: MismatchKind,
+}
+
+/// A sort of difference between a search pattern and some code that stops the code from matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// The code has a different kind of node or token to the pattern, e.g. a method call where the
+    /// pattern has a function call.
+    Kind,
+    /// The code has a token of the same kind as the pattern, but with different text, e.g. a
+    /// different identifier.
+    Token,
+    /// A placeholder's constraint, kind or text constraint wasn't met, or a repeated placeholder
+    /// matched different code.
+    Constraint,
+    /// The code was nested more deeply than `MatchOptions::max_depth`, so wasn't fully compared.
+    TooDeep,
+    /// Anything else, e.g. the code having more or fewer elements than the pattern.
+    Other,
+}
+
+/// Whether a candidate node matched a rule and, if not, where matching diverged. See `trace_match`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchTrace {
+    Matched,
+    Diverged {
+        kind: MismatchKind,
+        /// A description of the step where matching diverged.
+        reason: String,
+    },
+}
+
+/// Options that control which code our search patterns are considered to match.
+#[derive(Debug, Clone, Default)]
+pub struct MatchOptions {
+    /// If true, method and function calls with explicit generic arguments, e.g. `x.parse::<u32>()`
+    /// or `foo::<u32>(x)`, will match patterns without them, e.g. `$a.parse()` or `foo($a)`.
+    pub ignore_turbofish: bool,
+    /// If true, redundant parentheses on either side are ignored, so `foo($a + $b)` will match
+    /// `foo((x + y))` and vice versa. A placeholder still captures any parentheses around what it
+    /// matches. Replacement only inserts parentheses to preserve precedence where a placeholder is
+    /// followed by a method call, field access, index or `?`, so stripping them from a placeholder's
+    /// value could change the meaning of the replacement elsewhere. Likewise, the
+    /// outermost parentheses of a matched expression are ignored only on the pattern side.
+    pub ignore_parens: bool,
+    /// If set, code longer than this many bytes won't be matched. This guards against a pattern
+    /// like `$a` unexpectedly matching some enormous expression.
+    pub max_match_size: Option<usize>,
+    /// If true, string literals that differ only in the amount of whitespace between words will
+    /// match, e.g. `"SELECT  *"` and `"SELECT *"`. By default string literals must match exactly.
+    pub collapse_string_whitespace: bool,
+    /// If set, only code within the arguments of a call to a function, method or macro with this
+    /// name will be matched. e.g. `Some("assert".to_owned())` restricts matches to code inside
+    /// `assert!(...)`.
+    pub within_call: Option<String>,
+    /// If true, a match is skipped if the replacement calls a method on a placeholder, e.g.
+    /// `$r.bar()`, and the type of what the placeholder matched has no such method. This avoids
+    /// producing code that doesn't compile. If the type or its crate can't be determined, the
+    /// match is kept.
+    pub check_template_methods: bool,
+    /// If set, only items with an attribute whose path is this, e.g. `Some("test".to_owned())` for
+    /// `#[test]`, will be matched. The item's attributes then needn't be in the pattern, so
+    /// `fn $n() { $body:block }` matches `#[test] fn f() {}`, and any that aren't are kept when
+    /// we do replacement.
+    pub required_attribute: Option<String>,
+    /// If true, the doc comments of an item must be the same as those of the pattern, e.g.
+    /// `/** Old docs */ fn $n() {}` only matches functions with that doc comment, and they're only
+    /// reproduced in the replacement if the template includes them. By default, comments are
+    /// ignored when matching and added to the end of the replacement.
+    pub match_doc_comments: bool,
+    /// If true, the arguments of calls to well known macros such as `println!`, `vec!` and
+    /// `assert!` are parsed as a list of expressions and searched, if we can't expand the macro.
+    /// This can find matches that the real macro wouldn't have, so they're marked with
+    /// `Match::is_in_macro_arguments`.
+    pub match_in_macro_arguments: bool,
+    /// If true, numeric literals in the pattern match those in the code that differ only in their
+    /// underscores and type suffix, e.g. `1000` matches `1_000` and `1000u64`. This doesn't affect
+    /// what placeholders match.
+    pub normalize_numeric_literals: bool,
+    /// If true, as well as `normalize_numeric_literals`, integer literals match regardless of their
+    /// base, e.g. `1000` matches `0x3e8`. Has no effect unless `normalize_numeric_literals` is set.
+    pub normalize_numeric_base: bool,
+    /// How deeply nested code can be, relative to where a search starts, for us to search or match
+    /// it. Deeper code is skipped and an error is recorded in the `SsrMatches`, rather than risking
+    /// a stack overflow on pathological input. If unset, the limit is `DEFAULT_MAX_DEPTH`.
+    pub max_depth: Option<usize>,
+}
+
+/// The default for `MatchOptions::max_depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+impl MatchOptions {
+    pub(crate) fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
 }
 
+/// Function qualifiers that a function pattern matches regardless of whether they're present, unless
+/// the pattern includes them. `unsafe` is deliberately not included, since a pattern for a safe
+/// function shouldn't match an unsafe one.
+const IMPLICIT_FN_QUALIFIERS: &[SyntaxKind] = &[SyntaxKind::CONST_KW, SyntaxKind::ASYNC_KW];
+
 /// Checks if `code` matches the search pattern found in `search_scope`, returning information about
 /// the match, if it does. Since we only do matching in this module and searching is done by the
 /// parent module, we don't populate nested matches.
@@ -91,12 +220,123 @@ pub(crate) fn get_match(
     code: &SyntaxNode,
     restrict_range: &Option<FileRange>,
     sema: &Semantics<ra_ide_db::RootDatabase>,
+    options: &MatchOptions,
 ) -> Result<Match, MatchFailed> {
     record_match_fails_reasons_scope(debug_active, || {
-        MatchState::try_match(rule, code, restrict_range, sema)
+        MatchState::try_match(rule, code, restrict_range, sema, options)
     })
 }
 
+/// Finds the matches of `rule`, which must have a token-only search pattern, among the tokens
+/// directly within `token_tree`. Matches don't overlap and are returned in order.
+pub(crate) fn get_token_matches(
+    rule: &SsrRule,
+    token_tree: &SyntaxNode,
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+) -> Vec<Match> {
+    let original_range = sema.original_range(token_tree);
+    // Placeholder ranges are taken directly from the token tree, so they would be wrong for one
+    // that came from a macro expansion.
+    if original_range.range != token_tree.text_range() {
+        return Vec::new();
+    }
+    let elements: Vec<SyntaxElement> =
+        token_tree.children_with_tokens().filter(|e| !e.kind().is_trivia()).collect();
+    // Skip the delimiters.
+    let elements = match elements.len() {
+        0 | 1 => return Vec::new(),
+        len => &elements[1..len - 1],
+    };
+    let pattern: Vec<&PatternElement> = rule
+        .pattern
+        .raw
+        .tokens
+        .iter()
+        .filter(|e| match e {
+            PatternElement::Token(t) => !t.kind.is_trivia(),
+            PatternElement::Placeholder(_) => true,
+        })
+        .collect();
+    let mut matches = Vec::new();
+    let mut start = 0;
+    while start < elements.len() {
+        match match_tokens_at(&pattern, elements, start, original_range.file_id) {
+            Some((end, placeholder_values)) => {
+                let range = TextRange::new(
+                    elements[start].text_range().start(),
+                    elements[end - 1].text_range().end(),
+                );
+                matches.push(Match {
+                    range,
+                    matched_node: token_tree.clone(),
+                    placeholder_values,
+                    ignored_comments: Vec::new(),
+                    implicit_qualifiers: Vec::new(),
+                    implicit_attributes: Vec::new(),
+                    template: rule.template.clone(),
+                    rule_index: 0,
+                    module_path: lexical_module_path(token_tree),
+                    in_macro_arguments: false,
+                });
+                start = end;
+            }
+            None => start += 1,
+        }
+    }
+    matches
+}
+
+/// Tries to match `pattern` against `elements`, starting at index `start`. Returns the index after
+/// the last matched element and what each placeholder matched.
+fn match_tokens_at(
+    pattern: &[&PatternElement],
+    elements: &[SyntaxElement],
+    start: usize,
+    file_id: ra_db::FileId,
+) -> Option<(usize, FxHashMap<Var, PlaceholderMatch>)> {
+    let mut placeholder_values = FxHashMap::default();
+    let mut pos = start;
+    for (index, pattern_element) in pattern.iter().enumerate() {
+        match pattern_element {
+            PatternElement::Token(token) => {
+                if elements.get(pos)?.as_token()?.text() != &token.text {
+                    return None;
+                }
+                pos += 1;
+            }
+            PatternElement::Placeholder(placeholder) => {
+                let placeholder_start = pos;
+                let next_text = pattern.get(index + 1).and_then(|e| match e {
+                    PatternElement::Token(t) => Some(&t.text),
+                    PatternElement::Placeholder(_) => None,
+                });
+                // A placeholder matches at least one token.
+                pos += 1;
+                while let Some(element) = elements.get(pos) {
+                    if next_text.is_some() && element.as_token().map(|t| t.text()) == next_text {
+                        break;
+                    }
+                    pos += 1;
+                }
+                if pos > elements.len() {
+                    return None;
+                }
+                let range = TextRange::new(
+                    elements[placeholder_start].text_range().start(),
+                    elements[pos - 1].text_range().end(),
+                );
+                if !placeholder.anonymous {
+                    placeholder_values.insert(
+                        Var(placeholder.ident.to_string()),
+                        PlaceholderMatch::from_range(FileRange { file_id, range }),
+                    );
+                }
+            }
+        }
+    }
+    Some((pos, placeholder_values))
+}
+
 /// Inputs to matching. This cannot be part of `MatchState`, since we mutate `MatchState` and in at
 /// least one case need to hold a borrow of a placeholder from the input pattern while calling a
 /// mutable `MatchState` method.
@@ -107,6 +347,7 @@ struct MatchInputs<'pattern> {
 /// State used while attempting to match our search pattern against a particular node of the AST.
 struct MatchState<'db, 'sema> {
     sema: &'sema Semantics<'db, ra_ide_db::RootDatabase>,
+    options: &'sema MatchOptions,
     /// If any placeholders come from anywhere outside of this range, then the match will be
     /// rejected.
     restrict_range: Option<FileRange>,
@@ -118,6 +359,8 @@ struct MatchState<'db, 'sema> {
     /// method calls that aren't calls to `foo` on the first pass and only insert into the
     /// placeholders map on the second pass. Likewise for ignored comments.
     match_out: Option<Match>,
+    /// How many calls to `attempt_match_node` we're currently nested within.
+    depth: usize,
 }
 
 impl<'db, 'sema> MatchState<'db, 'sema> {
@@ -126,25 +369,79 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         code: &SyntaxNode,
         restrict_range: &Option<FileRange>,
         sema: &'sema Semantics<'db, ra_ide_db::RootDatabase>,
+        options: &'sema MatchOptions,
     ) -> Result<Match, MatchFailed> {
-        let mut match_state =
-            MatchState { sema, restrict_range: restrict_range.clone(), match_out: None };
-        let match_inputs = MatchInputs { ssr_pattern: &rule.pattern };
-        let pattern_tree = rule.pattern.tree_for_kind(code.kind())?;
+        if let Some(max_match_size) = options.max_match_size {
+            let size = usize::from(code.text_range().len());
+            if size > max_match_size {
+                fail_match!(
+                    "Code is {} bytes, which exceeds the maximum of {}",
+                    size,
+                    max_match_size
+                );
+            }
+        }
+        let mut failure = None;
+        for pattern in std::iter::once(&rule.pattern).chain(&rule.alternatives) {
+            match Self::try_match_pattern(rule, pattern, code, restrict_range, sema, options) {
+                Ok(m) => return Ok(m),
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.expect("rules have at least one pattern"))
+    }
+
+    /// Attempts to match `code` against `pattern`, which is one of the alternatives of `rule`.
+    fn try_match_pattern(
+        rule: &SsrRule,
+        pattern: &SsrPattern,
+        code: &SyntaxNode,
+        restrict_range: &Option<FileRange>,
+        sema: &'sema Semantics<'db, ra_ide_db::RootDatabase>,
+        options: &'sema MatchOptions,
+    ) -> Result<Match, MatchFailed> {
+        let mut match_state = MatchState {
+            sema,
+            options,
+            restrict_range: restrict_range.clone(),
+            match_out: None,
+            depth: 0,
+        };
+        let match_inputs = MatchInputs { ssr_pattern: pattern };
+        let pattern_tree = pattern.tree_for_kind(code.kind())?;
         // First pass at matching, where we check that node types and idents match.
         match_state.attempt_match_node(&match_inputs, &pattern_tree, code)?;
         match_state.validate_range(&sema.original_range(code))?;
+        if let Some(call_name) = &options.within_call {
+            if !is_within_call(sema, code, call_name) {
+                fail_match!("Code is not within a call to `{}`", call_name);
+            }
+        }
+        if let Some(attr_path) = &options.required_attribute {
+            if !has_attribute(code, attr_path) {
+                fail_match!("Code doesn't have the attribute `#[{}]`", attr_path);
+            }
+        }
         match_state.match_out = Some(Match {
             range: sema.original_range(code).range,
             matched_node: code.clone(),
             placeholder_values: FxHashMap::default(),
             ignored_comments: Vec::new(),
+            implicit_qualifiers: Vec::new(),
+            implicit_attributes: Vec::new(),
             template: rule.template.clone(),
+            rule_index: 0,
+            module_path: lexical_module_path(code),
+            in_macro_arguments: false,
         });
         // Second matching pass, where we record placeholder matches, ignored comments and maybe do
         // any other more expensive checks that we didn't want to do on the first pass.
         match_state.attempt_match_node(&match_inputs, &pattern_tree, code)?;
-        Ok(match_state.match_out.unwrap())
+        let m = match_state.match_out.unwrap();
+        if options.check_template_methods {
+            check_template_methods(sema, &m)?;
+        }
+        Ok(m)
     }
 
     /// Checks that `range` is within the permitted range if any. This is applicable when we're
@@ -166,29 +463,139 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         match_inputs: &MatchInputs,
         pattern: &SyntaxNode,
         code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        // Deeply nested code, e.g. thousands of parentheses when ignoring parentheses, could
+        // otherwise overflow the stack.
+        if self.depth >= self.options.max_depth() {
+            fail_match!(
+                kind = MismatchKind::TooDeep,
+                "Matching is nested more than {} levels deep",
+                self.options.max_depth()
+            );
+        }
+        self.depth += 1;
+        let result = self.attempt_match_node_unchecked(match_inputs, pattern, code);
+        self.depth -= 1;
+        result
+    }
+
+    fn attempt_match_node_unchecked(
+        &mut self,
+        match_inputs: &MatchInputs,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
         // Handle placeholders.
         if let Some(placeholder) =
             match_inputs.get_placeholder(&SyntaxElement::Node(pattern.clone()))
         {
+            if let Some(constraint) =
+                placeholder.constraints.iter().find(|c| !self.satisfies_constraint(c, code))
+            {
+                fail_match!(
+                    kind = MismatchKind::Constraint,
+                    "Placeholder `{}` has constraint {:?}, which code `{}` doesn't satisfy",
+                    placeholder.ident,
+                    constraint,
+                    code.text()
+                );
+            }
+            // In a slice or tuple pattern, `..` and `rest @ ..` match any number of elements, so a
+            // placeholder, which stands for a single element, mustn't match them.
+            if is_rest_pattern(code) {
+                fail_match!(
+                    kind = MismatchKind::Constraint,
+                    "Placeholder `{}` can't match the rest pattern `{}`",
+                    placeholder.ident,
+                    code.text()
+                );
+            }
+            if let Some(kind) = placeholder.kind {
+                if !kind.matches(code) {
+                    fail_match!(
+                        kind = MismatchKind::Constraint,
+                        "Placeholder `{}` only matches {:?}, but code `{}` is a {:?}",
+                        placeholder.ident,
+                        kind,
+                        code.text(),
+                        code.kind()
+                    );
+                }
+            }
+            if let Some(text_constraint) = &placeholder.text_constraint {
+                if !text_constraint.is_match(&code.text().to_string()) {
+                    fail_match!(
+                        kind = MismatchKind::Constraint,
+                        "Placeholder `{}` only matches text matching `{}`, but code is `{}`",
+                        placeholder.ident,
+                        text_constraint.source,
+                        code.text()
+                    );
+                }
+            }
             if self.match_out.is_none() {
                 return Ok(());
             }
+            // A placeholder that appears more than once must match the same code each time. We
+            // keep what the first occurrence matched.
+            if let Some(earlier) = self.placeholder_value(placeholder) {
+                if !matches!(&earlier.node, Some(earlier) if same_code(earlier, code)) {
+                    fail_match!(
+                        kind = MismatchKind::Constraint,
+                        "Placeholder `{}` matched `{}` earlier, but here the code is `{}`",
+                        placeholder.ident,
+                        earlier.node.as_ref().map(|n| n.text().to_string()).unwrap_or_default(),
+                        code.text()
+                    );
+                }
+                return Ok(());
+            }
             let original_range = self.sema.original_range(code);
             // We validated the range for the node when we started the match, so the placeholder
             // probably can't fail range validation, but just to be safe...
             self.validate_range(&original_range)?;
+            let mut placeholder_match = PlaceholderMatch::new(code, original_range);
+            // A placeholder in `$t::default()` matches the `Default` in `Default::default()`, but
+            // is bound to the type that the call produces, so that it can be used in the
+            // replacement. We only need the type once we're recording the match. If the type is
+            // unknown, or can't be written where the call is, there's no match.
+            if let (Some(call), Some(_)) = (default_trait_call(code), &self.match_out) {
+                match self.nameable_type_of(&call) {
+                    Some(text) => placeholder_match.inferred_text = Some(text),
+                    None => fail_match!("Couldn't name the type of `{}`", call.syntax().text()),
+                }
+            }
             if let Some(match_out) = &mut self.match_out {
-                match_out.placeholder_values.insert(
-                    Var(placeholder.ident.to_string()),
-                    PlaceholderMatch::new(code, original_range),
-                );
+                match_out.bind(placeholder, placeholder_match);
             }
             return Ok(());
         }
+        if let Some(receiver_pattern) = chain_wildcard_receiver(pattern) {
+            return self.attempt_match_chain_wildcard(match_inputs, &receiver_pattern, code);
+        }
+        if self.options.ignore_parens {
+            if code.kind() != SyntaxKind::PAREN_EXPR {
+                if let Some(inner) = paren_inner(pattern) {
+                    return self.attempt_match_node(match_inputs, &inner, code);
+                }
+            }
+            // If we unwrapped the code at the root of the match, we'd replace the parentheses
+            // along with their contents. We don't need to though, since we'll try matching the
+            // contents separately.
+            if pattern.kind() != SyntaxKind::PAREN_EXPR && !is_pattern_root(pattern) {
+                if let Some(inner) = paren_inner(code) {
+                    return self.attempt_match_node(match_inputs, pattern, &inner);
+                }
+            }
+        }
         // Non-placeholders.
         if pattern.kind() != code.kind() {
-            fail_match!("Pattern had a {:?}, code had {:?}", pattern.kind(), code.kind());
+            fail_match!(
+                kind = MismatchKind::Kind,
+                "Pattern had a {:?}, code had {:?}",
+                pattern.kind(),
+                code.kind()
+            );
         }
         // Some kinds of nodes have special handling. For everything else, we fall back to default
         // matching.
@@ -197,20 +604,238 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
                 self.attempt_match_record_field_list(match_inputs, pattern, code)
             }
             SyntaxKind::TOKEN_TREE => self.attempt_match_token_tree(match_inputs, pattern, code),
+            SyntaxKind::ATTR => self.attempt_match_attr(match_inputs, pattern, code),
+            SyntaxKind::USE_TREE => self.attempt_match_use_tree(match_inputs, pattern, code),
             _ => self.attempt_match_node_children(match_inputs, pattern, code),
         }
     }
 
+    /// A placeholder that is the whole of an attribute's contents, as in `#[$attr]`, matches
+    /// whatever is within the brackets of the attribute in the code, e.g. `cfg(test)`. Other
+    /// attributes get default matching.
+    fn attempt_match_attr(
+        &mut self,
+        match_inputs: &MatchInputs,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let (pattern_attr, code_attr) =
+            match (ast::Attr::cast(pattern.clone()), ast::Attr::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(match_inputs, pattern, code),
+            };
+        let placeholder = match pattern_attr.path() {
+            Some(path) if pattern_attr.input().is_none() => {
+                match_inputs.get_placeholder(&SyntaxElement::Node(path.syntax().clone()))
+            }
+            _ => None,
+        };
+        let (placeholder, code_path) = match (placeholder, code_attr.path()) {
+            (Some(placeholder), Some(code_path)) => (placeholder, code_path),
+            _ => return self.attempt_match_node_children(match_inputs, pattern, code),
+        };
+        if pattern_attr.excl_token().is_some() != code_attr.excl_token().is_some() {
+            fail_match!("Pattern and code differ in whether the attribute is an inner attribute");
+        }
+        let start = self.sema.original_range(code_path.syntax());
+        let end = match code_attr.input() {
+            Some(ast::AttrInput::Literal(literal)) => self.sema.original_range(literal.syntax()),
+            Some(ast::AttrInput::TokenTree(tt)) => self.sema.original_range(tt.syntax()),
+            None => start,
+        };
+        let range = FileRange {
+            file_id: start.file_id,
+            range: TextRange::new(start.range.start(), end.range.end()),
+        };
+        self.validate_range(&range)?;
+        if let Some(match_out) = &mut self.match_out {
+            match_out.bind(placeholder, PlaceholderMatch::from_range(range));
+        }
+        Ok(())
+    }
+
+    /// An unconstrained placeholder at the end of the path of a use tree, as in `use foo::$x`,
+    /// matches everything after the corresponding `::` in the code, e.g. `bar::Baz`, `{a, b}` or
+    /// `Baz as Qux`, not just a single path segment. Other use trees get default matching.
+    fn attempt_match_use_tree(
+        &mut self,
+        match_inputs: &MatchInputs,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let (pattern_tree, code_tree) =
+            match (ast::UseTree::cast(pattern.clone()), ast::UseTree::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(match_inputs, pattern, code),
+            };
+        let tail = pattern_tree.path().filter(|_| is_plain_path(&pattern_tree)).and_then(|path| {
+            let placeholder = match_inputs
+                .get_placeholder(&SyntaxElement::Node(path.segment()?.syntax().clone()))?;
+            Some((path.qualifier()?, placeholder))
+        });
+        let (qualifier, placeholder) = match (tail, code_tree.path()) {
+            (Some((qualifier, placeholder)), Some(code_path))
+                if placeholder.constraints.is_empty()
+                    && placeholder.kind.is_none()
+                    && placeholder.text_constraint.is_none()
+                    && !(is_plain_path(&code_tree)
+                        && code_path.qualifier().map(|q| path_len(&q))
+                            == Some(path_len(&qualifier))) =>
+            {
+                (qualifier, placeholder)
+            }
+            // A single segment after the qualifier can be matched as usual.
+            _ => return self.attempt_match_node_children(match_inputs, pattern, code),
+        };
+        let code_prefix = std::iter::successors(code_tree.path(), |p| p.qualifier())
+            .find(|p| path_len(p) == path_len(&qualifier))
+            .ok_or_else(|| {
+                match_error!("Use tree `{}` is too short for pattern `{}`", code.text(), pattern)
+            })?;
+        self.attempt_match_node(match_inputs, qualifier.syntax(), code_prefix.syntax())?;
+        let mut after_prefix =
+            std::iter::successors(code_prefix.syntax().next_sibling_or_token(), |e| {
+                e.next_sibling_or_token()
+            })
+            .filter(|e| !e.kind().is_trivia());
+        let tail_start = match (after_prefix.next(), after_prefix.next()) {
+            (Some(colons), Some(tail)) if colons.kind() == T![::] => tail.text_range().start(),
+            _ => fail_match!("Use tree `{}` has nothing after `{}`", code.text(), code_prefix),
+        };
+        let original_range = self.sema.original_range(code);
+        let range = FileRange {
+            file_id: original_range.file_id,
+            range: TextRange::new(tail_start, code.text_range().end()),
+        };
+        self.validate_range(&range)?;
+        if let Some(match_out) = &mut self.match_out {
+            if let Some(earlier) =
+                match_out.placeholder_values.get(&Var(placeholder.ident.to_string()))
+            {
+                if match_out.text_in_range(earlier.range.range)
+                    != match_out.text_in_range(range.range)
+                {
+                    fail_match!(
+                        kind = MismatchKind::Constraint,
+                        "Placeholder `{}` matched differently earlier",
+                        placeholder.ident
+                    );
+                }
+            } else {
+                match_out.bind(placeholder, PlaceholderMatch::from_range(range));
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches `code` against a receiver followed by `.**`, which can match any number of method
+    /// calls. We consume as many method calls as we can, so for `$r.**.unwrap()`, `$r` will match
+    /// `x` in `x.a().b().unwrap()`.
+    fn attempt_match_chain_wildcard(
+        &mut self,
+        match_inputs: &MatchInputs,
+        receiver_pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let mut receivers = vec![code.clone()];
+        while let Some(receiver) = receivers
+            .last()
+            .and_then(|r| ast::MethodCallExpr::cast(r.clone()))
+            .and_then(|call| call.expr())
+        {
+            receivers.push(receiver.syntax().clone());
+        }
+        let mut result = Ok(());
+        for receiver in receivers.iter().rev() {
+            // Try the match without recording anything, so that a failed attempt doesn't leave
+            // placeholder values behind.
+            let match_out = self.match_out.take();
+            result = self.attempt_match_node(match_inputs, receiver_pattern, receiver);
+            self.match_out = match_out;
+            if result.is_ok() {
+                return self.attempt_match_node(match_inputs, receiver_pattern, receiver);
+            }
+        }
+        result
+    }
+
     fn attempt_match_node_children(
         &mut self,
         match_inputs: &MatchInputs,
         pattern: &SyntaxNode,
         code: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
+        if match_inputs.ssr_pattern.exact_whitespace {
+            check_whitespace_lines_up(match_inputs, pattern, code)?;
+        }
+        if self.options.ignore_turbofish
+            && has_turbofish(code)
+            && !pattern.children().any(|p| p.kind() == SyntaxKind::TYPE_ARG_LIST)
+        {
+            return self.attempt_match_sequences(
+                match_inputs,
+                PatternIterator::new(pattern),
+                code.children_with_tokens().filter(|c| c.kind() != SyntaxKind::TYPE_ARG_LIST),
+                code,
+            );
+        }
+        // A function pattern that doesn't mention some qualifier, e.g. `const`, matches functions
+        // both with and without it. Likewise for attributes, if we only match items with a
+        // particular attribute. The qualifiers and attributes that we skip are kept when we do
+        // replacement. We only do this for the item that is the whole pattern, since that's the only
+        // one we'd know where to put them back on.
+        let mut skipped: Vec<SyntaxElement> = Vec::new();
+        if code.kind() == SyntaxKind::FN_DEF && is_pattern_root(pattern) {
+            let qualifiers: Vec<SyntaxToken> = code
+                .children_with_tokens()
+                .filter_map(|c| c.into_token())
+                .filter(|t| {
+                    IMPLICIT_FN_QUALIFIERS.contains(&t.kind())
+                        && !pattern.children_with_tokens().any(|p| p.kind() == t.kind())
+                })
+                .collect();
+            skipped.extend(qualifiers.iter().cloned().map(SyntaxElement::Token));
+            if let Some(match_out) = &mut self.match_out {
+                match_out.implicit_qualifiers = qualifiers;
+            }
+        }
+        if self.options.required_attribute.is_some()
+            && is_pattern_root(pattern)
+            && !pattern.children().any(|p| p.kind() == SyntaxKind::ATTR)
+        {
+            let attrs: Vec<ast::Attr> = code.children().filter_map(ast::Attr::cast).collect();
+            skipped.extend(attrs.iter().map(|a| SyntaxElement::Node(a.syntax().clone())));
+            if let Some(match_out) = &mut self.match_out {
+                match_out.implicit_attributes = attrs;
+            }
+        }
+        if self.options.match_doc_comments {
+            let (pattern_docs, code_docs) = (doc_comments(pattern), doc_comments(code));
+            let text = |docs: &[ast::Comment]| -> Vec<String> {
+                docs.iter().map(|c| c.text().trim().to_owned()).collect()
+            };
+            if text(&pattern_docs) != text(&code_docs) {
+                fail_match!(
+                    "Pattern has doc comments {:?}, but code has {:?}",
+                    text(&pattern_docs),
+                    text(&code_docs)
+                );
+            }
+            skipped.extend(code_docs.iter().map(|c| SyntaxElement::Token(c.syntax().clone())));
+        }
+        if !skipped.is_empty() {
+            return self.attempt_match_sequences(
+                match_inputs,
+                PatternIterator::new(pattern),
+                code.children_with_tokens().filter(move |c| !skipped.contains(c)),
+                code,
+            );
+        }
         self.attempt_match_sequences(
             match_inputs,
             PatternIterator::new(pattern),
             code.children_with_tokens(),
+            code,
         )
     }
 
@@ -218,10 +843,23 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         &mut self,
         match_inputs: &MatchInputs,
         pattern_it: PatternIterator,
-        mut code_it: SyntaxElementChildren,
+        mut code_it: impl Iterator<Item = SyntaxElement> + Clone,
+        code_parent: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
         let mut pattern_it = pattern_it.peekable();
         loop {
+            if let Some(placeholder) =
+                pattern_it.peek().and_then(|p| match_inputs.get_multi_placeholder(p))
+            {
+                pattern_it.next();
+                self.attempt_match_multi_placeholder(
+                    placeholder,
+                    pattern_it.peek(),
+                    &mut code_it,
+                    code_parent,
+                )?;
+                continue;
+            }
             match self.next_non_trivial(&mut code_it) {
                 None => {
                     if let Some(p) = pattern_it.next() {
@@ -230,13 +868,18 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
                     return Ok(());
                 }
                 Some(SyntaxElement::Token(c)) => {
-                    self.attempt_match_token(&mut pattern_it, &c)?;
+                    self.attempt_match_token(match_inputs, &mut pattern_it, &c)?;
                 }
                 Some(SyntaxElement::Node(c)) => match pattern_it.next() {
                     Some(SyntaxElement::Node(p)) => {
                         self.attempt_match_node(match_inputs, &p, &c)?;
                     }
-                    Some(p) => fail_match!("Pattern wanted '{}', code has {}", p, c.text()),
+                    Some(p) => fail_match!(
+                        kind = MismatchKind::Kind,
+                        "Pattern wanted '{}', code has {}",
+                        p,
+                        c.text()
+                    ),
                     None => fail_match!("Pattern reached end, code has {}", c.text()),
                 },
             }
@@ -245,6 +888,7 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
 
     fn attempt_match_token(
         &mut self,
+        match_inputs: &MatchInputs,
         pattern: &mut Peekable<PatternIterator>,
         code: &ra_syntax::SyntaxToken,
     ) -> Result<(), MatchFailed> {
@@ -254,6 +898,45 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
             return Ok(());
         }
         if let Some(SyntaxElement::Token(p)) = pattern.peek() {
+            // Some placeholders, e.g. lifetimes, are tokens rather than nodes. These match a single
+            // token of the same kind.
+            if let Some(placeholder) =
+                match_inputs.get_placeholder(&SyntaxElement::Token(p.clone()))
+            {
+                if p.kind() != code.kind() {
+                    fail_match!(
+                        "Placeholder ${} wanted a {:?}, but code had token '{}' ({:?})",
+                        placeholder.ident,
+                        p.kind(),
+                        code.text(),
+                        code.kind()
+                    );
+                }
+                pattern.next();
+                if let Some(earlier) = self.placeholder_value(placeholder) {
+                    let earlier_text = self
+                        .match_out
+                        .as_ref()
+                        .and_then(|match_out| match_out.text_in_range(earlier.range.range));
+                    if earlier_text.as_deref() != Some(code.text().as_str()) {
+                        fail_match!(
+                            "Placeholder `{}` matched `{}` earlier, but here the code is `{}`",
+                            placeholder.ident,
+                            earlier_text.unwrap_or_default(),
+                            code.text()
+                        );
+                    }
+                } else if let Some(match_out) = &mut self.match_out {
+                    match_out.bind(
+                        placeholder,
+                        PlaceholderMatch::from_range(FileRange {
+                            file_id: self.sema.original_range(&code.parent()).file_id,
+                            range: code.text_range(),
+                        }),
+                    );
+                }
+                return Ok(());
+            }
             // If the code has a comma and the pattern is about to close something, then accept the
             // comma without advancing the pattern. i.e. ignore trailing commas.
             if code.kind() == SyntaxKind::COMMA && is_closing_token(p.kind()) {
@@ -268,8 +951,14 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         // Consume an element from the pattern and make sure it matches.
         match pattern.next() {
             Some(SyntaxElement::Token(p)) => {
-                if p.kind() != code.kind() || p.text() != code.text() {
+                if p.kind() != code.kind() || !self.token_text_matches(&p, code) {
+                    let kind = if p.kind() == code.kind() {
+                        MismatchKind::Token
+                    } else {
+                        MismatchKind::Kind
+                    };
                     fail_match!(
+                        kind = kind,
                         "Pattern wanted token '{}' ({:?}), but code had token '{}' ({:?})",
                         p.text(),
                         p.kind(),
@@ -281,6 +970,7 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
             Some(SyntaxElement::Node(p)) => {
                 // Not sure if this is actually reachable.
                 fail_match!(
+                    kind = MismatchKind::Kind,
                     "Pattern wanted {:?}, but code had token '{}' ({:?})",
                     p,
                     code.text(),
@@ -294,6 +984,66 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         Ok(())
     }
 
+    /// Matches a multi-placeholder against a run of sibling elements from `code_it`. We consume
+    /// everything up to, but not including, the last element that could be the start of
+    /// `next_pattern`. If the placeholder is the last thing in the pattern, we consume everything.
+    fn attempt_match_multi_placeholder(
+        &mut self,
+        placeholder: &Placeholder,
+        next_pattern: Option<&SyntaxElement>,
+        code_it: &mut (impl Iterator<Item = SyntaxElement> + Clone),
+        code_parent: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let remaining: Vec<SyntaxElement> =
+            code_it.clone().filter(|c| !c.kind().is_trivia()).collect();
+        let num_to_take = match next_pattern {
+            Some(next) => {
+                remaining.iter().rposition(|c| could_start(next, c)).ok_or_else(|| {
+                    match_error!(
+                        "Code has nothing after ${} matching {:?}",
+                        placeholder.ident,
+                        next
+                    )
+                })?
+            }
+            None => remaining.len(),
+        };
+        let mut matched = Vec::with_capacity(num_to_take);
+        for _ in 0..num_to_take {
+            // Comments after the first element that we match are within the range that we capture,
+            // so they'll be reproduced as part of the placeholder and mustn't be recorded as
+            // ignored.
+            let next = if matched.is_empty() {
+                self.next_non_trivial(code_it)
+            } else {
+                code_it.find(|c| !c.kind().is_trivia())
+            };
+            if let Some(c) = next {
+                matched.push(c);
+            }
+        }
+        if let Some(match_out) = &mut self.match_out {
+            let range = match (matched.first(), matched.last()) {
+                (Some(first), Some(last)) => first.text_range().cover(last.text_range()),
+                // We matched nothing, so we record an empty range just before whatever comes next.
+                _ => TextRange::empty(
+                    remaining
+                        .first()
+                        .map_or(code_parent.text_range().end(), |next| next.text_range().start()),
+                ),
+            };
+            let placeholder_match = PlaceholderMatch {
+                node: None,
+                nodes: matched.into_iter().filter_map(|c| c.into_node()).collect(),
+                range: FileRange { file_id: self.sema.original_range(code_parent).file_id, range },
+                inner_matches: SsrMatches::default(),
+                inferred_text: None,
+            };
+            match_out.bind(placeholder, placeholder_match);
+        }
+        Ok(())
+    }
+
     /// We want to allow the records to match in any order, so we have special matching logic for
     /// them.
     fn attempt_match_record_field_list(
@@ -355,6 +1105,10 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         while let Some(child) = children.next() {
             if let Some(placeholder) = pattern.peek().and_then(|p| match_inputs.get_placeholder(p))
             {
+                // Whitespace before or after the matched tokens isn't part of the placeholder.
+                if child.kind().is_trivia() {
+                    continue;
+                }
                 pattern.next();
                 let next_pattern_token = pattern
                     .peek()
@@ -363,6 +1117,22 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
                         SyntaxElement::Node(n) => n.first_token(),
                     })
                     .map(|p| p.text().to_string());
+                // Multi-placeholders can match nothing at all, e.g. the empty list in `#[derive()]`.
+                if placeholder.multi && Some(child.to_string()) == next_pattern_token {
+                    if let SyntaxElement::Token(_) = &child {
+                        pattern.next();
+                        if let Some(match_out) = &mut self.match_out {
+                            match_out.bind(
+                                placeholder,
+                                PlaceholderMatch::from_range(FileRange {
+                                    file_id: self.sema.original_range(code).file_id,
+                                    range: TextRange::empty(child.text_range().start()),
+                                }),
+                            );
+                        }
+                        continue;
+                    }
+                }
                 let first_matched_token = child.clone();
                 let mut last_matched_token = child;
                 // Read code tokens util we reach one equal to the next token from our pattern
@@ -387,11 +1157,13 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
                             }
                         }
                     };
-                    last_matched_token = next;
+                    if !next.kind().is_trivia() {
+                        last_matched_token = next;
+                    }
                 }
                 if let Some(match_out) = &mut self.match_out {
-                    match_out.placeholder_values.insert(
-                        Var(placeholder.ident.to_string()),
+                    match_out.bind(
+                        placeholder,
                         PlaceholderMatch::from_range(FileRange {
                             file_id: self.sema.original_range(code).file_id,
                             range: first_matched_token
@@ -405,7 +1177,7 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
             // Match literal (non-placeholder) tokens.
             match child {
                 SyntaxElement::Token(token) => {
-                    self.attempt_match_token(&mut pattern, &token)?;
+                    self.attempt_match_token(match_inputs, &mut pattern, &token)?;
                 }
                 SyntaxElement::Node(node) => match pattern.next() {
                     Some(SyntaxElement::Node(p)) => {
@@ -426,7 +1198,93 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
         Ok(())
     }
 
-    fn next_non_trivial(&mut self, code_it: &mut SyntaxElementChildren) -> Option<SyntaxElement> {
+    /// Returns whether `code` satisfies `constraint`. Constraints that restrict which tokens can
+    /// match, such as `Lifetime`, are handled when we parse the pattern.
+    fn satisfies_constraint(&self, constraint: &Constraint, code: &SyntaxNode) -> bool {
+        match constraint {
+            Constraint::Lifetime => true,
+            Constraint::TupleIndex => {
+                code.kind() == SyntaxKind::NAME_REF
+                    && code.first_token().map(|t| t.kind()) == Some(SyntaxKind::INT_NUMBER)
+            }
+            Constraint::AnyLoop => matches!(
+                code.kind(),
+                SyntaxKind::LOOP_EXPR | SyntaxKind::WHILE_EXPR | SyntaxKind::FOR_EXPR
+            ),
+            Constraint::IsOption => self.has_type_named(code, "Option"),
+            Constraint::IsResult => self.has_type_named(code, "Result"),
+            Constraint::NotContains(pattern) => !self.contains_match(pattern, code),
+            Constraint::Not(pattern) => !self.is_match(pattern, code),
+            Constraint::IsSnakeCase => {
+                matches!(single_ident(code), Some(ident) if is_snake_case(ident_name(&ident)))
+            }
+            Constraint::IsUpperCamelCase => {
+                matches!(single_ident(code), Some(ident) if is_upper_camel_case(ident_name(&ident)))
+            }
+        }
+    }
+
+    /// Returns whether `code` or any of its descendants matches `pattern`. Only the first,
+    /// syntactic, matching pass is done.
+    fn contains_match(&self, pattern: &SsrPattern, code: &SyntaxNode) -> bool {
+        code.descendants().any(|node| self.is_match(pattern, &node))
+    }
+
+    /// Returns whether `code` itself matches `pattern`. Only the first, syntactic, matching pass is
+    /// done.
+    fn is_match(&self, pattern: &SsrPattern, code: &SyntaxNode) -> bool {
+        let match_inputs = MatchInputs { ssr_pattern: pattern };
+        let pattern_tree = match pattern.tree_for_kind(code.kind()) {
+            Ok(tree) => tree,
+            Err(_) => return false,
+        };
+        let mut match_state = MatchState {
+            sema: self.sema,
+            options: self.options,
+            restrict_range: None,
+            match_out: None,
+            depth: self.depth,
+        };
+        match_state.attempt_match_node(&match_inputs, pattern_tree, code).is_ok()
+    }
+
+    /// Returns whether `code` is an expression whose type is an ADT called `name`. Since inferring
+    /// types is expensive, we only check on the second pass. If the type can't be determined, we
+    /// return true so that the match degrades to being purely syntactic.
+    fn has_type_named(&self, code: &SyntaxNode, name: &str) -> bool {
+        if self.match_out.is_none() {
+            return true;
+        }
+        let ty = match ast::Expr::cast(code.clone()).and_then(|e| self.sema.type_of_expr(&e)) {
+            Some(ty) if !ty.is_unknown() => ty,
+            _ => return true,
+        };
+        ty.as_adt().map(|adt| adt.name(self.sema.db).to_string()).as_deref() == Some(name)
+    }
+
+    /// Returns what `placeholder` has already been bound to in the match so far, if anything.
+    fn placeholder_value(&self, placeholder: &Placeholder) -> Option<&PlaceholderMatch> {
+        self.match_out.as_ref()?.placeholder_values.get(&Var(placeholder.ident.to_string()))
+    }
+
+    fn token_text_matches(&self, pattern: &SyntaxToken, code: &SyntaxToken) -> bool {
+        if self.options.collapse_string_whitespace && code.kind() == SyntaxKind::STRING {
+            collapse_whitespace(pattern.text()) == collapse_whitespace(code.text())
+        } else if self.options.normalize_numeric_literals
+            && matches!(code.kind(), SyntaxKind::INT_NUMBER | SyntaxKind::FLOAT_NUMBER)
+        {
+            let normalize_base = self.options.normalize_numeric_base;
+            normalize_number(pattern.text(), normalize_base)
+                == normalize_number(code.text(), normalize_base)
+        } else {
+            pattern.text() == code.text()
+        }
+    }
+
+    fn next_non_trivial(
+        &mut self,
+        code_it: &mut impl Iterator<Item = SyntaxElement>,
+    ) -> Option<SyntaxElement> {
         loop {
             let c = code_it.next();
             if let Some(SyntaxElement::Token(t)) = &c {
@@ -448,12 +1306,304 @@ impl<'db, 'sema> MatchState<'db, 'sema> {
             }
         }
     }
+
+    /// Returns the type of `call` as it can be written in place of `Default` in
+    /// `Default::default()`, where `call` is. Types that aren't just a path, such as `&str`, are
+    /// wrapped in angle brackets.
+    fn nameable_type_of(&self, call: &ast::CallExpr) -> Option<String> {
+        let ty = self.sema.type_of_expr(&call.clone().into()).filter(|ty| !ty.is_unknown())?;
+        let module = self.sema.scope(call.syntax()).module()?;
+        let text = ty.display_source_code(self.sema.db, module.into()).ok()?;
+        let type_ref = parse_fragment(&text, PatternKind::TypeRef)?;
+        if text.contains("{unknown}") || type_ref.kind() == SyntaxKind::IMPL_TRAIT_TYPE {
+            return None;
+        }
+        Some(if type_ref.kind() == SyntaxKind::PATH_TYPE && !text.contains('<') {
+            text
+        } else {
+            format!("<{}>", text)
+        })
+    }
 }
 
 impl MatchInputs<'_> {
     fn get_placeholder(&self, element: &SyntaxElement) -> Option<&Placeholder> {
         only_ident(element.clone())
-            .and_then(|ident| self.ssr_pattern.placeholders_by_stand_in.get(ident.text()))
+            .or_else(|| element.as_token().filter(|t| t.kind() == SyntaxKind::LIFETIME).cloned())
+            .and_then(|token| self.ssr_pattern.placeholders_by_stand_in.get(token.text()))
+            .or_else(|| self.get_block_placeholder(element))
+    }
+
+    /// Returns the placeholder of kind `block` that is the only thing within `element`, if it's a
+    /// block. Such a placeholder stands for the whole block, braces included.
+    fn get_block_placeholder(&self, element: &SyntaxElement) -> Option<&Placeholder> {
+        let block = ast::BlockExpr::cast(element.as_node()?.clone())?;
+        if block.statements().next().is_some() {
+            return None;
+        }
+        let token = only_ident(SyntaxElement::Node(block.expr()?.syntax().clone()))?;
+        let placeholder = self.ssr_pattern.placeholders_by_stand_in.get(token.text())?;
+        if placeholder.kind == Some(PlaceholderKind::Block) {
+            Some(placeholder)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the multi-placeholder that `element` was parsed from, if any. Depending on where it
+    /// appears, a multi-placeholder may have been parsed as more than just its stand-in name, so we
+    /// check the first token, then that the whole element is one of the possible stand-in shapes.
+    fn get_multi_placeholder(&self, element: &SyntaxElement) -> Option<&Placeholder> {
+        let first_token = match element {
+            SyntaxElement::Token(t) => t.clone(),
+            SyntaxElement::Node(n) => n.first_token()?,
+        };
+        let placeholder = self.ssr_pattern.placeholders_by_stand_in.get(first_token.text())?;
+        if placeholder.multi && placeholder.is_stand_in_text(&element.to_string()) {
+            Some(placeholder)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns whether `code`, or wherever it came from if it's from a macro expansion, is within the
+/// arguments of a function, method or macro called `call_name`.
+fn is_within_call(
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+    code: &SyntaxNode,
+    call_name: &str,
+) -> bool {
+    use ast::ArgListOwner;
+    let range = sema.original_range(code);
+    let file = sema.parse(range.file_id);
+    let arguments_contain = |args: Option<&SyntaxNode>| match args {
+        Some(args) => args.text_range().contains_range(range.range),
+        None => false,
+    };
+    let start = match file.syntax().covering_element(range.range) {
+        SyntaxElement::Node(n) => n,
+        SyntaxElement::Token(t) => t.parent(),
+    };
+    start.ancestors().any(|ancestor| {
+        if let Some(macro_call) = ast::MacroCall::cast(ancestor.clone()) {
+            macro_call.path().and_then(|p| p.segment()).map(|s| s.syntax().text() == call_name)
+                == Some(true)
+                && arguments_contain(macro_call.token_tree().as_ref().map(|t| t.syntax()))
+        } else if let Some(call) = ast::CallExpr::cast(ancestor.clone()) {
+            let callee = match call.expr() {
+                Some(ast::Expr::PathExpr(path_expr)) => path_expr.path(),
+                _ => None,
+            };
+            callee.and_then(|p| p.segment()).map(|s| s.syntax().text() == call_name) == Some(true)
+                && arguments_contain(call.arg_list().as_ref().map(|a| a.syntax()))
+        } else if let Some(call) = ast::MethodCallExpr::cast(ancestor) {
+            call.name_ref().map(|n| n.text() == call_name) == Some(true)
+                && arguments_contain(call.arg_list().as_ref().map(|a| a.syntax()))
+        } else {
+            false
+        }
+    })
+}
+
+/// Checks that each method that `m`'s template calls on a placeholder, e.g. `bar` in `$r.bar()`,
+/// exists on the type of the code that the placeholder matched.
+fn check_template_methods(
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+    m: &Match,
+) -> Result<(), MatchFailed> {
+    let elements: Vec<&PatternElement> = m
+        .template
+        .tokens
+        .iter()
+        .filter(|e| match e {
+            PatternElement::Token(t) => !t.kind.is_trivia(),
+            PatternElement::Placeholder(_) => true,
+        })
+        .collect();
+    // A name that isn't followed by arguments or a turbofish, as in `$r.len`, is a field access.
+    for window in elements.windows(4) {
+        let (placeholder, method) = match window {
+            [PatternElement::Placeholder(p), PatternElement::Token(dot), PatternElement::Token(name), PatternElement::Token(next)]
+                if dot.kind == T![.]
+                    && name.kind == SyntaxKind::IDENT
+                    && matches!(next.kind, T!['('] | T![::]) =>
+            {
+                (p, &name.text)
+            }
+            _ => continue,
+        };
+        let code = match m.placeholder_values.get(&Var(placeholder.ident.to_string())) {
+            Some(PlaceholderMatch { node: Some(node), .. }) => node,
+            _ => continue,
+        };
+        let ty = match ast::Expr::cast(code.clone()).and_then(|e| sema.type_of_expr(&e)) {
+            Some(ty) if !ty.is_unknown() => ty,
+            _ => continue,
+        };
+        let scope = sema.scope(code);
+        let krate = match scope.module() {
+            Some(module) => module.krate(),
+            None => continue,
+        };
+        let found = ty.iterate_method_candidates(
+            sema.db,
+            krate,
+            &scope.traits_in_scope(),
+            None,
+            |_, function| {
+                if function.name(sema.db).to_string() == method.as_str() {
+                    Some(())
+                } else {
+                    None
+                }
+            },
+        );
+        if found.is_none() {
+            fail_match!("`{}` has no method `{}`", code.text(), method);
+        }
+    }
+    Ok(())
+}
+
+/// If `code` is the `Default` in a call to `Default::default()`, returns the call.
+fn default_trait_call(code: &SyntaxNode) -> Option<ast::CallExpr> {
+    let qualifier = ast::Path::cast(code.clone())?;
+    if qualifier.syntax().text() != "Default" {
+        return None;
+    }
+    let path = ast::Path::cast(qualifier.syntax().parent()?)?;
+    if path.segment()?.syntax().text() != "default" {
+        return None;
+    }
+    let path_expr = ast::PathExpr::cast(path.syntax().parent()?)?;
+    let call = ast::CallExpr::cast(path_expr.syntax().parent()?)?;
+    if call.expr()?.syntax() != path_expr.syntax() {
+        return None;
+    }
+    Some(call)
+}
+
+/// If `pattern` is a `.**` chain wildcard, returns the pattern for its receiver.
+fn chain_wildcard_receiver(pattern: &SyntaxNode) -> Option<SyntaxNode> {
+    let call = ast::MethodCallExpr::cast(pattern.clone())?;
+    if call.name_ref()?.text() != CHAIN_WILDCARD_STAND_IN {
+        return None;
+    }
+    call.expr().map(|e| e.syntax().clone())
+}
+
+/// Returns whether `tree` is just a path, without a `{...}` list, `*` or alias.
+fn is_plain_path(tree: &ast::UseTree) -> bool {
+    tree.use_tree_list().is_none() && tree.star_token().is_none() && tree.alias().is_none()
+}
+
+/// Returns the number of segments in `path`, e.g. 2 for `foo::bar`.
+fn path_len(path: &ast::Path) -> usize {
+    std::iter::successors(Some(path.clone()), |p| p.qualifier()).count()
+}
+
+/// Returns `text` with each run of whitespace replaced by a single space.
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the text of a numeric literal without underscores or a type suffix, e.g. `1000` for
+/// `1_000u64`. If `normalize_base` is set, integers are also converted to decimal, so `0x3e8` is
+/// also `1000`.
+fn normalize_number(text: &str, normalize_base: bool) -> String {
+    const INT_SUFFIXES: &[&str] =
+        &["i128", "isize", "i16", "i32", "i64", "i8", "u128", "usize", "u16", "u32", "u64", "u8"];
+    let text: String = text.chars().filter(|&c| c != '_').collect();
+    let radix = match text.get(..2) {
+        Some("0x") => 16,
+        Some("0o") => 8,
+        Some("0b") => 2,
+        _ => 10,
+    };
+    let mut digits = text.as_str();
+    if let Some(suffix) = INT_SUFFIXES.iter().find(|suffix| digits.ends_with(*suffix)) {
+        digits = &digits[..digits.len() - suffix.len()];
+    } else if radix == 10 {
+        // In a hex literal, `f32` would be digits rather than a suffix.
+        digits = digits.trim_end_matches("f32").trim_end_matches("f64");
+    }
+    if normalize_base {
+        let unprefixed = if radix == 10 { digits } else { &digits[2..] };
+        if let Ok(value) = u128::from_str_radix(unprefixed, radix) {
+            return value.to_string();
+        }
+    }
+    digits.to_owned()
+}
+
+/// Returns whether `code` is a method call or path segment with generic arguments given using
+/// turbofish syntax, e.g. `x.parse::<u32>()` or the `foo::<u32>` in `foo::<u32>(x)`.
+fn has_turbofish(code: &SyntaxNode) -> bool {
+    match code.kind() {
+        SyntaxKind::METHOD_CALL_EXPR => true,
+        SyntaxKind::PATH_SEGMENT => {
+            code.children()
+                .find(|c| c.kind() == SyntaxKind::TYPE_ARG_LIST)
+                .and_then(|args| args.first_token())
+                .map(|t| t.kind())
+                == Some(SyntaxKind::COLON2)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the name of `ident`, without the `r#` of a raw identifier and any leading underscores.
+fn ident_name(ident: &SyntaxToken) -> &str {
+    let text = ident.text().as_str();
+    text.strip_prefix("r#").unwrap_or(text).trim_start_matches('_')
+}
+
+/// Returns whether `name` contains no uppercase letters, e.g. `foo_bar` but not `fooBar`.
+fn is_snake_case(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_uppercase)
+}
+
+/// Returns whether `name` starts with an uppercase letter and contains no underscores, e.g.
+/// `FooBar` but not `Foo_bar`.
+fn is_upper_camel_case(name: &str) -> bool {
+    matches!(name.chars().next(), Some(c) if c.is_uppercase()) && !name.contains('_')
+}
+
+/// Returns the doc comments that are children of `node`.
+fn doc_comments(node: &SyntaxNode) -> Vec<ast::Comment> {
+    node.children_with_tokens()
+        .filter_map(|c| c.into_token().and_then(ast::Comment::cast))
+        .filter(|c| c.kind().doc.is_some())
+        .collect()
+}
+
+/// Returns whether `code` has an attribute whose path is `attr_path`, e.g. `test` or `tokio::test`.
+fn has_attribute(code: &SyntaxNode, attr_path: &str) -> bool {
+    code.children().filter_map(ast::Attr::cast).any(|attr| match attr.path() {
+        Some(path) => path.syntax().text() == attr_path,
+        None => false,
+    })
+}
+
+/// Returns whether `pattern` is the whole of a search pattern, rather than some part of one.
+fn is_pattern_root(pattern: &SyntaxNode) -> bool {
+    pattern.parent().map(|p| p.kind()) == Some(SyntaxKind::SOURCE_FILE)
+}
+
+/// If `node` is a parenthesized expression, returns the expression inside the parentheses.
+fn paren_inner(node: &SyntaxNode) -> Option<SyntaxNode> {
+    ast::ParenExpr::cast(node.clone())?.expr().map(|e| e.syntax().clone())
+}
+
+/// Returns whether the code element `code` could be the start of the pattern element `pattern`.
+fn could_start(pattern: &SyntaxElement, code: &SyntaxElement) -> bool {
+    match (pattern, code) {
+        (SyntaxElement::Token(p), SyntaxElement::Token(c)) => {
+            p.kind() == c.kind() && p.text() == c.text()
+        }
+        (SyntaxElement::Node(p), SyntaxElement::Node(c)) => p.kind() == c.kind(),
+        _ => false,
     }
 }
 
@@ -483,36 +1633,166 @@ fn recording_match_fail_reasons() -> bool {
     RECORDING_MATCH_FAIL_REASONS.with(|c| c.get())
 }
 
+impl Match {
+    /// Records that `placeholder` matched `value`. Anonymous placeholders, `$_`, are never
+    /// recorded, since they can't be used in the replacement.
+    fn bind(&mut self, placeholder: &Placeholder, value: PlaceholderMatch) {
+        if !placeholder.anonymous {
+            self.placeholder_values.insert(Var(placeholder.ident.to_string()), value);
+        }
+    }
+}
+
 impl PlaceholderMatch {
     fn new(node: &SyntaxNode, range: FileRange) -> Self {
-        Self { node: Some(node.clone()), range, inner_matches: SsrMatches::default() }
+        Self {
+            node: Some(node.clone()),
+            nodes: Vec::new(),
+            range,
+            inner_matches: SsrMatches::default(),
+            inferred_text: None,
+        }
     }
 
     fn from_range(range: FileRange) -> Self {
-        Self { node: None, range, inner_matches: SsrMatches::default() }
+        Self {
+            node: None,
+            nodes: Vec::new(),
+            range,
+            inner_matches: SsrMatches::default(),
+            inferred_text: None,
+        }
     }
 }
 
 impl SsrPattern {
     pub(crate) fn tree_for_kind(&self, kind: SyntaxKind) -> Result<&SyntaxNode, MatchFailed> {
-        let (tree, kind_name) = if ast::Expr::can_cast(kind) {
-            (&self.expr, "expression")
+        let (tree, kind_name) = match PatternKind::for_syntax_kind(kind) {
+            Some(PatternKind::Expr) => (&self.expr, "expression"),
+            Some(PatternKind::TypeRef) => (&self.type_ref, "type reference"),
+            Some(PatternKind::Item) => (&self.item, "item"),
+            Some(PatternKind::Path) => (&self.path, "path"),
+            Some(PatternKind::Pattern) => (&self.pattern, "pattern"),
+            Some(PatternKind::Variant) => (&self.variant, "enum variant"),
+            Some(PatternKind::Statement) => (&self.stmt, "statement"),
+            Some(PatternKind::Attribute) => (&self.attr, "attribute"),
+            None => fail_match!(
+                kind = MismatchKind::Kind,
+                "Matching nodes of kind {:?} is not supported",
+                kind
+            ),
+        };
+        match tree {
+            Some(tree) => Ok(tree),
+            None => fail_match!(
+                kind = MismatchKind::Kind,
+                "Pattern cannot be parsed as a {}",
+                kind_name
+            ),
+        }
+    }
+}
+
+impl PatternKind {
+    /// Returns the kind of pattern that would be used to match a node of kind `kind`, if any.
+    pub(crate) fn for_syntax_kind(kind: SyntaxKind) -> Option<PatternKind> {
+        if ast::Expr::can_cast(kind) {
+            Some(PatternKind::Expr)
         } else if ast::TypeRef::can_cast(kind) {
-            (&self.type_ref, "type reference")
+            Some(PatternKind::TypeRef)
         } else if ast::ModuleItem::can_cast(kind) {
-            (&self.item, "item")
+            Some(PatternKind::Item)
         } else if ast::Path::can_cast(kind) {
-            (&self.path, "path")
+            Some(PatternKind::Path)
         } else if ast::Pat::can_cast(kind) {
-            (&self.pattern, "pattern")
+            Some(PatternKind::Pattern)
+        } else if ast::EnumVariant::can_cast(kind) {
+            Some(PatternKind::Variant)
+        } else if ast::Stmt::can_cast(kind) {
+            Some(PatternKind::Statement)
+        } else if ast::Attr::can_cast(kind) {
+            Some(PatternKind::Attribute)
         } else {
-            fail_match!("Matching nodes of kind {:?} is not supported", kind);
-        };
-        match tree {
-            Some(tree) => Ok(tree),
-            None => fail_match!("Pattern cannot be parsed as a {}", kind_name),
+            None
+        }
+    }
+}
+
+/// Returns the names of the `mod` blocks that enclose `node`, outermost first. Modules in other
+/// files, e.g. from `mod foo;`, aren't known about.
+pub(crate) fn lexical_module_path(node: &SyntaxNode) -> Vec<SmolStr> {
+    let mut path: Vec<SmolStr> = node
+        .ancestors()
+        .filter_map(ast::Module::cast)
+        .filter_map(|module| module.name())
+        .map(|name| name.text().clone())
+        .collect();
+    path.reverse();
+    path
+}
+
+/// Returns whether `node` is `..`, or a binding of it such as `rest @ ..`, within a pattern.
+fn is_rest_pattern(node: &SyntaxNode) -> bool {
+    match node.kind() {
+        SyntaxKind::DOT_DOT_PAT => true,
+        SyntaxKind::BIND_PAT => {
+            node.children().any(|child| child.kind() == SyntaxKind::DOT_DOT_PAT)
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether `a` and `b` are the same code, ignoring whitespace and comments.
+fn same_code(a: &SyntaxNode, b: &SyntaxNode) -> bool {
+    let tokens = |node: &SyntaxNode| {
+        node.descendants_with_tokens()
+            .filter_map(|e| e.into_token())
+            .filter(|t| !t.kind().is_trivia())
+            .map(|t| (t.kind(), t.text().clone()))
+            .collect::<Vec<_>>()
+    };
+    tokens(a) == tokens(b)
+}
+
+/// Checks that the whitespace between the children of `pattern` corresponds to the whitespace
+/// between the children of `code`. We can't tell which children correspond if `pattern` contains a
+/// multi-placeholder, or has a different number of children, e.g. due to an ignored trailing comma,
+/// so we don't check in those cases.
+fn check_whitespace_lines_up(
+    match_inputs: &MatchInputs,
+    pattern: &SyntaxNode,
+    code: &SyntaxNode,
+) -> Result<(), MatchFailed> {
+    if pattern.children_with_tokens().any(|p| match_inputs.get_multi_placeholder(&p).is_some()) {
+        return Ok(());
+    }
+    let pattern_gaps = whitespace_gaps(pattern);
+    let code_gaps = whitespace_gaps(code);
+    if pattern_gaps.len() == code_gaps.len() && pattern_gaps != code_gaps {
+        fail_match!("Whitespace in code `{}` doesn't line up with the pattern", code.text());
+    }
+    Ok(())
+}
+
+/// Returns, for each gap between consecutive non-trivia children of `node`, the number of
+/// newlines in the whitespace in that gap, or None if there's no whitespace there.
+fn whitespace_gaps(node: &SyntaxNode) -> Vec<Option<usize>> {
+    let mut gaps = Vec::new();
+    let mut current = None;
+    let mut seen_non_trivia = false;
+    for element in node.children_with_tokens() {
+        if element.kind() == SyntaxKind::WHITESPACE {
+            let newlines = element.to_string().matches('\n').count();
+            current = Some(current.unwrap_or(0) + newlines);
+        } else if !element.kind().is_trivia() {
+            if seen_non_trivia {
+                gaps.push(current);
+            }
+            seen_non_trivia = true;
+            current = None;
         }
     }
+    gaps
 }
 
 // If `node` contains nothing but an ident then return it, otherwise return None.
@@ -583,7 +1863,7 @@ mod tests {
             "1+2"
         );
 
-        let edit = crate::replacing::matches_to_edit(&matches);
+        let edit = crate::replacing::matches_to_edit(&matches, &Default::default()).unwrap();
         let mut after = input.to_string();
         edit.apply(&mut after);
         assert_eq!(after, "fn main() { bar(1+2); }");
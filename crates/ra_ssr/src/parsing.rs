@@ -1,29 +1,36 @@
 //! This file contains code for parsing SSR rules, which look something like `foo($a) ==>> bar($b)`.
-//! We first split everything before and after the separator `==>>`. Next, both the search pattern
-//! and the replacement template get tokenized by the Rust tokenizer. Tokens are then searched for
-//! placeholders, which start with `$`. For replacement templates, this is the final form. For
-//! search patterns, we go further and parse the pattern as each kind of thing that we can match.
-//! e.g. expressions, type references etc.
-
-use crate::{SsrError, SsrPattern, SsrRule};
-use ra_syntax::{ast, AstNode, SmolStr, SyntaxKind};
-use rustc_hash::{FxHashMap, FxHashSet};
+//! We first split everything before and after the separator `==>>`, which needs whitespace on
+//! either side. Next, both the search pattern and the replacement template get tokenized by the
+//! Rust tokenizer. Tokens are then searched for placeholders, which start with `$`. For replacement
+//! templates, this is the final form. For search patterns, we go further and parse the pattern as
+//! each kind of thing that we can match. e.g. expressions, type references etc.
+
+use crate::{PatternKind, SsrError, SsrErrorKind, SsrPattern, SsrRule, SsrRules};
+use ra_syntax::{
+    ast, AstNode, SmolStr, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TextSize, T,
+};
+use rustc_hash::FxHashMap;
 use std::str::FromStr;
 
 /// Returns from the current function with an error, supplied by arguments as for format!
 macro_rules! bail {
-    ($e:expr) => {return Err($crate::SsrError::new($e))};
-    ($fmt:expr, $($arg:tt)+) => {return Err($crate::SsrError::new(format!($fmt, $($arg)+)))}
+    ($e:expr) => {return Err($crate::SsrError::invalid_rule($e))};
+    ($fmt:expr, $($arg:tt)+) => {return Err($crate::SsrError::invalid_rule(format!($fmt, $($arg)+)))}
 }
 
+/// A placeholder that a replacement template can use without the search pattern defining it. It's
+/// replaced with the qualifiers, such as `const`, that a function pattern matched without mentioning
+/// them, which are then no longer added before the template's `fn`.
+pub(crate) const QUALIFIERS_PLACEHOLDER: &str = "qualifiers";
+
 #[derive(Clone, Debug)]
 pub(crate) struct SsrTemplate {
     pub(crate) tokens: Vec<PatternElement>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct RawSearchPattern {
-    tokens: Vec<PatternElement>,
+    pub(crate) tokens: Vec<PatternElement>,
 }
 
 // Part of a search or replace pattern.
@@ -39,11 +46,97 @@ pub(crate) struct Placeholder {
     pub(crate) ident: SmolStr,
     /// A unique name used in place of this placeholder when we parse the pattern as Rust code.
     stand_in_name: String,
+    /// Whether this placeholder was written as `$a:*`, meaning that it can match a sequence of zero
+    /// or more sibling nodes, such as the arguments in an argument list.
+    pub(crate) multi: bool,
+    /// Restrictions on what this placeholder can match, written as `${a:constraint}`.
+    pub(crate) constraints: Vec<Constraint>,
+    /// The syntactic category that this placeholder is restricted to, written as `$a:expr`.
+    pub(crate) kind: Option<PlaceholderKind>,
+    /// A pattern that the text of whatever this placeholder matches must match, written as
+    /// `$a~^get_`.
+    pub(crate) text_constraint: Option<TextConstraint>,
+    /// The name of a transformation to apply to the placeholder's text when it's substituted into
+    /// a template, written as `$a:upper`. Only valid in templates.
+    pub(crate) transform: Option<SmolStr>,
+    /// Whether this placeholder was written as `$_`. Each anonymous placeholder in a search pattern
+    /// gets its own name, so they needn't match the same code, and they can't be used in templates.
+    pub(crate) anonymous: bool,
+}
+
+/// A regular expression that the text of a placeholder's match must match. Without the `regex`
+/// feature, only plain text is supported, optionally anchored with `^` and `$` and preceded by
+/// `(?i)` to ignore case, and any other regex syntax is an error. Without anchors, the text may
+/// appear anywhere within the match.
+#[derive(Clone, Debug)]
+pub(crate) struct TextConstraint {
+    pub(crate) source: String,
+    #[cfg(feature = "regex")]
+    regex: regex::Regex,
+}
+
+/// A syntactic category that a placeholder can be restricted to matching. Written after the
+/// placeholder's name, as in macro_rules, e.g. `$a:literal`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PlaceholderKind {
+    Expr,
+    Ident,
+    Path,
+    Ty,
+    Pat,
+    Literal,
+    /// A block, including its braces. Written as the only thing within a block in the pattern,
+    /// e.g. `fn $n() { $body:block }`, in which case it matches the whole block.
+    Block,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Constraint {
+    /// The placeholder matches a lifetime or label, such as `'a`, rather than a node.
+    Lifetime,
+    /// The placeholder only matches a numeric tuple index, such as the `0` in `x.0`.
+    TupleIndex,
+    /// The placeholder only matches a `loop`, `while` or `for` loop.
+    AnyLoop,
+    /// The placeholder only matches an expression whose type is `Option`. If the type can't be
+    /// determined, the placeholder matches anyway.
+    IsOption,
+    /// Like `IsOption`, but for `Result`.
+    IsResult,
+    /// The placeholder only matches code that doesn't contain a match of the given pattern,
+    /// written as `${a:not_contains(bar())}`.
+    NotContains(SsrPattern),
+    /// The placeholder only matches code that isn't itself a match of the given pattern, written as
+    /// `${a:not(Ok($_))}`.
+    Not(SsrPattern),
+    /// The placeholder only matches an identifier written in snake_case, such as `foo_bar`.
+    IsSnakeCase,
+    /// The placeholder only matches an identifier written in UpperCamelCase, such as `FooBar`.
+    IsUpperCamelCase,
 }
 
+/// The Rust code that we put in place of a multi-placeholder when parsing a pattern. Which of these
+/// is valid depends on where the placeholder appears, which we mostly tell from the tokens around
+/// it. Where we can't, we try each that might be valid in turn.
+#[derive(Clone, Copy, Debug)]
+enum MultiShape {
+    /// Just the stand-in name. Valid in argument lists, token trees, record literals etc.
+    Name,
+    /// `name: name`, for the fields of a struct definition.
+    Field,
+    /// `name => {}`, for match arms.
+    MatchArm,
+}
+
+const MULTI_SHAPES: [MultiShape; 3] = [MultiShape::Name, MultiShape::Field, MultiShape::MatchArm];
+
+/// The most candidates, i.e. combinations of shapes for the multi-placeholders, that we'll try
+/// parsing a pattern as.
+const MAX_CANDIDATES: usize = 64;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct Token {
-    kind: SyntaxKind,
+    pub(crate) kind: SyntaxKind,
     pub(crate) text: SmolStr,
 }
 
@@ -51,39 +144,264 @@ impl FromStr for SsrRule {
     type Err = SsrError;
 
     fn from_str(query: &str) -> Result<SsrRule, SsrError> {
-        let mut it = query.split("==>>");
-        let pattern = it.next().expect("at least empty string").trim();
-        let template = it
-            .next()
-            .ok_or_else(|| SsrError("Cannot find delemiter `==>>`".into()))?
-            .trim()
-            .to_string();
-        if it.next().is_some() {
-            return Err(SsrError("More than one delimiter found".into()));
-        }
-        let rule = SsrRule { pattern: pattern.parse()?, template: template.parse()? };
+        SsrRule::from_str_with_options(query, &PatternOptions::default())
+    }
+}
+
+/// Returns the offset of `part`, which must be a slice of `whole`, within `whole`.
+fn offset_within(whole: &str, part: &str) -> TextSize {
+    TextSize::from((part.as_ptr() as usize - whole.as_ptr() as usize) as u32)
+}
+
+/// Builds a rule from its search pattern and replacement template, adding `extra_constraints` to
+/// the placeholders that they name.
+/// Each of `pattern` and `template` is given along with its offset within the text of the rule, so
+/// that the ranges of errors are relative to the rule's text.
+pub(crate) fn build_rule(
+    (pattern, pattern_offset): (&str, TextSize),
+    (template, template_offset): (&str, TextSize),
+    extra_constraints: &[(SmolStr, Constraint)],
+    options: &PatternOptions,
+) -> Result<SsrRule, SsrError> {
+    let mut unused_constraints: Vec<&SmolStr> =
+        extra_constraints.iter().map(|(name, _)| name).collect();
+    let mut patterns = Vec::new();
+    for (offset, alternative) in
+        split_alternatives(pattern).map_err(|e| e.offset_by(pattern_offset))?
+    {
+        let mut raw: RawSearchPattern =
+            alternative.parse().map_err(|e: SsrError| e.offset_by(pattern_offset + offset))?;
+        for element in &mut raw.tokens {
+            if let PatternElement::Placeholder(placeholder) = element {
+                for (name, constraint) in extra_constraints {
+                    if *name == placeholder.ident {
+                        placeholder.add_constraint(constraint.clone());
+                        unused_constraints.retain(|unused| *unused != name);
+                    }
+                }
+            }
+        }
+        let pattern = SsrPattern::from_raw(raw, options)?;
+        validate_multi_placeholders(&pattern)?;
+        patterns.push(pattern);
+    }
+    if let Some(name) = unused_constraints.first() {
+        return Err(SsrError {
+            kind: SsrErrorKind::UndefinedPlaceholder,
+            message: format!("Constraint given for `${}`, which isn't in the search pattern", name),
+            range: None,
+        });
+    }
+    let mut patterns = patterns.into_iter();
+    let pattern = patterns.next().expect("at least one alternative");
+    let template = template.parse().map_err(|e: SsrError| e.offset_by(template_offset))?;
+    let rule = SsrRule { pattern, alternatives: patterns.collect(), template };
+    validate_rule(&rule)?;
+    Ok(rule)
+}
+
+impl SsrRule {
+    /// Parses `query` as a rule, as `parse` does, but with `options` for its search pattern.
+    pub fn from_str_with_options(
+        query: &str,
+        options: &PatternOptions,
+    ) -> Result<SsrRule, SsrError> {
+        let (pattern, template) = split_rule(query)?;
+        build_rule(
+            (pattern, offset_within(query, pattern)),
+            (template, offset_within(query, template)),
+            &[],
+            options,
+        )
+    }
+
+    /// Parses `query` as a rule whose search pattern is only a sequence of tokens, for matching
+    /// within macro calls that contain something other than Rust code, e.g. SQL. Neither side of
+    /// the rule needs to be valid Rust. Each placeholder in the search pattern matches one or more
+    /// tokens, up until whatever token follows it in the pattern, or the end of the macro call's
+    /// token tree if nothing does. Placeholder constraints and kinds aren't checked.
+    pub fn parse_tokens(query: &str) -> Result<SsrRule, SsrError> {
+        let (pattern, template) = split_rule(query)?;
+        let raw = RawSearchPattern { tokens: parse_pattern(pattern, false)? };
+        check_no_transforms(&raw.tokens)?;
+        let pattern = SsrPattern {
+            placeholders_by_stand_in: raw.placeholders_by_stand_in(),
+            raw,
+            expr: None,
+            type_ref: None,
+            item: None,
+            path: None,
+            pattern: None,
+            variant: None,
+            stmt: None,
+            attr: None,
+            tokens_only: true,
+            exact_whitespace: false,
+        };
+        let template = SsrTemplate { tokens: parse_pattern(template, false)? };
+        let rule = SsrRule { pattern, alternatives: Vec::new(), template };
         validate_rule(&rule)?;
         Ok(rule)
     }
 }
 
+/// Splits a rule into its search pattern and replacement template. A `==>>` is only a delimiter if
+/// it has whitespace, or the start or end of the rule, on both sides, so code such as `x==>>y` can
+/// appear in either half.
+fn split_rule(query: &str) -> Result<(&str, &str), SsrError> {
+    const DELIMITER: &str = "==>>";
+    let is_boundary = |c: Option<char>| c.filter(|c| !c.is_whitespace()).is_none();
+    let mut starts = query.match_indices(DELIMITER).map(|(start, _)| start).filter(|&start| {
+        is_boundary(query[..start].chars().next_back())
+            && is_boundary(query[start + DELIMITER.len()..].chars().next())
+    });
+    let start = match starts.next() {
+        Some(start) => start,
+        None if query.contains(DELIMITER) => {
+            return Err(SsrError {
+                kind: SsrErrorKind::MissingDelimiter,
+                message: "The delimiter `==>>` must have whitespace on either side".to_string(),
+                range: None,
+            });
+        }
+        None => {
+            return Err(SsrError {
+                kind: SsrErrorKind::MissingDelimiter,
+                message: "Cannot find delemiter `==>>`".to_string(),
+                range: None,
+            })
+        }
+    };
+    if starts.next().is_some() {
+        return Err(SsrError {
+            kind: SsrErrorKind::MultipleDelimiters,
+            message: "More than one delimiter found".to_string(),
+            range: None,
+        });
+    }
+    Ok((query[..start].trim(), query[start + DELIMITER.len()..].trim()))
+}
+
+impl FromStr for SsrRules {
+    type Err = SsrError;
+
+    fn from_str(rules: &str) -> Result<SsrRules, SsrError> {
+        let mut result = Vec::new();
+        for (index, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let rule = line.parse().map_err(|mut e: SsrError| {
+                e.message = format!("Line {}: {}", index + 1, e.message);
+                e.range = e.range.map(|range| range + offset_within(rules, line));
+                e
+            })?;
+            result.push(rule);
+        }
+        Ok(SsrRules(result))
+    }
+}
+
 impl FromStr for RawSearchPattern {
     type Err = SsrError;
 
     fn from_str(pattern_str: &str) -> Result<RawSearchPattern, SsrError> {
-        Ok(RawSearchPattern { tokens: parse_pattern(pattern_str)? })
+        let tokens = parse_pattern(pattern_str, true)?;
+        check_delimiters(&tokenize(pattern_str)?)?;
+        check_no_transforms(&tokens)?;
+        Ok(RawSearchPattern { tokens: replace_chain_wildcards(tokens) })
+    }
+}
+
+/// The name of the method call that we put in place of `.**` when parsing a search pattern.
+pub(crate) const CHAIN_WILDCARD_STAND_IN: &str = "__ssr_any_chain";
+
+/// Replaces each `.**` in `tokens`, which matches zero or more method calls in a chain, with a call
+/// to a method named `CHAIN_WILDCARD_STAND_IN`, so that the pattern can be parsed as Rust code.
+pub(crate) fn replace_chain_wildcards(tokens: Vec<PatternElement>) -> Vec<PatternElement> {
+    let is_token = |element: Option<&PatternElement>, kind| matches!(element, Some(PatternElement::Token(t)) if t.kind == kind);
+    let mut res: Vec<PatternElement> = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(element) = tokens.next() {
+        if is_token(Some(&element), SyntaxKind::STAR)
+            && is_token(res.last(), SyntaxKind::DOT)
+            && is_token(tokens.peek(), SyntaxKind::STAR)
+        {
+            tokens.next();
+            for (kind, text) in &[
+                (SyntaxKind::IDENT, CHAIN_WILDCARD_STAND_IN),
+                (SyntaxKind::L_PAREN, "("),
+                (SyntaxKind::R_PAREN, ")"),
+            ] {
+                res.push(PatternElement::Token(Token { kind: *kind, text: SmolStr::new(*text) }));
+            }
+        } else {
+            res.push(element);
+        }
     }
+    res
 }
 
 impl RawSearchPattern {
-    /// Returns this search pattern as Rust source code that we can feed to the Rust parser.
-    fn as_rust_code(&self) -> String {
+    /// Returns the identifier and keyword tokens in this pattern, without duplicates.
+    pub(crate) fn literals(&self) -> Vec<SmolStr> {
+        let mut literals: Vec<SmolStr> = Vec::new();
+        for element in &self.tokens {
+            if let PatternElement::Token(token) = element {
+                if (token.kind == SyntaxKind::IDENT || token.kind.is_keyword())
+                    && token.text != CHAIN_WILDCARD_STAND_IN
+                    && !literals.contains(&token.text)
+                {
+                    literals.push(token.text.clone());
+                }
+            }
+        }
+        literals
+    }
+
+    /// Returns this search pattern as Rust source code that we can feed to the Rust parser. There's
+    /// one candidate for each combination of the shapes that our multi-placeholders might take.
+    /// Patterns without multi-placeholders only have a single candidate. Fails if there would be
+    /// more than `MAX_CANDIDATES`.
+    fn rust_code_candidates(&self) -> Result<Vec<String>, SsrError> {
+        let shapes: Vec<&[MultiShape]> = (0..self.tokens.len())
+            .filter(|&i| matches!(&self.tokens[i], PatternElement::Placeholder(p) if p.multi))
+            .map(|i| multi_shapes(&self.tokens, i))
+            .collect();
+        let num_candidates = shapes
+            .iter()
+            .try_fold(1usize, |n, s| n.checked_mul(s.len()))
+            .filter(|&n| n <= MAX_CANDIDATES);
+        let num_candidates = match num_candidates {
+            Some(n) => n,
+            None => bail!(
+                "Pattern has too many multi-placeholders whose kind can't be told from the code \
+                 around them"
+            ),
+        };
+        Ok((0..num_candidates).map(|candidate| self.as_rust_code(&shapes, candidate)).collect())
+    }
+
+    /// Returns this search pattern as Rust source code. The shape of each multi-placeholder is
+    /// picked from its entry in `shapes` by the digits of `candidate`, least significant first,
+    /// where each digit's base is the number of shapes for that placeholder.
+    fn as_rust_code(&self, shapes: &[&[MultiShape]], mut candidate: usize) -> String {
+        let mut shapes = shapes.iter();
         let mut res = String::new();
         for t in &self.tokens {
-            res.push_str(match t {
-                PatternElement::Token(token) => token.text.as_str(),
-                PatternElement::Placeholder(placeholder) => placeholder.stand_in_name.as_str(),
-            });
+            match t {
+                PatternElement::Token(token) => res.push_str(token.text.as_str()),
+                PatternElement::Placeholder(placeholder) if placeholder.multi => {
+                    let options = shapes.next().expect("a shape for each multi-placeholder");
+                    let shape = options[candidate % options.len()];
+                    candidate /= options.len();
+                    res.push_str(&shape.stand_in_text(&placeholder.stand_in_name));
+                }
+                PatternElement::Placeholder(placeholder) => {
+                    res.push_str(placeholder.stand_in_name.as_str())
+                }
+            }
         }
         res
     }
@@ -103,26 +421,139 @@ impl FromStr for SsrPattern {
     type Err = SsrError;
 
     fn from_str(pattern_str: &str) -> Result<SsrPattern, SsrError> {
-        let raw: RawSearchPattern = pattern_str.parse()?;
-        let raw_str = raw.as_rust_code();
-        let res = SsrPattern {
-            expr: ast::Expr::parse(&raw_str).ok().map(|n| n.syntax().clone()),
-            type_ref: ast::TypeRef::parse(&raw_str).ok().map(|n| n.syntax().clone()),
-            item: ast::ModuleItem::parse(&raw_str).ok().map(|n| n.syntax().clone()),
-            path: ast::Path::parse(&raw_str).ok().map(|n| n.syntax().clone()),
-            pattern: ast::Pat::parse(&raw_str).ok().map(|n| n.syntax().clone()),
-            placeholders_by_stand_in: raw.placeholders_by_stand_in(),
-            raw,
-        };
-        if res.expr.is_none()
-            && res.type_ref.is_none()
-            && res.item.is_none()
-            && res.path.is_none()
-            && res.pattern.is_none()
-        {
-            bail!("Pattern is not a valid Rust expression, type, item, path or pattern");
+        SsrPattern::from_raw(pattern_str.parse()?, &PatternOptions::default())
+    }
+}
+
+/// Options that control how a search pattern is parsed.
+#[derive(Debug, Clone, Default)]
+pub struct PatternOptions {
+    /// If true, whitespace in the pattern is significant. Wherever the pattern has whitespace
+    /// between two tokens, the code must too, and vice versa, and the whitespace must contain the
+    /// same number of newlines. Indentation isn't compared, nor is whitespace next to a
+    /// multi-placeholder or within code matched by a placeholder.
+    pub exact_whitespace: bool,
+    /// If true, a placeholder that's all there is within a block, as in `if $c { $body }`, matches
+    /// all of the block's statements, rather than just an expression.
+    pub block_statements: bool,
+}
+
+impl SsrPattern {
+    /// Parses `pattern_str` as a search pattern, as `parse` does, but with `options`.
+    pub fn from_str_with_options(
+        pattern_str: &str,
+        options: &PatternOptions,
+    ) -> Result<SsrPattern, SsrError> {
+        SsrPattern::from_raw(pattern_str.parse()?, options)
+    }
+
+    pub(crate) fn from_raw(
+        mut raw: RawSearchPattern,
+        options: &PatternOptions,
+    ) -> Result<SsrPattern, SsrError> {
+        let mut placeholders_by_stand_in = raw.placeholders_by_stand_in();
+        for raw_str in raw.rust_code_candidates()? {
+            let expr = parse_fragment(&raw_str, PatternKind::Expr);
+            let type_ref = parse_fragment(&raw_str, PatternKind::TypeRef);
+            let item = parse_fragment(&raw_str, PatternKind::Item);
+            let path = parse_fragment(&raw_str, PatternKind::Path);
+            let pattern = parse_fragment(&raw_str, PatternKind::Pattern);
+            let variant = parse_fragment(&raw_str, PatternKind::Variant);
+            let stmt = parse_fragment(&raw_str, PatternKind::Statement);
+            let attr = parse_fragment(&raw_str, PatternKind::Attribute);
+            if expr.is_some()
+                || type_ref.is_some()
+                || item.is_some()
+                || path.is_some()
+                || pattern.is_some()
+                || variant.is_some()
+                || stmt.is_some()
+                || attr.is_some()
+            {
+                let trees = expr.iter().chain(&item).chain(&stmt);
+                for tree in trees.filter(|_| options.block_statements) {
+                    for stand_in in block_content_stand_ins(tree) {
+                        make_statements_placeholder(
+                            &mut raw,
+                            &mut placeholders_by_stand_in,
+                            &stand_in,
+                        );
+                    }
+                }
+                return Ok(SsrPattern {
+                    raw,
+                    placeholders_by_stand_in,
+                    expr,
+                    type_ref,
+                    item,
+                    path,
+                    pattern,
+                    variant,
+                    stmt,
+                    attr,
+                    tokens_only: false,
+                    exact_whitespace: options.exact_whitespace,
+                });
+            }
+        }
+        Err(SsrError {
+            kind: SsrErrorKind::InvalidPattern,
+            message: "Pattern is not a valid Rust expression, type, item, path or pattern"
+                .to_string(),
+            range: None,
+        })
+    }
+}
+
+/// Returns the text of each expression in `tree` that is all there is within the braces of a block,
+/// as in `if $c { $body }`.
+fn block_content_stand_ins(tree: &SyntaxNode) -> Vec<String> {
+    tree.descendants()
+        .filter(|n| n.kind() == SyntaxKind::BLOCK_EXPR)
+        .filter_map(|block| {
+            let mut content = block
+                .children_with_tokens()
+                .filter(|c| !c.kind().is_trivia() && c.kind() != T!['{'] && c.kind() != T!['}']);
+            match (content.next(), content.next()) {
+                (Some(only), None) if only.kind() == SyntaxKind::PATH_EXPR => {
+                    Some(only.to_string())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// A placeholder that is all there is within a block, as in `if $c { $body }`, matches all the
+/// statements of the block, as if it were written `$body:*`. We don't do this for placeholders with
+/// a kind or constraints, since those only match a single thing, or for ones that appear more than
+/// once, since multi-placeholders can't be repeated.
+fn make_statements_placeholder(
+    raw: &mut RawSearchPattern,
+    placeholders_by_stand_in: &mut FxHashMap<SmolStr, Placeholder>,
+    stand_in: &str,
+) {
+    let ident = match placeholders_by_stand_in.get_mut(stand_in) {
+        Some(p) if p.kind.is_none() && p.constraints.is_empty() && !p.anonymous => p.ident.clone(),
+        _ => return,
+    };
+    let occurrences = raw
+        .tokens
+        .iter()
+        .filter(|e| matches!(e, PatternElement::Placeholder(p) if p.ident == ident))
+        .count();
+    if occurrences != 1 {
+        return;
+    }
+    for placeholder in
+        placeholders_by_stand_in.values_mut().chain(raw.tokens.iter_mut().filter_map(|e| match e {
+            PatternElement::Placeholder(p) => Some(p),
+            PatternElement::Token(_) => None,
+        }))
+    {
+        if placeholder.ident == ident {
+            placeholder.multi = true;
         }
-        Ok(res)
     }
 }
 
@@ -130,29 +561,103 @@ impl FromStr for SsrTemplate {
     type Err = SsrError;
 
     fn from_str(pattern_str: &str) -> Result<SsrTemplate, SsrError> {
-        let tokens = parse_pattern(pattern_str)?;
+        let tokens = parse_pattern(pattern_str, false)?;
         // Validate that the template is a valid fragment of Rust code. We reuse the validation
         // logic for search patterns since the only thing that differs is the error message.
-        if SsrPattern::from_str(pattern_str).is_err() {
-            bail!("Replacement is not a valid Rust expression, type, item, path or pattern");
+        // Transforms only affect how a placeholder is rendered, so they can be ignored here.
+        // `$qualifiers` can go where no placeholder could, so we also try without it.
+        let without_qualifiers = tokens
+            .iter()
+            .filter(|t| {
+                !matches!(t, PatternElement::Placeholder(p) if p.ident == QUALIFIERS_PLACEHOLDER)
+            })
+            .cloned()
+            .collect();
+        let is_valid = |tokens: Vec<PatternElement>| {
+            let raw = RawSearchPattern { tokens: replace_chain_wildcards(tokens) };
+            SsrPattern::from_raw(raw, &PatternOptions::default()).is_ok()
+        };
+        if !is_valid(tokens.clone()) && !is_valid(without_qualifiers) {
+            return Err(SsrError {
+                kind: SsrErrorKind::InvalidPattern,
+                message: "Replacement is not a valid Rust expression, type, item, path or pattern"
+                    .to_string(),
+                range: None,
+            });
         }
         // Our actual template needs to preserve whitespace, so we can't reuse `tokens`.
         Ok(SsrTemplate { tokens })
     }
 }
 
-/// Returns `pattern_str`, parsed as a search or replace pattern. If `remove_whitespace` is true,
-/// then any whitespace tokens will be removed, which we do for the search pattern, but not for the
-/// replace pattern.
-fn parse_pattern(pattern_str: &str) -> Result<Vec<PatternElement>, SsrError> {
+/// Returns `pattern_str`, parsed as a search or replace pattern. If `allow_repeats` is true, which
+/// it is for search patterns, a placeholder may appear more than once, with each repeat requiring
+/// the same code as the first. Any constraints must be given where the placeholder first appears,
+/// and each repeat is given those same constraints.
+fn parse_pattern(pattern_str: &str, allow_repeats: bool) -> Result<Vec<PatternElement>, SsrError> {
     let mut res = Vec::new();
-    let mut placeholder_names = FxHashSet::default();
+    let mut placeholders: FxHashMap<SmolStr, Placeholder> = FxHashMap::default();
+    let mut anonymous_count = 0;
     let mut tokens = tokenize(pattern_str)?.into_iter();
+    let mut offset = TextSize::from(0);
     while let Some(token) = tokens.next() {
-        if token.kind == SyntaxKind::DOLLAR {
-            let placeholder = parse_placeholder(&mut tokens)?;
-            if !placeholder_names.insert(placeholder.ident.clone()) {
-                bail!("Name `{}` repeats more than once", placeholder.ident);
+        let start = offset;
+        offset += TextSize::of(token.text.as_str());
+        let next_kind = tokens.as_slice().first().map(|t| t.kind);
+        if token.kind == SyntaxKind::DOLLAR && next_kind == Some(SyntaxKind::DOLLAR) {
+            // `$$` is an escaped `$`, which we keep as a single literal token.
+            if let Some(escaped) = tokens.next() {
+                offset += TextSize::of(escaped.text.as_str());
+            }
+            res.push(PatternElement::Token(token));
+        } else if token.kind == SyntaxKind::DOLLAR {
+            let before = tokens.clone();
+            let result = parse_placeholder(&mut tokens);
+            // Work out the range of each token that parsing the placeholder consumed.
+            let mut last_token_range = TextRange::at(start, TextSize::of(token.text.as_str()));
+            let consumed_count = before.len() - tokens.len();
+            for consumed in before.take(consumed_count) {
+                last_token_range = TextRange::at(offset, TextSize::of(consumed.text.as_str()));
+                offset += last_token_range.len();
+            }
+            let mut placeholder = result.map_err(|e| e.or_range(last_token_range))?;
+            if placeholder.ident == "_" {
+                placeholder.anonymous = true;
+                placeholder.ident = SmolStr::new(format!("__anonymous_{}", anonymous_count));
+                placeholder.stand_in_name =
+                    stand_in_name(&placeholder.ident, &placeholder.constraints);
+                anonymous_count += 1;
+            } else if let Some(first) = placeholders.get(&placeholder.ident) {
+                let range = TextRange::new(start, offset);
+                if !allow_repeats {
+                    return Err(SsrError {
+                        kind: SsrErrorKind::DuplicatePlaceholder,
+                        message: format!("Name `{}` repeats more than once", placeholder.ident),
+                        range: Some(range),
+                    });
+                }
+                if first.multi || placeholder.multi {
+                    return Err(SsrError {
+                        kind: SsrErrorKind::DuplicatePlaceholder,
+                        message: format!(
+                            "Multi-placeholder `${}` can't be repeated",
+                            placeholder.ident
+                        ),
+                        range: Some(range),
+                    });
+                }
+                if placeholder != Placeholder::new(placeholder.ident.clone(), Vec::new()) {
+                    return Err(SsrError::with_range(
+                        format!(
+                            "Constraints for `${}` must be given where it first appears",
+                            placeholder.ident
+                        ),
+                        range,
+                    ));
+                }
+                placeholder = first.clone();
+            } else {
+                placeholders.insert(placeholder.ident.clone(), placeholder.clone());
             }
             res.push(PatternElement::Placeholder(placeholder));
         } else {
@@ -162,25 +667,306 @@ fn parse_pattern(pattern_str: &str) -> Result<Vec<PatternElement>, SsrError> {
     Ok(res)
 }
 
+/// Returns `text` parsed as the kind of syntax fragment given by `kind`, provided it parses without
+/// errors.
+pub(crate) fn parse_fragment(text: &str, kind: PatternKind) -> Option<SyntaxNode> {
+    match kind {
+        PatternKind::Expr => ast::Expr::parse(text).ok().map(|n| n.syntax().clone()),
+        PatternKind::TypeRef => ast::TypeRef::parse(text).ok().map(|n| n.syntax().clone()),
+        PatternKind::Item => ast::ModuleItem::parse(text).ok().map(|n| n.syntax().clone()),
+        PatternKind::Path => ast::Path::parse(text).ok().map(|n| n.syntax().clone()),
+        PatternKind::Pattern => ast::Pat::parse(text).ok().map(|n| n.syntax().clone()),
+        PatternKind::Variant => parse_enum_variant(text),
+        PatternKind::Statement => parse_stmt(text),
+        PatternKind::Attribute => parse_attr(text),
+    }
+}
+
+/// Returns `text` parsed as an enum variant definition. There's no parser entry point for variants,
+/// so we parse `text` as the only variant of an otherwise empty enum.
+fn parse_enum_variant(text: &str) -> Option<SyntaxNode> {
+    let item = ast::ModuleItem::parse(&format!("enum __SsrEnum {{ {} }}", text)).ok()?;
+    let mut variants = ast::EnumDef::cast(item.syntax().clone())?.variant_list()?.variants();
+    match (variants.next(), variants.next()) {
+        // A variant without fields is just a name. Such patterns are meant as paths, e.g. `None`,
+        // so we don't want them to also match the definition of the variant.
+        (Some(variant), None) if variant.field_def_list().is_some() => {
+            Some(variant.syntax().clone())
+        }
+        _ => None,
+    }
+}
+
+/// Returns `text` parsed as a statement. We parse `text` as the only statement in a block, which
+/// means that expressions must be followed by a semicolon. Otherwise they'd be the block's tail
+/// expression and we'd return None.
+fn parse_stmt(text: &str) -> Option<SyntaxNode> {
+    let block =
+        ast::BlockExpr::cast(ast::Expr::parse(&format!("{{ {} }}", text)).ok()?.syntax().clone())?;
+    if block.expr().is_some() {
+        return None;
+    }
+    let mut statements = block.statements();
+    match (statements.next(), statements.next()) {
+        (Some(stmt), None) => Some(stmt.syntax().clone()),
+        _ => None,
+    }
+}
+
+/// Returns `text` parsed as an outer attribute. We parse `text` as the only attribute on an empty
+/// function.
+fn parse_attr(text: &str) -> Option<SyntaxNode> {
+    use ast::{AttrsOwner, ModuleItemOwner};
+    // An inner attribute, e.g. `#![allow(dead_code)]`, is parsed at the start of a file, since
+    // that's where inner attributes can go without anything else around them.
+    let is_inner = text.trim_start().starts_with("#!");
+    let mut attrs = if is_inner {
+        let parse = ast::SourceFile::parse(text);
+        if !parse.errors().is_empty() || parse.tree().items().next().is_some() {
+            return None;
+        }
+        parse.tree().attrs()
+    } else {
+        let item = ast::ModuleItem::parse(&format!("{} fn __ssr_attr() {{}}", text)).ok()?;
+        ast::FnDef::cast(item.syntax().clone())?.attrs()
+    };
+    match (attrs.next(), attrs.next()) {
+        (Some(attr), None) if attr.excl_token().is_some() == is_inner => {
+            Some(attr.syntax().clone())
+        }
+        _ => None,
+    }
+}
+
+/// Splits a search pattern at each top-level `|`, returning the alternatives. A `|` only separates
+/// alternatives if it's where a binary operator could go and isn't part of `||` or `|=`, so closures
+/// and logical or aren't affected. This does however mean that a bitwise or, or an or-pattern, needs
+/// to be put in parentheses.
+/// Each alternative is returned along with its offset within `pattern_str`.
+fn split_alternatives(pattern_str: &str) -> Result<Vec<(TextSize, String)>, SsrError> {
+    let tokens = tokenize(pattern_str)?;
+    let mut alternatives = vec![String::new()];
+    let mut starts = vec![TextSize::from(0)];
+    let token_ends: Vec<TextSize> = tokens
+        .iter()
+        .scan(TextSize::from(0), |end, token| {
+            *end += TextSize::of(token.text.as_str());
+            Some(*end)
+        })
+        .collect();
+    let mut depth = 0usize;
+    // Whether the next token starts an operand, in which case a `|` starts a closure.
+    let mut expecting_operand = true;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let next_kind = tokens.get(i + 1).map(|t| t.kind);
+        match token.kind {
+            SyntaxKind::WHITESPACE | SyntaxKind::COMMENT | T![$] => {}
+            T!['('] | T!['['] | T!['{'] => {
+                depth += 1;
+                expecting_operand = true;
+            }
+            T![')'] | T![']'] | T!['}'] => {
+                depth = depth.saturating_sub(1);
+                expecting_operand = false;
+            }
+            T![|] if expecting_operand => {
+                // Skip over the closure's parameters, up to and including its closing `|`.
+                alternatives.last_mut().unwrap().push_str(&token.text);
+                let mut param_depth = 0usize;
+                i += 1;
+                while let Some(token) = tokens.get(i) {
+                    alternatives.last_mut().unwrap().push_str(&token.text);
+                    match token.kind {
+                        T!['('] | T!['['] | T!['{'] | T![<] => param_depth += 1,
+                        T![')'] | T![']'] | T!['}'] | T![>] => {
+                            param_depth = param_depth.saturating_sub(1)
+                        }
+                        T![|] if param_depth == 0 => break,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            T![|] if depth == 0 && next_kind != Some(T![|]) && next_kind != Some(T![=]) => {
+                alternatives.push(String::new());
+                starts.push(token_ends[i]);
+                expecting_operand = true;
+                i += 1;
+                continue;
+            }
+            T![|] if next_kind == Some(T![|]) || next_kind == Some(T![=]) => {
+                alternatives.last_mut().unwrap().push_str(&token.text);
+                alternatives.last_mut().unwrap().push_str(&tokens[i + 1].text);
+                expecting_operand = true;
+                i += 2;
+                continue;
+            }
+            kind => expecting_operand = !ends_operand(kind),
+        }
+        alternatives.last_mut().unwrap().push_str(&token.text);
+        i += 1;
+    }
+    if alternatives.len() > 1 && alternatives.iter().any(|a| a.trim().is_empty()) {
+        bail!("Search pattern has an empty alternative");
+    }
+    Ok(starts
+        .into_iter()
+        .zip(alternatives)
+        .map(|(start, a)| {
+            let leading_whitespace = TextSize::of(a.as_str()) - TextSize::of(a.trim_start());
+            (start + leading_whitespace, a.trim().to_owned())
+        })
+        .collect())
+}
+
+/// Returns whether a token of kind `kind` can be the last token of an operand.
+fn ends_operand(kind: SyntaxKind) -> bool {
+    kind.is_literal()
+        || matches!(
+            kind,
+            SyntaxKind::IDENT
+                | SyntaxKind::LIFETIME
+                | T![?]
+                | T![_]
+                | T![self]
+                | T![super]
+                | T![crate]
+                | T![true]
+                | T![false]
+        )
+}
+
+/// Returns whether `a` and `b` have the same tokens, ignoring whitespace, once the placeholders of
+/// one are consistently renamed to those of the other.
+pub(crate) fn rules_alpha_equivalent(a: &SsrRule, b: &SsrRule) -> bool {
+    let patterns_a = std::iter::once(&a.pattern).chain(&a.alternatives);
+    let patterns_b = std::iter::once(&b.pattern).chain(&b.alternatives);
+    if a.alternatives.len() != b.alternatives.len() {
+        return false;
+    }
+    let mut renames = (FxHashMap::default(), FxHashMap::default());
+    patterns_a
+        .zip(patterns_b)
+        .all(|(a, b)| elements_alpha_equivalent(&a.raw.tokens, &b.raw.tokens, &mut renames))
+        && elements_alpha_equivalent(&a.template.tokens, &b.template.tokens, &mut renames)
+}
+
+/// Checks `a` against `b`, recording placeholder renames from `a` to `b` and from `b` to `a` in
+/// `renames`, so that the renaming is one-to-one.
+fn elements_alpha_equivalent(
+    a: &[PatternElement],
+    b: &[PatternElement],
+    renames: &mut (FxHashMap<SmolStr, SmolStr>, FxHashMap<SmolStr, SmolStr>),
+) -> bool {
+    fn non_trivia(elements: &[PatternElement]) -> impl Iterator<Item = &PatternElement> {
+        elements.iter().filter(|e| match e {
+            PatternElement::Token(token) => !token.kind.is_trivia(),
+            PatternElement::Placeholder(_) => true,
+        })
+    }
+    let (mut a, mut b) = (non_trivia(a), non_trivia(b));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return true,
+            (Some(PatternElement::Token(a)), Some(PatternElement::Token(b))) => {
+                if a != b {
+                    return false;
+                }
+            }
+            (Some(PatternElement::Placeholder(a)), Some(PatternElement::Placeholder(b))) => {
+                if a.multi != b.multi || a.constraints != b.constraints {
+                    return false;
+                }
+                let a_to_b = renames.0.entry(a.ident.clone()).or_insert_with(|| b.ident.clone());
+                let b_to_a = renames.1.entry(b.ident.clone()).or_insert_with(|| a.ident.clone());
+                if *a_to_b != b.ident || *b_to_a != a.ident {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
 /// Checks for errors in a rule. e.g. the replace pattern referencing placeholders that the search
 /// pattern didn't define.
 fn validate_rule(rule: &SsrRule) -> Result<(), SsrError> {
     let mut defined_placeholders = std::collections::HashSet::new();
     for p in &rule.pattern.raw.tokens {
         if let PatternElement::Placeholder(placeholder) = p {
-            defined_placeholders.insert(&placeholder.ident);
+            if !placeholder.anonymous {
+                defined_placeholders.insert(&placeholder.ident);
+            }
+        }
+    }
+    for alternative in &rule.alternatives {
+        let alternative_placeholders: std::collections::HashSet<_> = alternative
+            .raw
+            .tokens
+            .iter()
+            .filter_map(|p| match p {
+                PatternElement::Placeholder(placeholder) if !placeholder.anonymous => {
+                    Some(&placeholder.ident)
+                }
+                _ => None,
+            })
+            .collect();
+        if alternative_placeholders != defined_placeholders {
+            bail!("Alternatives of a search pattern must define the same placeholders");
         }
     }
     let mut undefined = Vec::new();
     for p in &rule.template.tokens {
         if let PatternElement::Placeholder(placeholder) = p {
-            if !defined_placeholders.contains(&placeholder.ident) {
+            if placeholder.anonymous {
+                bail!("The anonymous placeholder `$_` can't be used in a replacement");
+            }
+            if !defined_placeholders.contains(&placeholder.ident)
+                && placeholder.ident != QUALIFIERS_PLACEHOLDER
+            {
                 undefined.push(format!("${}", placeholder.ident));
             }
         }
     }
     if !undefined.is_empty() {
-        bail!("Replacement contains undefined placeholders: {}", undefined.join(", "));
+        return Err(SsrError {
+            kind: SsrErrorKind::UndefinedPlaceholder,
+            message: format!(
+                "Replacement contains undefined placeholders: {}",
+                undefined.join(", ")
+            ),
+            range: None,
+        });
+    }
+    for p in &rule.template.tokens {
+        if let PatternElement::Placeholder(Placeholder { transform: Some(name), .. }) = p {
+            if Transform::from_name(name).is_none() {
+                bail!(
+                    "Unknown placeholder transform `{}`. Expected one of upper, lower or pascal",
+                    name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if any placeholder in a search pattern has a transform, which is really an
+/// unknown placeholder kind, since transforms are only valid in templates.
+fn check_no_transforms(tokens: &[PatternElement]) -> Result<(), SsrError> {
+    for p in tokens {
+        if let PatternElement::Placeholder(Placeholder { transform: Some(name), .. }) = p {
+            bail!(
+                "Unknown placeholder kind `{}`. Expected one of expr, ident, path, ty, pat, \
+                 literal or block. Put a space after the `:` if `{}` is a type",
+                name,
+                name
+            );
+        }
     }
     Ok(())
 }
@@ -189,7 +975,11 @@ fn tokenize(source: &str) -> Result<Vec<Token>, SsrError> {
     let mut start = 0;
     let (raw_tokens, errors) = ra_syntax::tokenize(source);
     if let Some(first_error) = errors.first() {
-        bail!("Failed to parse pattern: {}", first_error);
+        return Err(SsrError {
+            kind: SsrErrorKind::TokenizeFailed,
+            message: format!("Failed to parse pattern: {}", first_error),
+            range: Some(first_error.range()),
+        });
     }
     let mut tokens: Vec<Token> = Vec::new();
     for raw_token in raw_tokens {
@@ -203,31 +993,538 @@ fn tokenize(source: &str) -> Result<Vec<Token>, SsrError> {
     Ok(tokens)
 }
 
+/// Checks that each `(`, `[` and `{` in `tokens` is closed by the matching delimiter. This gives a
+/// more helpful error than failing to parse the search pattern as anything.
+fn check_delimiters(tokens: &[Token]) -> Result<(), SsrError> {
+    let mut open: Vec<(&Token, TextRange)> = Vec::new();
+    let mut offset = TextSize::from(0);
+    for token in tokens {
+        let range = TextRange::at(offset, TextSize::of(token.text.as_str()));
+        offset += range.len();
+        let opener = match token.kind {
+            T!['('] | T!['['] | T!['{'] => {
+                open.push((token, range));
+                continue;
+            }
+            T![')'] => T!['('],
+            T![']'] => T!['['],
+            T!['}'] => T!['{'],
+            _ => continue,
+        };
+        match open.pop() {
+            Some((open_token, _)) if open_token.kind == opener => {}
+            _ => {
+                return Err(SsrError {
+                    kind: SsrErrorKind::InvalidPattern,
+                    message: format!("Unbalanced `{}` in search pattern", token.text),
+                    range: Some(range),
+                })
+            }
+        }
+    }
+    if let Some((token, range)) = open.pop() {
+        return Err(SsrError {
+            kind: SsrErrorKind::InvalidPattern,
+            message: format!("Unbalanced `{}` in search pattern", token.text),
+            range: Some(range),
+        });
+    }
+    Ok(())
+}
+
 fn parse_placeholder(tokens: &mut std::vec::IntoIter<Token>) -> Result<Placeholder, SsrError> {
     let mut name = None;
+    let mut constraints = Vec::new();
     if let Some(token) = tokens.next() {
         match token.kind {
-            SyntaxKind::IDENT => {
+            SyntaxKind::IDENT | T![_] => {
+                name = Some(token.text);
+            }
+            SyntaxKind::L_CURLY => {
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| SsrError::invalid_rule("Unexpected end of placeholder"))?;
+                if token.kind != SyntaxKind::IDENT && token.kind != T![_] {
+                    bail!("Placeholders should be ${name} or ${name:constraint}");
+                }
                 name = Some(token.text);
+                loop {
+                    let token = tokens.next().ok_or_else(|| {
+                        SsrError::invalid_rule("Placeholder is missing a closing `}`")
+                    })?;
+                    match token.kind {
+                        SyntaxKind::COLON => constraints.push(parse_constraint(tokens)?),
+                        SyntaxKind::R_CURLY => break,
+                        SyntaxKind::WHITESPACE => {}
+                        _ => bail!("Unexpected token while parsing placeholder: '{}'", token.text),
+                    }
+                }
             }
             _ => {
                 bail!("Placeholders should be $name");
             }
         }
     }
-    let name = name.ok_or_else(|| SsrError::new("Placeholder ($) with no name"))?;
-    Ok(Placeholder::new(name))
+    let name = name.ok_or_else(|| SsrError::invalid_rule("Placeholder ($) with no name"))?;
+    let mut placeholder = Placeholder::new(name, constraints);
+    // A `:*` directly after the name makes this a multi-placeholder, while `:` directly followed
+    // by a name makes it a placeholder of that kind. We need to look two tokens ahead, since a
+    // colon on its own might be part of the pattern, e.g. `fn f($a: i32)`.
+    let mut lookahead = tokens.clone();
+    if let (Some(colon), Some(next)) = (lookahead.next(), lookahead.next()) {
+        if colon.kind == SyntaxKind::COLON && next.kind == SyntaxKind::STAR {
+            placeholder.multi = true;
+            *tokens = lookahead;
+        } else if colon.kind == SyntaxKind::COLON && next.kind == SyntaxKind::IDENT {
+            *tokens = lookahead;
+            placeholder.kind = match next.text.as_str() {
+                "expr" => Some(PlaceholderKind::Expr),
+                "ident" => Some(PlaceholderKind::Ident),
+                "path" => Some(PlaceholderKind::Path),
+                "ty" => Some(PlaceholderKind::Ty),
+                "pat" => Some(PlaceholderKind::Pat),
+                "literal" => Some(PlaceholderKind::Literal),
+                "block" => Some(PlaceholderKind::Block),
+                // Whether this is a valid transform, or even allowed at all, depends on whether
+                // we're parsing a template, so it gets checked later.
+                _ => {
+                    placeholder.transform = Some(next.text.clone());
+                    None
+                }
+            };
+        }
+    }
+    if tokens.as_slice().first().map(|t| t.kind) == Some(T![~]) {
+        tokens.next();
+        placeholder.text_constraint = Some(TextConstraint::new(parse_text_constraint(tokens)?)?);
+    }
+    Ok(placeholder)
+}
+
+/// Parses the pattern following the `~` of a text constraint. This is either a string literal, which
+/// may contain any pattern, or a word optionally anchored with `^` and `$`, e.g. `$a~^get_`.
+fn parse_text_constraint(tokens: &mut std::vec::IntoIter<Token>) -> Result<String, SsrError> {
+    if let Some(token) = tokens.as_slice().first() {
+        if token.kind == SyntaxKind::STRING {
+            // The contents are used as is, so that regex escapes such as `\d` don't need doubling.
+            let text = token.text[1..token.text.len() - 1].to_owned();
+            tokens.next();
+            return Ok(text);
+        }
+    }
+    let mut source = String::new();
+    while let Some(token) = tokens.as_slice().first() {
+        if !matches!(token.kind, SyntaxKind::IDENT | SyntaxKind::INT_NUMBER | T![^] | T![$]) {
+            break;
+        }
+        source.push_str(&token.text);
+        tokens.next();
+    }
+    if source.is_empty() {
+        bail!("Placeholder text constraint `~` must be followed by a pattern");
+    }
+    Ok(source)
+}
+
+fn parse_constraint(tokens: &mut std::vec::IntoIter<Token>) -> Result<Constraint, SsrError> {
+    let constraint_type = tokens
+        .find(|t| t.kind != SyntaxKind::WHITESPACE)
+        .ok_or_else(|| SsrError::invalid_rule("Placeholder constraint is missing"))?;
+    match constraint_type.text.as_str() {
+        "lifetime" => Ok(Constraint::Lifetime),
+        "index" => Ok(Constraint::TupleIndex),
+        "any_loop" => Ok(Constraint::AnyLoop),
+        "is_option" => Ok(Constraint::IsOption),
+        "is_result" => Ok(Constraint::IsResult),
+        "is_snake_case" => Ok(Constraint::IsSnakeCase),
+        "is_upper_camel_case" => Ok(Constraint::IsUpperCamelCase),
+        "not_contains" => Ok(Constraint::NotContains(parse_constraint_arg(tokens)?.parse()?)),
+        "not" => Ok(Constraint::Not(parse_constraint_arg(tokens)?.parse()?)),
+        _ => bail!("Unsupported placeholder constraint `{}`", constraint_type.text),
+    }
+}
+
+/// The kinds of node whose children a multi-placeholder can stand in for.
+const MULTI_PLACEHOLDER_PARENTS: &[SyntaxKind] = &[
+    SyntaxKind::ARG_LIST,
+    SyntaxKind::ARRAY_EXPR,
+    SyntaxKind::BLOCK_EXPR,
+    SyntaxKind::ENUM_VARIANT_LIST,
+    SyntaxKind::ITEM_LIST,
+    SyntaxKind::MATCH_ARM_LIST,
+    SyntaxKind::PARAM_LIST,
+    SyntaxKind::RECORD_FIELD_DEF_LIST,
+    SyntaxKind::RECORD_FIELD_LIST,
+    SyntaxKind::RECORD_FIELD_PAT_LIST,
+    SyntaxKind::SLICE_PAT,
+    SyntaxKind::TOKEN_TREE,
+    SyntaxKind::TUPLE_EXPR,
+    SyntaxKind::TUPLE_FIELD_DEF_LIST,
+    SyntaxKind::TUPLE_PAT,
+    SyntaxKind::TUPLE_STRUCT_PAT,
+    SyntaxKind::TUPLE_TYPE,
+    SyntaxKind::TYPE_ARG_LIST,
+    SyntaxKind::TYPE_PARAM_LIST,
+    SyntaxKind::USE_TREE_LIST,
+];
+
+/// Checks that each multi-placeholder in `pattern` is somewhere that it can match a list of
+/// things, such as function arguments, in at least one of the ways that we parsed the pattern.
+/// Anywhere else, it would only ever match a single node, or nothing at all.
+fn validate_multi_placeholders(pattern: &SsrPattern) -> Result<(), SsrError> {
+    let trees: Vec<&SyntaxNode> = [
+        &pattern.expr,
+        &pattern.type_ref,
+        &pattern.item,
+        &pattern.path,
+        &pattern.pattern,
+        &pattern.variant,
+        &pattern.stmt,
+        &pattern.attr,
+    ]
+    .iter()
+    .filter_map(|tree| tree.as_ref())
+    .collect();
+    for placeholder in pattern.placeholders_by_stand_in.values().filter(|p| p.multi) {
+        let in_list = trees.iter().any(|tree| {
+            tree.descendants_with_tokens().any(|element| {
+                let parent = match element.parent() {
+                    Some(parent) => parent,
+                    None => return false,
+                };
+                placeholder.is_stand_in_text(&element.to_string())
+                    && !placeholder.is_stand_in_text(&parent.to_string())
+                    && MULTI_PLACEHOLDER_PARENTS.contains(&parent.kind())
+            })
+        });
+        if !in_list {
+            bail!(
+                "`${}:*` can only be used where a list of things is allowed, such as the \
+                 arguments of a call",
+                placeholder.ident
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the text between the parentheses that follow a constraint's name, e.g. the `bar()` in
+/// `not_contains(bar())`.
+fn parse_constraint_arg(tokens: &mut std::vec::IntoIter<Token>) -> Result<String, SsrError> {
+    if tokens.next().map(|t| t.kind) != Some(T!['(']) {
+        bail!("Expected `(` after placeholder constraint");
+    }
+    let mut arg = String::new();
+    let mut depth = 0usize;
+    for token in tokens {
+        match token.kind {
+            T!['('] => depth += 1,
+            T![')'] if depth == 0 => return Ok(arg),
+            T![')'] => depth -= 1,
+            _ => {}
+        }
+        arg.push_str(&token.text);
+    }
+    bail!("Placeholder constraint is missing a closing `)`");
 }
 
 impl Placeholder {
-    fn new(name: SmolStr) -> Self {
-        Self { stand_in_name: format!("__placeholder_{}", name), ident: name }
+    fn new(name: SmolStr, constraints: Vec<Constraint>) -> Self {
+        let stand_in_name = stand_in_name(&name, &constraints);
+        Self {
+            stand_in_name,
+            ident: name,
+            multi: false,
+            constraints,
+            kind: None,
+            text_constraint: None,
+            transform: None,
+            anonymous: false,
+        }
+    }
+
+    fn add_constraint(&mut self, constraint: Constraint) {
+        self.constraints.push(constraint);
+        self.stand_in_name = stand_in_name(&self.ident, &self.constraints);
+    }
+
+    /// Returns whether `text` is one of the pieces of Rust code that we'd substitute for this
+    /// placeholder when parsing a pattern.
+    pub(crate) fn is_stand_in_text(&self, text: &str) -> bool {
+        MULTI_SHAPES.iter().any(|shape| shape.stand_in_text(&self.stand_in_name) == text)
+    }
+}
+
+impl TextConstraint {
+    fn new(source: String) -> Result<TextConstraint, SsrError> {
+        #[cfg(feature = "regex")]
+        let regex = regex::Regex::new(&source)
+            .map_err(|e| SsrError::invalid_rule(format!("Invalid regex `{}`: {}", source, e)))?;
+        #[cfg(not(feature = "regex"))]
+        {
+            // Anything else would be silently matched as plain text, so would never match.
+            let (_, text, _) = TextConstraint::parts(&source);
+            if text.contains(|c| "\\.+*?()|[]{}^$".contains(c)) {
+                bail!(
+                    "Text constraint `{}` uses regex syntax, which needs the `regex` feature",
+                    source
+                );
+            }
+        }
+        Ok(TextConstraint {
+            source,
+            #[cfg(feature = "regex")]
+            regex,
+        })
+    }
+
+    #[cfg(feature = "regex")]
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    #[cfg(not(feature = "regex"))]
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let (ignore_case, pattern, (at_start, at_end)) = TextConstraint::parts(&self.source);
+        let (pattern, text) = if ignore_case {
+            (pattern.to_lowercase(), text.to_lowercase())
+        } else {
+            (pattern.to_owned(), text.to_owned())
+        };
+        let (pattern, text) = (pattern.as_str(), text.as_str());
+        match (at_start, at_end) {
+            (true, true) => text == pattern,
+            (true, false) => text.starts_with(pattern),
+            (false, true) => text.ends_with(pattern),
+            (false, false) => text.contains(pattern),
+        }
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+impl TextConstraint {
+    /// Splits `source` into whether it starts with `(?i)`, the text to look for, and whether that
+    /// text is anchored to the start and end.
+    fn parts(source: &str) -> (bool, &str, (bool, bool)) {
+        let (ignore_case, pattern) = match source.strip_prefix("(?i)") {
+            Some(pattern) => (true, pattern),
+            None => (false, source),
+        };
+        let (pattern, at_start) = match pattern.strip_prefix('^') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, at_end) = match pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        (ignore_case, pattern, (at_start, at_end))
+    }
+}
+
+impl PartialEq for TextConstraint {
+    fn eq(&self, other: &TextConstraint) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for TextConstraint {}
+
+fn stand_in_name(name: &str, constraints: &[Constraint]) -> String {
+    // Lifetimes can't be parsed in the same places as identifiers, so lifetime placeholders need a
+    // stand-in that is itself a lifetime.
+    if constraints.contains(&Constraint::Lifetime) {
+        format!("'__placeholder_{}", name)
+    } else {
+        format!("__placeholder_{}", name)
+    }
+}
+
+/// A change applied to the text of a placeholder when it's substituted into a template. Written
+/// after the placeholder's name, e.g. `$a:pascal`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transform {
+    Upper,
+    Lower,
+    /// Joins `_` separated words, capitalizing the first letter of each. e.g. `foo_bar` becomes
+    /// `FooBar`.
+    Pascal,
+}
+
+impl Transform {
+    pub(crate) fn from_name(name: &str) -> Option<Transform> {
+        match name {
+            "upper" => Some(Transform::Upper),
+            "lower" => Some(Transform::Lower),
+            "pascal" => Some(Transform::Pascal),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn apply(self, text: &str) -> String {
+        match self {
+            Transform::Upper => text.to_uppercase(),
+            Transform::Lower => text.to_lowercase(),
+            Transform::Pascal => text
+                .split('_')
+                .flat_map(|word| {
+                    let mut chars = word.chars();
+                    chars.next().into_iter().flat_map(char::to_uppercase).chain(chars)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns the identifier that `code` consists of, if it's nothing but a single identifier.
+pub(crate) fn single_ident(code: &SyntaxNode) -> Option<SyntaxToken> {
+    let mut tokens = code
+        .descendants_with_tokens()
+        .filter_map(|e| e.into_token())
+        .filter(|t| !t.kind().is_trivia());
+    match (tokens.next(), tokens.next()) {
+        (Some(token), None) if token.kind() == SyntaxKind::IDENT => Some(token),
+        _ => None,
+    }
+}
+
+impl PlaceholderKind {
+    /// Returns whether `code` is of this kind.
+    pub(crate) fn matches(self, code: &SyntaxNode) -> bool {
+        match self {
+            PlaceholderKind::Expr => ast::Expr::can_cast(code.kind()),
+            PlaceholderKind::Ident => single_ident(code).is_some(),
+            PlaceholderKind::Path => matches!(
+                code.kind(),
+                SyntaxKind::PATH
+                    | SyntaxKind::PATH_EXPR
+                    | SyntaxKind::PATH_TYPE
+                    | SyntaxKind::PATH_PAT
+            ),
+            // A placeholder that's a generic argument, e.g. in `Vec<$t:ty>`, matches the whole
+            // argument rather than just the type within it.
+            PlaceholderKind::Ty => {
+                ast::TypeRef::can_cast(code.kind()) || code.kind() == SyntaxKind::TYPE_ARG
+            }
+            PlaceholderKind::Pat => ast::Pat::can_cast(code.kind()),
+            PlaceholderKind::Literal => code.kind() == SyntaxKind::LITERAL,
+            PlaceholderKind::Block => code.kind() == SyntaxKind::BLOCK_EXPR,
+        }
+    }
+}
+
+/// Returns the shapes that the multi-placeholder at `tokens[index]` might take, going by the
+/// delimiters it's within and the tokens around them. e.g. within braces after `match`, it must be
+/// match arms.
+fn multi_shapes(tokens: &[PatternElement], index: usize) -> &'static [MultiShape] {
+    let kind = |i: usize| match &tokens[i] {
+        PatternElement::Token(t) => Some(t.kind),
+        PatternElement::Placeholder(_) => None,
+    };
+    // Find the delimiter that opens the group we're in.
+    let mut depth = 0;
+    let mut open = None;
+    for i in (0..index).rev() {
+        match kind(i) {
+            Some(T![')']) | Some(T![']']) | Some(T!['}']) => depth += 1,
+            Some(T!['(']) | Some(T!['[']) | Some(T!['{']) if depth > 0 => depth -= 1,
+            Some(T!['(']) | Some(T!['[']) | Some(T!['{']) => {
+                open = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let open = match open {
+        Some(open) if kind(open) == Some(T!['{']) => open,
+        Some(open) if kind(open) == Some(T!['(']) => {
+            // The parameters of a function, as in `fn $n($p:*)`, are like fields. So are those of a
+            // generic function, but we don't try to work out where its generics start.
+            let mut before = (0..open).rev().filter(|&i| kind(i) != Some(SyntaxKind::WHITESPACE));
+            return match (before.next().map(kind), before.next().map(kind)) {
+                (Some(None), Some(Some(T![fn])))
+                | (Some(Some(SyntaxKind::IDENT)), Some(Some(T![fn]))) => &[MultiShape::Field],
+                (Some(Some(T![>])), _) => &[MultiShape::Name, MultiShape::Field],
+                _ => &[MultiShape::Name],
+            };
+        }
+        _ => return &[MultiShape::Name],
+    };
+    // The tokens within the braces, at the same level as the placeholder.
+    let mut depth = 0;
+    let mut has_colon = false;
+    for i in open + 1..tokens.len() {
+        match kind(i) {
+            Some(T!['(']) | Some(T!['[']) | Some(T!['{']) => depth += 1,
+            Some(T![')']) | Some(T![']']) | Some(T!['}']) if depth == 0 => break,
+            Some(T![')']) | Some(T![']']) | Some(T!['}']) => depth -= 1,
+            Some(T![=>]) if depth == 0 => return &[MultiShape::MatchArm],
+            Some(T![:]) if depth == 0 => has_colon = true,
+            _ => {}
+        }
+    }
+    // The keyword that introduced the braces, if any, e.g. `match $e {` or `struct $n {`.
+    let mut depth = 0;
+    for i in (0..open).rev() {
+        let text = match &tokens[i] {
+            PatternElement::Token(t) => t.text.as_str(),
+            PatternElement::Placeholder(_) => continue,
+        };
+        match kind(i) {
+            Some(T![')']) | Some(T![']']) | Some(T!['}']) => depth += 1,
+            Some(T!['(']) | Some(T!['[']) | Some(T!['{']) if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            Some(T![;]) | Some(T!['(']) | Some(T!['[']) | Some(T!['{']) | Some(T![,]) => break,
+            Some(T![match]) => return &[MultiShape::MatchArm],
+            Some(T![struct]) => return &[MultiShape::Field],
+            Some(SyntaxKind::IDENT) if text == "union" => return &[MultiShape::Field],
+            Some(T![enum]) | Some(T![=]) => break,
+            _ => {}
+        }
+    }
+    if has_colon {
+        // Either the fields of a struct definition or of a record literal.
+        &[MultiShape::Name, MultiShape::Field]
+    } else {
+        &[MultiShape::Name]
+    }
+}
+
+impl MultiShape {
+    fn stand_in_text(self, name: &str) -> String {
+        match self {
+            MultiShape::Name => name.to_string(),
+            MultiShape::Field => format!("{}: {}", name, name),
+            MultiShape::MatchArm => format!("{} => {{}}", name),
+        }
     }
 }
 
 impl SsrError {
-    fn new(message: impl Into<String>) -> SsrError {
-        SsrError(message.into())
+    /// An error that doesn't fit any of the more specific kinds.
+    pub(crate) fn new(message: impl Into<String>) -> SsrError {
+        SsrError { kind: SsrErrorKind::Other, message: message.into(), range: None }
+    }
+
+    /// A problem with the text of a rule that doesn't fit any of the more specific kinds.
+    pub(crate) fn invalid_rule(message: impl Into<String>) -> SsrError {
+        SsrError { kind: SsrErrorKind::InvalidRule, message: message.into(), range: None }
+    }
+
+    fn with_range(message: impl Into<String>, range: TextRange) -> SsrError {
+        SsrError { kind: SsrErrorKind::InvalidRule, message: message.into(), range: Some(range) }
+    }
+
+    /// Sets our range to `range`, unless we already have one.
+    fn or_range(mut self, range: TextRange) -> SsrError {
+        self.range = self.range.or(Some(range));
+        self
+    }
+
+    /// Moves our range, if any, by `offset`. For when the error came from parsing part of a rule.
+    fn offset_by(mut self, offset: TextSize) -> SsrError {
+        self.range = self.range.map(|range| range + offset);
+        self
     }
 }
 
@@ -241,7 +1538,7 @@ mod tests {
             PatternElement::Token(Token { kind, text: SmolStr::new(text) })
         }
         fn placeholder(name: &str) -> PatternElement {
-            PatternElement::Placeholder(Placeholder::new(SmolStr::new(name)))
+            PatternElement::Placeholder(Placeholder::new(SmolStr::new(name), Vec::new()))
         }
         let result: SsrRule = "foo($a, $b) ==>> bar($b, $a)".parse().unwrap();
         assert_eq!(
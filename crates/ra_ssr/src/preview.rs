@@ -0,0 +1,139 @@
+//! Produces a unified diff of the changes that replacing a set of matches would make, so that a
+//! replacement can be reviewed before it's applied.
+
+use crate::replacing::matches_to_edit;
+use crate::{ReplaceOptions, SsrError, SsrMatches};
+use ra_syntax::TextSize;
+use ra_text_edit::{Indel, TextEditBuilder};
+
+/// How many unchanged lines are shown before and after each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Returns a unified diff of the changes that replacing `matches` in `source` would make. Changes
+/// that are close together share a hunk, while those that are further apart get hunks of their
+/// own. Only the hunks are included, so callers that want `---` and `+++` headers need to add them.
+/// Returns an empty string if nothing would change.
+pub fn unified_diff(
+    source: &str,
+    matches: &SsrMatches,
+    options: &ReplaceOptions,
+) -> Result<String, SsrError> {
+    let edit = matches_to_edit(matches, options)?;
+    let mut lines: Vec<&str> = source.split_inclusive('\n').collect();
+    if lines.is_empty() {
+        lines.push("");
+    }
+    let mut line_starts = Vec::with_capacity(lines.len());
+    let mut offset = 0;
+    for line in &lines {
+        line_starts.push(offset);
+        offset += line.len();
+    }
+    let line_of = |offset: usize| match line_starts.binary_search(&offset) {
+        Ok(line) => line,
+        Err(next_line) => next_line - 1,
+    };
+
+    // Group the edits into changes, each of which replaces a run of whole lines. Edits that touch
+    // the same line end up in the same change.
+    let mut changes: Vec<Change> = Vec::new();
+    for indel in edit.iter() {
+        let start = usize::from(indel.delete.start());
+        let end = usize::from(indel.delete.end());
+        let first_line = line_of(start);
+        let last_line = if end > start { line_of(end - 1) } else { first_line };
+        match changes.last_mut() {
+            Some(change) if change.last_line >= first_line => {
+                change.last_line = change.last_line.max(last_line);
+                change.indels.push(indel);
+            }
+            _ => changes.push(Change { first_line, last_line, indels: vec![indel] }),
+        }
+    }
+    let new_texts: Vec<String> = changes
+        .iter()
+        .map(|change| {
+            let start = line_starts[change.first_line];
+            let end = line_starts[change.last_line] + lines[change.last_line].len();
+            let mut edit_builder = TextEditBuilder::default();
+            for indel in &change.indels {
+                edit_builder
+                    .replace(indel.delete - TextSize::from(start as u32), indel.insert.clone());
+            }
+            let mut new_text = source[start..end].to_owned();
+            edit_builder.finish().apply(&mut new_text);
+            new_text
+        })
+        .collect();
+
+    let mut out = String::new();
+    let mut line_delta: isize = 0;
+    let mut remaining = &changes[..];
+    while !remaining.is_empty() {
+        let hunk_len = 1 + remaining
+            .windows(2)
+            .take_while(|pair| pair[1].first_line - pair[0].last_line - 1 <= 2 * CONTEXT_LINES)
+            .count();
+        let (hunk, rest) = remaining.split_at(hunk_len);
+        let hunk_new_texts = &new_texts[changes.len() - remaining.len()..][..hunk_len];
+        remaining = rest;
+        let hunk_start = hunk[0].first_line.saturating_sub(CONTEXT_LINES);
+        let hunk_end = (hunk[hunk.len() - 1].last_line + 1 + CONTEXT_LINES).min(lines.len());
+        let mut body = String::new();
+        let mut new_len = 0;
+        let mut line = hunk_start;
+        for (change, new_text) in hunk.iter().zip(hunk_new_texts) {
+            for context in &lines[line..change.first_line] {
+                push_line(&mut body, ' ', context);
+            }
+            new_len += change.first_line - line;
+            for old in &lines[change.first_line..=change.last_line] {
+                push_line(&mut body, '-', old);
+            }
+            for new in new_text.split_inclusive('\n') {
+                push_line(&mut body, '+', new);
+                new_len += 1;
+            }
+            line = change.last_line + 1;
+        }
+        for context in &lines[line..hunk_end] {
+            push_line(&mut body, ' ', context);
+        }
+        new_len += hunk_end - line;
+        let old_len = hunk_end - hunk_start;
+        let new_start = (hunk_start as isize + line_delta) as usize;
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(hunk_start, old_len),
+            hunk_range(new_start, new_len)
+        ));
+        out.push_str(&body);
+        line_delta += new_len as isize - old_len as isize;
+    }
+    Ok(out)
+}
+
+/// A run of whole lines, `first_line..=last_line`, and the edits that change them.
+struct Change<'a> {
+    first_line: usize,
+    last_line: usize,
+    indels: Vec<&'a Indel>,
+}
+
+/// Formats the range of lines in a hunk header. Lines are numbered from 1, except that an empty
+/// range is given by the number of the line before it.
+fn hunk_range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
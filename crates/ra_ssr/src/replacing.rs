@@ -1,63 +1,490 @@
 //! Code for applying replacement templates for matches that have previously been found.
 
 use crate::matching::Var;
-use crate::parsing::PatternElement;
-use crate::{Match, SsrMatches};
-use ra_syntax::ast::AstToken;
-use ra_syntax::TextSize;
+use crate::parsing::{parse_fragment, PatternElement, Transform, QUALIFIERS_PLACEHOLDER};
+use crate::{Match, PatternKind, SsrError, SsrMatches};
+use ra_syntax::ast::{AstNode, AstToken};
+use ra_syntax::{SyntaxElement, SyntaxKind, SyntaxNode, TextRange, TextSize, T};
 use ra_text_edit::TextEdit;
 
+/// Options that control how replacement templates are rendered.
+#[derive(Debug, Clone)]
+pub struct ReplaceOptions {
+    /// How whitespace in the template that is next to a placeholder is emitted.
+    pub placeholder_whitespace: PlaceholderWhitespace,
+    /// If true, each edit only covers the part of the matched code that actually differs from its
+    /// replacement. e.g. `f($a) ==>> f($a, new_arg)` produces an insertion of `, new_arg` rather
+    /// than replacing the whole call.
+    pub minimal_diff: bool,
+    /// What happens to comments within the matched code that aren't part of what a placeholder
+    /// matched, and so aren't otherwise in the replacement.
+    pub comments: CommentHandling,
+}
+
+/// Controls what happens to comments in the matched code that the replacement doesn't include.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentHandling {
+    /// Each comment is added to the end of the replacement, in the order they appeared. This keeps
+    /// them, but moves them away from the code they were next to.
+    AppendAtEnd,
+    /// The comments are left out of the replacement.
+    Drop,
+}
+
+/// Controls the whitespace that surrounds placeholder substitutions in the rendered replacement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceholderWhitespace {
+    /// Whitespace next to a placeholder is emitted exactly as written in the template.
+    Preserve,
+    /// Whitespace next to a placeholder is collapsed to a single space.
+    Collapse,
+    /// Like `Collapse`, but additionally inserts a single space between a comma and a following
+    /// placeholder, so that `$a,$b` and `$a, $b` render identically.
+    Enforce,
+}
+
+impl Default for ReplaceOptions {
+    fn default() -> Self {
+        ReplaceOptions {
+            placeholder_whitespace: PlaceholderWhitespace::Preserve,
+            minimal_diff: false,
+            comments: CommentHandling::AppendAtEnd,
+        }
+    }
+}
+
 /// Returns a text edit that will replace each match in `matches` with its corresponding replacement
 /// template. Placeholders in the template will have been substituted with whatever they matched to
 /// in the original code.
-pub(crate) fn matches_to_edit(matches: &SsrMatches) -> TextEdit {
-    matches_to_edit_at_offset(matches, 0.into())
+pub(crate) fn matches_to_edit(
+    matches: &SsrMatches,
+    options: &ReplaceOptions,
+) -> Result<TextEdit, SsrError> {
+    matches_to_edit_with(matches, options, &mut |_, _| None)
 }
 
-fn matches_to_edit_at_offset(matches: &SsrMatches, relative_start: TextSize) -> TextEdit {
+/// Like `matches_to_edit`, but each placeholder in a template is replaced by whatever `substitute`
+/// returns for the placeholder's name and the match, if it returns anything. See
+/// `MatchFinder::edits_for_file_with`.
+pub(crate) fn matches_to_edit_with(
+    matches: &SsrMatches,
+    options: &ReplaceOptions,
+    substitute: &mut dyn FnMut(&str, &Match) -> Option<String>,
+) -> Result<TextEdit, SsrError> {
+    matches_to_edit_at_offset(matches, 0.into(), options, substitute)
+}
+
+fn matches_to_edit_at_offset(
+    matches: &SsrMatches,
+    relative_start: TextSize,
+    options: &ReplaceOptions,
+    substitute: &mut dyn FnMut(&str, &Match) -> Option<String>,
+) -> Result<TextEdit, SsrError> {
     let mut edit_builder = ra_text_edit::TextEditBuilder::default();
+    for m in without_overlaps(&matches.matches) {
+        let range = m.range.checked_sub(relative_start).ok_or_else(|| {
+            SsrError::new("Internal error: match started before the range being edited")
+        })?;
+        let replacement = render_replace_with(m, options, substitute)?;
+        let matched_text = m.matched_node.text().to_string();
+        // The matched node may have come from a macro expansion, in which case its text isn't
+        // what's in the file and we can't diff against it.
+        if options.minimal_diff && TextSize::of(matched_text.as_str()) == range.len() {
+            let (range, replacement) = minimal_edit(&matched_text, range, &replacement);
+            edit_builder.replace(range, replacement.to_owned());
+        } else {
+            edit_builder.replace(range, replacement);
+        }
+    }
+    Ok(edit_builder.finish())
+}
+
+/// Returns `matches`, in order, minus any that overlap a match from an earlier rule, or an earlier
+/// match from the same rule. Overlapping matches can't both be replaced.
+fn without_overlaps(matches: &[Match]) -> impl Iterator<Item = &Match> {
+    let mut by_priority: Vec<usize> = (0..matches.len()).collect();
+    by_priority.sort_by_key(|&i| matches[i].rule_index);
+    let mut kept: Vec<usize> = Vec::new();
+    for i in by_priority {
+        let range = matches[i].range;
+        if kept.iter().all(|&k| {
+            let other = matches[k].range;
+            range != other && (range.end() <= other.start() || other.end() <= range.start())
+        }) {
+            kept.push(i);
+        }
+    }
+    kept.sort_unstable();
+    kept.into_iter().map(move |i| &matches[i])
+}
+
+/// Renders the replacement for each of `matches` and checks that it parses as the same kind of
+/// thing as the code that it replaces. Returns the range and the error for each that doesn't.
+pub(crate) fn validate_replacements(
+    matches: &SsrMatches,
+    options: &ReplaceOptions,
+) -> Vec<(TextRange, SsrError)> {
+    let mut errors = Vec::new();
     for m in &matches.matches {
-        edit_builder.replace(m.range.checked_sub(relative_start).unwrap(), render_replace(m));
+        let kind = match PatternKind::for_syntax_kind(m.matched_node.kind()) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        match render_replace(m, options) {
+            Ok(replacement) => {
+                if parse_fragment(&replacement, kind).is_none() {
+                    errors.push((
+                        m.range,
+                        SsrError::new(format!(
+                            "Replacement `{}` is not a valid {:?}",
+                            replacement, kind
+                        )),
+                    ));
+                }
+            }
+            Err(error) => errors.push((m.range, error)),
+        }
+    }
+    errors
+}
+
+/// Shrinks an edit that replaces `original`, which is at `range`, with `replacement` so that it
+/// excludes any text at the start or end that the two have in common.
+fn minimal_edit<'a>(
+    original: &str,
+    range: TextRange,
+    replacement: &'a str,
+) -> (TextRange, &'a str) {
+    let prefix_len = common_prefix_len(original.chars(), replacement.chars());
+    let original_rest = &original[prefix_len..];
+    let replacement_rest = &replacement[prefix_len..];
+    let suffix_len = common_prefix_len(original_rest.chars().rev(), replacement_rest.chars().rev());
+    let start = range.start() + TextSize::of(&original[..prefix_len]);
+    let end = range.end() - TextSize::of(&original_rest[original_rest.len() - suffix_len..]);
+    (TextRange::new(start, end), &replacement_rest[..replacement_rest.len() - suffix_len])
+}
+
+/// Returns the length in bytes of the run of identical chars at the start of `a` and `b`.
+fn common_prefix_len(a: impl Iterator<Item = char>, b: impl Iterator<Item = char>) -> usize {
+    let mut len = 0;
+    for (a, b) in a.zip(b) {
+        if a != b {
+            break;
+        }
+        len += a.len_utf8();
     }
-    edit_builder.finish()
+    len
 }
 
-fn render_replace(match_info: &Match) -> String {
+pub(crate) fn render_replace(
+    match_info: &Match,
+    options: &ReplaceOptions,
+) -> Result<String, SsrError> {
+    render_replace_with(match_info, options, &mut |_, _| None)
+}
+
+fn render_replace_with(
+    match_info: &Match,
+    options: &ReplaceOptions,
+    substitute: &mut dyn FnMut(&str, &Match) -> Option<String>,
+) -> Result<String, SsrError> {
     let mut out = String::new();
     let match_start = match_info.matched_node.text_range().start();
-    for r in &match_info.template.tokens {
+    // Newlines in the template are followed by the indentation of the line that the match starts
+    // on. We can only work out indentation if the matched node came from the file, rather than from
+    // a macro expansion.
+    let file_root = if match_info.matched_node.text_range() == match_info.range {
+        match_info.matched_node.ancestors().last()
+    } else {
+        None
+    };
+    let indent = file_root
+        .as_ref()
+        .map_or_else(String::new, |root| line_indent_at(root, match_info.range.start()));
+    let indent = indent.as_str();
+    let tokens = &match_info.template.tokens;
+    for attr in &match_info.implicit_attributes {
+        out.push_str(&attr.syntax().to_string());
+        // Keep whatever separated the attribute from what followed it, e.g. `#[test] fn a()` stays
+        // on one line.
+        match attr.syntax().next_sibling_or_token() {
+            Some(SyntaxElement::Token(t)) if t.kind() == SyntaxKind::WHITESPACE => {
+                out.push_str(t.text())
+            }
+            _ => {
+                out.push('\n');
+                out.push_str(indent);
+            }
+        }
+    }
+    // If the template says where qualifiers go with `$qualifiers`, we put them there instead.
+    let qualifiers_var = Var(QUALIFIERS_PLACEHOLDER.to_owned());
+    let has_qualifiers_placeholder = !match_info.placeholder_values.contains_key(&qualifiers_var)
+        && tokens.iter().any(
+            |t| matches!(t, PatternElement::Placeholder(p) if p.ident == QUALIFIERS_PLACEHOLDER),
+        );
+    let mut implicit_qualifiers = match_info.implicit_qualifiers.as_slice();
+    if has_qualifiers_placeholder {
+        implicit_qualifiers = &[];
+    }
+    let mut skip_whitespace = false;
+    let mut skip_to = 0;
+    for (index, r) in tokens.iter().enumerate() {
+        if index < skip_to {
+            continue;
+        }
         match r {
-            PatternElement::Token(t) => out.push_str(t.text.as_str()),
+            PatternElement::Token(t) => {
+                // When `$qualifiers` is empty, we drop the whitespace that separated it from `fn`.
+                if std::mem::replace(&mut skip_whitespace, false)
+                    && t.kind == SyntaxKind::WHITESPACE
+                {
+                    continue;
+                }
+                // Qualifiers that the pattern didn't mention go before the first qualifier or `fn`
+                // keyword in the template.
+                if !implicit_qualifiers.is_empty() && FN_START_TOKENS.contains(&t.kind) {
+                    for qualifier in implicit_qualifiers {
+                        out.push_str(qualifier.text());
+                        out.push(' ');
+                    }
+                    implicit_qualifiers = &[];
+                }
+                if t.kind == SyntaxKind::WHITESPACE && is_next_to_placeholder(tokens, index) {
+                    let whitespace = options.placeholder_whitespace.render(t.text.as_str());
+                    out.push_str(&whitespace.replace('\n', &format!("\n{}", indent)));
+                } else if t.kind == SyntaxKind::WHITESPACE {
+                    out.push_str(&t.text.replace('\n', &format!("\n{}", indent)));
+                } else {
+                    out.push_str(t.text.as_str());
+                    if t.kind == SyntaxKind::COMMA
+                        && options.placeholder_whitespace == PlaceholderWhitespace::Enforce
+                        && matches!(tokens.get(index + 1), Some(PatternElement::Placeholder(_)))
+                    {
+                        out.push(' ');
+                    }
+                }
+            }
+            PatternElement::Placeholder(p)
+                if has_qualifiers_placeholder && p.ident == QUALIFIERS_PLACEHOLDER =>
+            {
+                let qualifiers: Vec<&str> =
+                    match_info.implicit_qualifiers.iter().map(|q| q.text().as_str()).collect();
+                skip_whitespace = qualifiers.is_empty();
+                out.push_str(&qualifiers.join(" "));
+            }
             PatternElement::Placeholder(p) => {
                 if let Some(placeholder_value) =
                     match_info.placeholder_values.get(&Var(p.ident.to_string()))
                 {
+                    if let Some(text) = substitute(&p.ident, match_info) {
+                        out.push_str(&text);
+                        continue;
+                    }
                     let range = &placeholder_value.range.range;
-                    let mut matched_text = if let Some(node) = &placeholder_value.node {
+                    let mut matched_text = if let Some(text) = &placeholder_value.inferred_text {
+                        text.clone()
+                    } else if let Some(node) = &placeholder_value.node {
                         node.text().to_string()
                     } else {
-                        let relative_range = range.checked_sub(match_start).unwrap();
-                        match_info.matched_node.text().to_string()
-                            [usize::from(relative_range.start())..usize::from(relative_range.end())]
+                        let relative_range = range.checked_sub(match_start).ok_or_else(|| {
+                            SsrError::new("Internal error: placeholder started before its match")
+                        })?;
+                        match_info
+                            .matched_node
+                            .text()
+                            .to_string()
+                            .get(
+                                usize::from(relative_range.start())
+                                    ..usize::from(relative_range.end()),
+                            )
+                            .ok_or_else(|| {
+                                SsrError::new(
+                                    "Internal error: placeholder extends outside its match",
+                                )
+                            })?
                             .to_string()
                     };
-                    let edit =
-                        matches_to_edit_at_offset(&placeholder_value.inner_matches, range.start());
+                    let edit = matches_to_edit_at_offset(
+                        &placeholder_value.inner_matches,
+                        range.start(),
+                        options,
+                        substitute,
+                    )?;
                     edit.apply(&mut matched_text);
+                    // Keep the indentation of each line of the value relative to its first line.
+                    if let Some(root) = &file_root {
+                        let original_indent = line_indent_at(root, range.start());
+                        let current_indent = match out.rfind('\n') {
+                            Some(newline) => line_indent(&out, TextSize::of(&out[..=newline])),
+                            None => indent,
+                        };
+                        matched_text =
+                            reindent_lines(&matched_text, &original_indent, current_indent);
+                    }
+                    if let Some(transform) = p.transform.as_deref().and_then(Transform::from_name) {
+                        matched_text = transform.apply(&matched_text);
+                    }
+                    // e.g. for `foo($a) ==>> $a.bar()`, `foo(x + y)` becomes `(x + y).bar()`.
+                    let needs_parens = matches!(&placeholder_value.node,
+                        Some(node) if binds_looser_than_postfix(node.kind()))
+                        && is_followed_by_postfix_operator(tokens, index);
+                    if needs_parens {
+                        matched_text = format!("({})", matched_text);
+                    }
+                    // An empty multi-placeholder takes one of the commas around it with it, e.g. so
+                    // that `#[derive($d:*, Clone)]` renders as `#[derive(Clone)]`.
+                    if p.multi && matched_text.trim().is_empty() {
+                        skip_to = skip_separator(tokens, index + 1, &mut out);
+                    }
                     out.push_str(&matched_text);
                 } else {
                     // We validated that all placeholder references were valid before we
                     // started, so this shouldn't happen.
-                    panic!(
+                    return Err(SsrError::new(format!(
                         "Internal error: replacement referenced unknown placeholder {}",
                         p.ident
-                    );
+                    )));
                 }
             }
         }
     }
-    for comment in &match_info.ignored_comments {
-        out.push_str(&comment.syntax().to_string());
+    if options.comments == CommentHandling::AppendAtEnd {
+        for comment in &match_info.ignored_comments {
+            out.push_str(&comment.syntax().to_string());
+        }
+    }
+    Ok(out)
+}
+
+/// Called for an empty multi-placeholder followed by `tokens[start..]`. If a comma follows it, returns
+/// the index of the token after that comma and any whitespace after it, so that they're skipped.
+/// Otherwise, removes a comma that preceded the placeholder from the end of `out`.
+fn skip_separator(tokens: &[PatternElement], start: usize, out: &mut String) -> usize {
+    let is_token = |index: usize, kind: SyntaxKind| matches!(tokens.get(index), Some(PatternElement::Token(t)) if t.kind == kind);
+    let mut index = start;
+    while is_token(index, SyntaxKind::WHITESPACE) {
+        index += 1;
+    }
+    if is_token(index, T![,]) {
+        index += 1;
+        while is_token(index, SyntaxKind::WHITESPACE) {
+            index += 1;
+        }
+        return index;
+    }
+    let trimmed = out.trim_end();
+    if trimmed.ends_with(',') {
+        out.truncate(trimmed.len() - 1);
+    }
+    start
+}
+
+/// Returns the whitespace at the start of the line of `text` that contains `offset`.
+fn line_indent(text: &str, offset: TextSize) -> &str {
+    let offset = usize::from(offset).min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Like `line_indent`, but for the text of the tree `root`. Rather than getting the text of the
+/// whole tree, we walk back from `offset` through the tokens before it to the start of the line,
+/// so that the cost doesn't depend on the size of the file.
+fn line_indent_at(root: &SyntaxNode, offset: TextSize) -> String {
+    if !root.text_range().contains_inclusive(offset) {
+        return String::new();
+    }
+    // The text of the line up to `offset`, one piece per token, last token first.
+    let mut pieces: Vec<String> = Vec::new();
+    let mut token = root.token_at_offset(offset).right_biased();
+    let mut end = offset;
+    while let Some(current) = token {
+        let start = current.text_range().start();
+        let text =
+            &current.text().as_str()[..usize::from(end.min(current.text_range().end()) - start)];
+        if let Some(newline) = text.rfind('\n') {
+            pieces.push(text[newline + 1..].to_owned());
+            break;
+        }
+        pieces.push(text.to_owned());
+        token = current.prev_token();
+        end = token.as_ref().map_or(start, |prev| prev.text_range().end());
+    }
+    let line: String = pieces.into_iter().rev().collect();
+    line_indent(&line, TextSize::of(&line)).to_owned()
+}
+
+/// Replaces `original_indent` at the start of each line of `text` after the first with
+/// `new_indent`. Lines that don't start with `original_indent` are left alone.
+fn reindent_lines(text: &str, original_indent: &str, new_indent: &str) -> String {
+    if original_indent == new_indent {
+        return text.to_owned();
+    }
+    let mut lines = text.split('\n');
+    let mut out = lines.next().unwrap_or_default().to_owned();
+    for line in lines {
+        out.push('\n');
+        match line.strip_prefix(original_indent) {
+            Some(rest) if !line.is_empty() => {
+                out.push_str(new_indent);
+                out.push_str(rest);
+            }
+            _ => out.push_str(line),
+        }
     }
     out
 }
+
+/// The tokens that can start a function definition after any visibility or attributes.
+const FN_START_TOKENS: &[SyntaxKind] = &[
+    SyntaxKind::CONST_KW,
+    SyntaxKind::ASYNC_KW,
+    SyntaxKind::UNSAFE_KW,
+    SyntaxKind::EXTERN_KW,
+    SyntaxKind::FN_KW,
+];
+
+/// Returns whether the template element at `index` has a placeholder immediately before or after it.
+fn is_next_to_placeholder(tokens: &[PatternElement], index: usize) -> bool {
+    let is_placeholder = |i: Option<usize>| {
+        matches!(i.and_then(|i| tokens.get(i)), Some(PatternElement::Placeholder(_)))
+    };
+    is_placeholder(index.checked_sub(1)) || is_placeholder(Some(index + 1))
+}
+
+/// Returns whether the token after the placeholder at `index`, ignoring whitespace, is one that
+/// applies to the expression before it, as in a method call, field access, index or `?`.
+fn is_followed_by_postfix_operator(tokens: &[PatternElement], index: usize) -> bool {
+    let next = tokens[index + 1..].iter().find(|t| match t {
+        PatternElement::Token(t) => t.kind != SyntaxKind::WHITESPACE,
+        PatternElement::Placeholder(_) => true,
+    });
+    matches!(next, Some(PatternElement::Token(t)) if matches!(t.kind, T![.] | T![?] | T!['[']))
+}
+
+/// Returns whether an expression of kind `kind` would need parentheses for a postfix operator, such
+/// as a method call, to apply to all of it, e.g. `x + y` or `&x`, but not `x.y()` or `(x + y)`.
+fn binds_looser_than_postfix(kind: SyntaxKind) -> bool {
+    matches!(
+        kind,
+        SyntaxKind::BIN_EXPR
+            | SyntaxKind::RANGE_EXPR
+            | SyntaxKind::PREFIX_EXPR
+            | SyntaxKind::REF_EXPR
+            | SyntaxKind::BOX_EXPR
+            | SyntaxKind::CAST_EXPR
+            | SyntaxKind::LAMBDA_EXPR
+            | SyntaxKind::RETURN_EXPR
+            | SyntaxKind::BREAK_EXPR
+    )
+}
+
+impl PlaceholderWhitespace {
+    fn render(self, whitespace: &str) -> &str {
+        match self {
+            PlaceholderWhitespace::Preserve => whitespace,
+            PlaceholderWhitespace::Collapse | PlaceholderWhitespace::Enforce => " ",
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Generates a candidate search pattern from a piece of existing code. This is intended as an aid
+//! when writing rules: given a node the user selected, we produce a pattern that matches it, with
+//! placeholders in place of the leaf values such as local variables and literals.
+
+use ra_syntax::{ast, AstNode, SyntaxKind, SyntaxNode, SyntaxToken};
+
+/// Returns a search pattern that matches `node`. For example, given `foo(x, 1)`, returns
+/// `foo($a, $b)`. The called function isn't replaced with a placeholder, since it's usually the
+/// thing that the user wants to search for.
+pub fn suggest_pattern(node: &SyntaxNode) -> String {
+    let mut out = String::new();
+    let mut num_placeholders = 0;
+    for token in node.descendants_with_tokens().filter_map(|e| e.into_token()) {
+        if is_leaf_value(&token) {
+            out.push('$');
+            out.push_str(&placeholder_name(num_placeholders));
+            num_placeholders += 1;
+        } else {
+            out.push_str(token.text().as_str());
+        }
+    }
+    out
+}
+
+/// Returns whether `token` is a literal or a reference to a local variable. Neither of these are
+/// likely to be important to the user, so we replace them with placeholders.
+fn is_leaf_value(token: &SyntaxToken) -> bool {
+    let parent = token.parent();
+    if parent.kind() == SyntaxKind::LITERAL {
+        return true;
+    }
+    if token.kind() != SyntaxKind::IDENT || parent.kind() != SyntaxKind::NAME_REF {
+        return false;
+    }
+    // Only single-segment paths, e.g. `x`, are considered to be local variables.
+    let path = match parent.parent().and_then(|segment| segment.parent()).and_then(ast::Path::cast)
+    {
+        Some(path) if path.qualifier().is_none() => path,
+        _ => return false,
+    };
+    let path_expr = match path.syntax().parent().and_then(ast::PathExpr::cast) {
+        Some(path_expr) => path_expr,
+        None => return false,
+    };
+    let callee = path_expr.syntax().parent().and_then(ast::CallExpr::cast).and_then(|c| c.expr());
+    callee.map(|callee| callee.syntax().clone()) != Some(path_expr.syntax().clone())
+}
+
+/// Returns a placeholder name for the placeholder at `index`. The first 26 are single letters.
+fn placeholder_name(index: usize) -> String {
+    let letter = (b'a' + (index % 26) as u8) as char;
+    if index < 26 {
+        letter.to_string()
+    } else {
+        format!("{}{}", letter, index / 26)
+    }
+}
@@ -1,9 +1,14 @@
 use crate::matching::MatchFailureReason;
-use crate::{matching, Match, MatchFinder, SsrMatches, SsrPattern, SsrRule};
+use crate::{
+    matching, CommentHandling, Match, MatchFinder, MatchOptions, PatternKind, PatternOptions,
+    PlaceholderConstraint, PlaceholderWhitespace, ReplaceOptions, SsrError, SsrErrorKind,
+    SsrMatches, SsrPattern, SsrRule, SsrRuleBuilder, SsrRules, SsrWarning,
+};
 use matching::record_match_fails_reasons_scope;
 use ra_db::{FileId, FileRange, SourceDatabaseExt};
 use ra_syntax::ast::AstNode;
-use ra_syntax::{ast, SyntaxKind, SyntaxNode, TextRange};
+use ra_syntax::{ast, SmolStr, SyntaxKind, SyntaxNode, TextRange, TextSize};
+use ra_text_edit::TextEdit;
 
 struct MatchDebugInfo {
     node: SyntaxNode,
@@ -48,35 +53,15 @@ impl std::fmt::Debug for MatchDebugInfo {
     }
 }
 
-impl SsrMatches {
-    /// Returns `self` with any nested matches removed and made into top-level matches.
-    pub(crate) fn flattened(self) -> SsrMatches {
-        let mut out = SsrMatches::default();
-        self.flatten_into(&mut out);
-        out
-    }
-
-    fn flatten_into(self, out: &mut SsrMatches) {
-        for mut m in self.matches {
-            for p in m.placeholder_values.values_mut() {
-                std::mem::replace(&mut p.inner_matches, SsrMatches::default()).flatten_into(out);
-            }
-            out.matches.push(m);
-        }
-    }
-}
-
-impl Match {
-    pub(crate) fn matched_text(&self) -> String {
-        self.matched_node.text().to_string()
-    }
-}
-
 impl<'db> MatchFinder<'db> {
     /// Adds a search pattern. For use if you intend to only call `find_matches_in_file`. If you
     /// intend to do replacement, use `add_rule` instead.
     fn add_search_pattern(&mut self, pattern: SsrPattern) {
-        self.add_rule(SsrRule { pattern, template: "()".parse().unwrap() })
+        self.add_rule(SsrRule {
+            pattern,
+            alternatives: Vec::new(),
+            template: "()".parse().unwrap(),
+        })
     }
 
     /// Finds all nodes in `file_id` whose text is exactly equal to `snippet` and attempts to match
@@ -120,12 +105,19 @@ impl<'db> MatchFinder<'db> {
                     let pattern =
                         rule.pattern.tree_for_kind_with_reason(node.kind()).map(|p| p.clone());
                     out.push(MatchDebugInfo {
-                        matched: matching::get_match(true, rule, &node, restrict_range, &self.sema)
-                            .map_err(|e| MatchFailureReason {
-                                reason: e.reason.unwrap_or_else(|| {
-                                    "Match failed, but no reason was given".to_owned()
-                                }),
+                        matched: matching::get_match(
+                            true,
+                            rule,
+                            &node,
+                            restrict_range,
+                            &self.sema,
+                            &self.match_options,
+                        )
+                        .map_err(|e| MatchFailureReason {
+                            reason: e.reason.unwrap_or_else(|| {
+                                "Match failed, but no reason was given".to_owned()
                             }),
+                        }),
                         pattern,
                         node: node.clone(),
                     });
@@ -160,6 +152,23 @@ fn parser_no_delimiter() {
     assert_eq!(parse_error_text("foo()"), "Parse error: Cannot find delemiter `==>>`");
 }
 
+#[test]
+fn parser_delimiter_without_whitespace() {
+    assert_eq!(
+        parse_error_text("foo()==>>bar()"),
+        "Parse error: The delimiter `==>>` must have whitespace on either side"
+    );
+}
+
+#[test]
+fn replace_pattern_containing_delimiter_text() {
+    assert_ssr_transform(
+        "m!(x==>>$a) ==>> m!($a==>>x)",
+        "fn f() {m!(x==>>1)}",
+        "fn f() {m!(1==>>x)}",
+    );
+}
+
 #[test]
 fn parser_two_delimiters() {
     assert_eq!(
@@ -171,11 +180,23 @@ fn parser_two_delimiters() {
 #[test]
 fn parser_repeated_name() {
     assert_eq!(
-        parse_error_text("foo($a, $a) ==>>"),
+        parse_error_text("foo($a) ==>> bar($a, $a)"),
         "Parse error: Name `a` repeats more than once"
     );
 }
 
+#[test]
+fn parser_escaped_dollar() {
+    // `$$` is a literal `$`, so `x` is an ordinary token rather than a placeholder name.
+    assert!("write!($$x) ==>> write!($$y)".parse::<SsrRule>().is_ok());
+    assert!("write!($$) ==>> write!($$)".parse::<SsrRule>().is_ok());
+}
+
+#[test]
+fn replace_with_escaped_dollar() {
+    assert_ssr_transform("foo($a) ==>> bar!($$x, $a)", "fn f() {foo(1)}", "fn f() {bar!($x, 1)}");
+}
+
 #[test]
 fn parser_invalid_pattern() {
     assert_eq!(
@@ -200,6 +221,87 @@ fn parser_undefined_placeholder_in_replacement() {
     );
 }
 
+fn parse_error_range(query: &str) -> Option<TextRange> {
+    query.parse::<SsrRule>().unwrap_err().range()
+}
+
+#[test]
+fn parser_error_ranges() {
+    assert_eq!(
+        parse_error_range("foo($a) ==>> bar($a, $a)"),
+        Some(TextRange::new(21.into(), 23.into()))
+    );
+    assert_eq!(parse_error_range("foo($1) ==>> ()"), Some(TextRange::new(5.into(), 6.into())));
+    assert_eq!(
+        parse_error_range("foo($a) | bar($1) ==>> ()"),
+        Some(TextRange::new(15.into(), 16.into()))
+    );
+    assert_eq!(parse_error_range("foo()"), None);
+}
+
+#[test]
+fn parser_unbalanced_delimiters() {
+    assert_eq!(
+        parse_error_text("foo($a ==>> bar($a)"),
+        "Parse error: Unbalanced `(` in search pattern"
+    );
+    assert_eq!(parse_error_range("foo($a ==>> bar($a)"), Some(TextRange::new(3.into(), 4.into())));
+    assert_eq!(
+        parse_error_text("foo($a]) ==>> ()"),
+        "Parse error: Unbalanced `]` in search pattern"
+    );
+}
+
+#[test]
+fn parser_error_variants() {
+    fn parse_error(query: &str) -> SsrError {
+        query.parse::<SsrRule>().unwrap_err()
+    }
+    let kind = |query: &str| parse_error(query).kind();
+    assert_eq!(kind("foo()"), SsrErrorKind::MissingDelimiter);
+    assert_eq!(kind("foo()==>>bar()"), SsrErrorKind::MissingDelimiter);
+    assert_eq!(kind("a ==>> b ==>> c"), SsrErrorKind::MultipleDelimiters);
+    assert_eq!(kind("\"a ==>> b"), SsrErrorKind::TokenizeFailed);
+    assert_eq!(kind("foo( ==>> bar()"), SsrErrorKind::InvalidPattern);
+    assert_eq!(kind("foo() ==>> )"), SsrErrorKind::InvalidPattern);
+    assert_eq!(kind("foo($a) ==>> bar($a, $a)"), SsrErrorKind::DuplicatePlaceholder);
+    assert_eq!(kind("foo($a) ==>> bar($b)"), SsrErrorKind::UndefinedPlaceholder);
+    assert_eq!(kind("foo($a:bar) ==>> ()"), SsrErrorKind::InvalidRule);
+    let error = parse_error("foo($a) ==>> bar($b)");
+    assert_eq!(error.message(), "Replacement contains undefined placeholders: $b");
+    assert_eq!(error.to_string(), "Parse error: Replacement contains undefined placeholders: $b");
+}
+
+#[test]
+fn lint_suspicious_rules() {
+    fn lint(query: &str) -> Vec<SsrWarning> {
+        query.parse::<SsrRule>().unwrap().lint()
+    }
+    let a = || SmolStr::new("a");
+    assert_eq!(lint("foo($a) ==>> bar($a)"), vec![]);
+    assert_eq!(lint("foo($a,  $b) ==>> foo($a, $b)"), vec![SsrWarning::NoOp]);
+    assert_eq!(lint("foo($a) | bar($a) ==>> foo($a)"), vec![]);
+    assert_eq!(lint("foo($a) ==>> foo($a:upper)"), vec![]);
+    assert_eq!(
+        lint("foo(${a:is_option:is_result}) ==>> bar($a)"),
+        vec![SsrWarning::ImpossibleConstraints { placeholder: a() }]
+    );
+    assert_eq!(
+        lint("let $x: $a:ty = $e; ==>> let $x = $a + 1;"),
+        vec![SsrWarning::IncompatiblePlaceholder { placeholder: a() }]
+    );
+    assert_eq!(lint("let $x: $a:ty = $e; ==>> let $x = $a::new();"), vec![]);
+    assert_eq!(
+        lint("break ${a:lifetime} $v ==>> foo($a)"),
+        vec![SsrWarning::IncompatiblePlaceholder { placeholder: a() }]
+    );
+    assert_eq!(lint("foo($a:expr) ==>> $a"), vec![]);
+    assert_eq!(
+        SsrWarning::NoOp.to_string(),
+        "Replacement is the same as the search pattern, so nothing will change"
+    );
+}
+
 fn single_file(code: &str) -> (ra_ide_db::RootDatabase, FileId) {
     use ra_db::fixture::WithFixture;
     ra_ide_db::RootDatabase::with_single_file(code)
@@ -210,13 +312,23 @@ fn assert_ssr_transform(rule: &str, input: &str, result: &str) {
 }
 
 fn assert_ssr_transforms(rules: &[&str], input: &str, result: &str) {
+    assert_ssr_transforms_with_options(rules, ReplaceOptions::default(), input, result);
+}
+
+fn assert_ssr_transforms_with_options(
+    rules: &[&str],
+    options: ReplaceOptions,
+    input: &str,
+    result: &str,
+) {
     let (db, file_id) = single_file(input);
     let mut match_finder = MatchFinder::new(&db);
+    match_finder.set_replace_options(options);
     for rule in rules {
         let rule: SsrRule = rule.parse().unwrap();
         match_finder.add_rule(rule);
     }
-    if let Some(edits) = match_finder.edits_for_file(file_id) {
+    if let Some(edits) = match_finder.edits_for_file(file_id).unwrap() {
         let mut after = input.to_string();
         edits.apply(&mut after);
         assert_eq!(after, result);
@@ -226,8 +338,18 @@ fn assert_ssr_transforms(rules: &[&str], input: &str, result: &str) {
 }
 
 fn assert_matches(pattern: &str, code: &str, expected: &[&str]) {
+    assert_matches_with_options(pattern, MatchOptions::default(), code, expected);
+}
+
+fn assert_matches_with_options(
+    pattern: &str,
+    options: MatchOptions,
+    code: &str,
+    expected: &[&str],
+) {
     let (db, file_id) = single_file(code);
     let mut match_finder = MatchFinder::new(&db);
+    match_finder.set_match_options(options);
     match_finder.add_search_pattern(pattern.parse().unwrap());
     let matched_strings: Vec<String> = match_finder
         .find_matches_in_file(file_id)
@@ -304,6 +426,24 @@ fn ssr_keeps_comment() {
     )
 }
 
+#[test]
+fn ssr_drops_comments() {
+    let options = ReplaceOptions { comments: CommentHandling::Drop, ..ReplaceOptions::default() };
+    assert_ssr_transforms_with_options(
+        &["foo($x) ==>> bar($x)"],
+        options.clone(),
+        "fn main() { foo(5 /* using 5 */) }",
+        "fn main() { bar(5) }",
+    );
+    // Comments within what a placeholder matched are part of the placeholder's value, so are kept.
+    assert_ssr_transforms_with_options(
+        &["foo($x) ==>> bar($x)"],
+        options,
+        "fn main() { foo(other(5 /* using 5 */)) }",
+        "fn main() { bar(other(5 /* using 5 */)) }",
+    );
+}
+
 #[test]
 fn ssr_struct_lit() {
     assert_ssr_transform(
@@ -488,6 +628,20 @@ fn replace_nested_function_calls() {
     );
 }
 
+#[test]
+fn replace_match_nested_in_placeholder_once() {
+    assert_ssr_transform(
+        "foo(foo($a)) ==>> bar($a)",
+        "fn f1() {foo(foo(foo(foo(42))))}",
+        "fn f1() {bar(bar(42))}",
+    );
+    assert_ssr_transform(
+        "foo(foo($a)) ==>> bar($a)",
+        "macro_rules! m { ($e:expr) => { $e } } fn f1() {m!(foo(foo(foo(foo(42)))))}",
+        "macro_rules! m { ($e:expr) => { $e } } fn f1() {m!(bar(bar(42)))}",
+    );
+}
+
 #[test]
 fn replace_type() {
     assert_ssr_transform(
@@ -547,3 +701,2004 @@ fn multiple_rules() {
         "fn f() -> i32 {add_one(add(3, 2))}",
     )
 }
+
+#[test]
+fn placeholder_whitespace_preserve() {
+    let options = ReplaceOptions {
+        placeholder_whitespace: PlaceholderWhitespace::Preserve,
+        ..ReplaceOptions::default()
+    };
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,$b)"],
+        options.clone(),
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1,2)}",
+    );
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,   $b)"],
+        options,
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1,   2)}",
+    );
+}
+
+#[test]
+fn placeholder_whitespace_collapse() {
+    let options = ReplaceOptions {
+        placeholder_whitespace: PlaceholderWhitespace::Collapse,
+        ..ReplaceOptions::default()
+    };
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,   $b)"],
+        options.clone(),
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1, 2)}",
+    );
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,$b)"],
+        options,
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1,2)}",
+    );
+}
+
+#[test]
+fn placeholder_whitespace_enforce() {
+    let options = ReplaceOptions {
+        placeholder_whitespace: PlaceholderWhitespace::Enforce,
+        ..ReplaceOptions::default()
+    };
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,$b)"],
+        options.clone(),
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1, 2)}",
+    );
+    assert_ssr_transforms_with_options(
+        &["foo($a, $b) ==>> bar($a,   $b)"],
+        options,
+        "fn f() {foo(1, 2)}",
+        "fn f() {bar(1, 2)}",
+    );
+}
+
+#[test]
+fn replace_method_chain_binding_each_level() {
+    assert_ssr_transform(
+        "$e.map($f).unwrap_or($d) ==>> $e.map_or($d, $f)",
+        "fn f() {let v = opt.as_ref().map(|x| x.len()).unwrap_or(0);}",
+        "fn f() {let v = opt.as_ref().map_or(0, |x| x.len());}",
+    );
+    assert_matches(
+        "$e.map($f).unwrap_or($d)",
+        "fn f() {a.map(g).unwrap_or(0); b.unwrap_or(0); c.map(g).unwrap_or_default();}",
+        &["a.map(g).unwrap_or(0)"],
+    );
+}
+
+#[test]
+fn matches_record_originating_rule() {
+    fn matched_rules(rules: &[&str], code: &str) -> Vec<(String, usize)> {
+        let (db, file_id) = single_file(code);
+        let mut match_finder = MatchFinder::new(&db);
+        for rule in rules {
+            match_finder.add_rule(rule.parse().unwrap());
+        }
+        let matches = match_finder.find_matches_in_file(file_id).flattened();
+        matches.matches.iter().map(|m| (m.matched_text(), m.rule_index)).collect()
+    }
+    let code = "fn f() {foo(1); bar(2);}";
+    assert_eq!(
+        matched_rules(&["foo($a) ==>> x($a)", "bar($a) ==>> y($a)"], code),
+        vec![("foo(1)".to_owned(), 0), ("bar(2)".to_owned(), 1)]
+    );
+    assert_eq!(matched_rules(&["bar($a) ==>> y($a)"], code), vec![("bar(2)".to_owned(), 0)]);
+}
+
+#[test]
+fn match_labelled_break_and_continue() {
+    let code = r#"
+        fn f() {
+            'outer: loop {
+                loop {
+                    break 'outer 42;
+                    break 'inner 42;
+                    break 'outer;
+                    break 42;
+                    break;
+                    continue 'outer;
+                    continue;
+                }
+            }
+        }"#;
+    assert_matches("break 'outer $v", code, &["break 'outer 42"]);
+    assert_matches("break $v", code, &["break 42"]);
+    assert_matches("continue 'outer", code, &["continue 'outer"]);
+    assert_matches("continue", code, &["continue"]);
+    assert_ssr_transform(
+        "break 'outer $v ==>> break 'outer Some($v)",
+        "fn f() {'outer: loop {break 'outer 42; break;}}",
+        "fn f() {'outer: loop {break 'outer Some(42); break;}}",
+    );
+}
+
+#[test]
+fn match_restricted_to_pattern_kind() {
+    let code = "use foo::bar; fn f() {foo::bar(1);}";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_search_pattern("foo::bar".parse().unwrap());
+    let matched_ranges = |matches: SsrMatches| -> Vec<TextRange> {
+        matches.flattened().matches.iter().map(|m| m.range).collect()
+    };
+    let use_path = TextRange::new(4.into(), 12.into());
+    let call_path = TextRange::new(22.into(), 30.into());
+    assert_eq!(
+        matched_ranges(match_finder.find_matches_in_file(file_id)),
+        vec![use_path, call_path]
+    );
+    assert_eq!(
+        matched_ranges(match_finder.find_matches_of_kind(file_id, PatternKind::Expr)),
+        vec![call_path]
+    );
+    // When restricted to paths, we skip the call's path expression and instead match its path.
+    assert_eq!(
+        matched_ranges(match_finder.find_matches_of_kind(file_id, PatternKind::Path)),
+        vec![use_path, call_path]
+    );
+    assert_eq!(
+        matched_ranges(match_finder.find_matches_of_kind(file_id, PatternKind::TypeRef)),
+        vec![]
+    );
+}
+
+#[test]
+fn replace_enum_variant_definition() {
+    assert_ssr_transform(
+        "$v($t) ==>> $v { value: $t }",
+        "enum E { A(i32), B, C { x: i32 } }",
+        "enum E { A { value: i32 }, B, C { x: i32 } }",
+    );
+    assert_matches("$v($t)", "enum E { A(i32), B, C(Vec<u8>) }", &["A(i32)", "C(Vec<u8>)"]);
+    assert_no_match("$v($t)", "enum E { A, B }");
+}
+
+#[test]
+fn suggest_pattern_for_call() {
+    let code = "fn f() {foo(x, 1 + y.len());}";
+    let file = ast::SourceFile::parse(code).tree();
+    let call = file.syntax().descendants().find_map(ast::CallExpr::cast).unwrap();
+    let pattern = crate::suggest_pattern(call.syntax());
+    assert_eq!(pattern, "foo($a, $b + $c.len())");
+    assert_matches(&pattern, code, &["foo(x, 1 + y.len())"]);
+}
+
+#[test]
+fn replace_match_arms_with_multi_placeholder() {
+    assert_ssr_transform(
+        "match $e { $arms:* } ==>> match $e { $arms:* _ => default() }",
+        "fn f() {match x { A => 1, B(y) => { y } }}",
+        "fn f() {match x { A => 1, B(y) => { y } _ => default() }}",
+    );
+    assert_ssr_transform(
+        "match $e { $arms:* } ==>> match $e { $arms:* _ => default() }",
+        "fn f() {match x { A => 1, B => 2, }}",
+        "fn f() {match x { A => 1, B => 2, _ => default() }}",
+    );
+    assert_matches("match $e { $arms:* }", "fn f() {match x {}}", &["match x {}"]);
+}
+
+#[test]
+fn parser_placeholder_constraints() {
+    assert_eq!(
+        parse_error_text("${a:foo} ==>> ()"),
+        "Parse error: Unsupported placeholder constraint `foo`"
+    );
+    assert_eq!(
+        parse_error_text("${a:lifetime ==>> ()"),
+        "Parse error: Placeholder is missing a closing `}`"
+    );
+    assert_eq!(
+        parse_error_text("${a:lifetime x} ==>> ()"),
+        "Parse error: Unexpected token while parsing placeholder: 'x'"
+    );
+    assert_eq!(
+        parse_error_text("${} ==>> ()"),
+        "Parse error: Placeholders should be ${name} or ${name:constraint}"
+    );
+}
+
+#[test]
+fn match_lifetime_placeholders() {
+    assert_ssr_transform(
+        "fn $n<${lt:lifetime}>() {} ==>> fn $n<${lt:lifetime}: 'static>() {}",
+        "fn f<'a>() {} fn g() {} fn h<T>() {}",
+        "fn f<'a: 'static>() {} fn g() {} fn h<T>() {}",
+    );
+    assert_no_match("fn $n<${lt:lifetime}>() {}", "fn g() {} fn h<T>() {}");
+    assert_matches(
+        "fn $n<${a:lifetime}: ${b:lifetime}>() {}",
+        "fn f<'x: 'y>() {} fn g<'x>() {}",
+        &["fn f<'x: 'y>() {}"],
+    );
+    assert_ssr_transform("&${lt:lifetime} $t ==>> &$t", "fn f(x: &'a i32) {}", "fn f(x: &i32) {}");
+    assert_ssr_transform(
+        "break ${l:lifetime} $v ==>> break ${l:lifetime} Some($v)",
+        "fn f() {'outer: loop {break 'outer 42; break 42;}}",
+        "fn f() {'outer: loop {break 'outer Some(42); break 42;}}",
+    );
+}
+
+#[test]
+fn replace_expression_leaves_semicolon() {
+    assert_ssr_transform(
+        "foo() ==>> bar()",
+        "fn f() {foo(); let x = foo(); foo()}",
+        "fn f() {bar(); let x = bar(); bar()}",
+    );
+}
+
+#[test]
+fn match_statements() {
+    let code = "fn f() {foo(); let x = foo(); foo()}";
+    assert_matches("foo();", code, &["foo();"]);
+    assert_matches("let $a = foo();", code, &["let x = foo();"]);
+    assert_ssr_transform("foo(); ==>> bar();", code, "fn f() {bar(); let x = foo(); foo()}");
+    // A statement pattern doesn't match the same expression nested within another statement.
+    assert_no_match("foo();", "fn f() {bar(foo()); baz(foo());}");
+    assert_matches("foo()", "fn f() {bar(foo());}", &["foo()"]);
+}
+
+#[test]
+fn add_derive() {
+    let rule = "#[derive($d:*)] ==>> #[derive($d:*, Clone)]";
+    assert_ssr_transform(
+        rule,
+        "#[derive(Debug)] struct Foo { a: i32, b: String }",
+        "#[derive(Debug, Clone)] struct Foo { a: i32, b: String }",
+    );
+    assert_ssr_transform(
+        rule,
+        "#[derive(Debug, PartialEq)]\nstruct Foo {\n    a: i32,\n}\nstruct Bar {}",
+        "#[derive(Debug, PartialEq, Clone)]\nstruct Foo {\n    a: i32,\n}\nstruct Bar {}",
+    );
+    // An empty derive list doesn't leave a stray comma, whichever side of `$d` it was on.
+    assert_ssr_transform(rule, "#[derive()] struct Foo {}", "#[derive(Clone)] struct Foo {}");
+    let rule = "#[derive($d:*)] ==>> #[derive(Clone, $d:*)]";
+    assert_ssr_transform(rule, "#[derive()] struct Foo {}", "#[derive(Clone)] struct Foo {}");
+    assert_ssr_transform(
+        rule,
+        "#[derive(Debug, Hash)] struct Foo {}",
+        "#[derive(Clone, Debug, Hash)] struct Foo {}",
+    );
+    // The rule can also say what the derive must be on.
+    assert_ssr_transform(
+        "#[derive($d:*)] struct $n { $f:* } ==>> #[derive($d:*, Clone)] struct $n { $f:* }",
+        "#[derive(Debug)] struct Foo { a: i32, b: String }\n#[derive(Debug)] enum E {}",
+        "#[derive(Debug, Clone)] struct Foo { a: i32, b: String }\n#[derive(Debug)] enum E {}",
+    );
+}
+
+#[test]
+fn match_method_call_ignoring_turbofish() {
+    let code = "fn f() {let x: u32 = s.parse().unwrap(); let y = s.parse::<u32>().unwrap();}";
+    assert_matches("$a.parse()", code, &["s.parse()"]);
+    assert_matches_with_options(
+        "$a.parse()",
+        MatchOptions { ignore_turbofish: true, ..MatchOptions::default() },
+        code,
+        &["s.parse()", "s.parse::<u32>()"],
+    );
+    // A pattern with explicit generic arguments still requires them to match.
+    assert_matches_with_options(
+        "$a.parse::<u32>()",
+        MatchOptions { ignore_turbofish: true, ..MatchOptions::default() },
+        code,
+        &["s.parse::<u32>()"],
+    );
+}
+
+#[test]
+fn match_files_skips_filtered_paths() {
+    use ra_db::fixture::WithFixture;
+    use ra_db::salsa::{debug::DebugQueryTable, Database};
+    let db = ra_ide_db::RootDatabase::with_files(
+        r#"
+        //- /lib.rs
+        mod generated;
+        fn f() { foo(1); }
+        //- /generated.rs
+        fn g() { foo(2); }
+        "#,
+    );
+    let files: Vec<FileId> = db.source_root(db.file_source_root(FileId(0))).iter().collect();
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_search_pattern("foo($a)".parse().unwrap());
+    let matches =
+        match_finder.match_files(files, |path| !path.to_string().ends_with("generated.rs"));
+    let matched: Vec<String> = matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["foo(1)"]);
+    // The excluded file should never have been parsed.
+    let parsed: Vec<FileId> = db
+        .query(ra_db::ParseQuery)
+        .entries::<Vec<ra_db::salsa::debug::TableEntry<FileId, _>>>()
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    assert_eq!(parsed, vec![FileId(0)]);
+}
+
+#[test]
+fn remove_unsafe_block() {
+    assert_ssr_transform(
+        "unsafe { $body } ==>> { $body }",
+        "fn f() { let x = unsafe { foo() }; let y = { bar() }; }",
+        "fn f() { let x = { foo() }; let y = { bar() }; }",
+    );
+    assert_no_match("unsafe { $body }", "fn f() { let y = { bar() }; }");
+    assert_ssr_transform(
+        "unsafe { $body:* } ==>> { $body }",
+        "fn f() { unsafe { a(); b() } }",
+        "fn f() { { a(); b() } }",
+    );
+}
+
+#[test]
+fn match_unsafe_fn() {
+    let code = "unsafe fn f1() {} fn f2() {}";
+    assert_matches("unsafe fn $a() {}", code, &["unsafe fn f1() {}"]);
+    assert_matches("fn $a() {}", code, &["fn f2() {}"]);
+}
+
+#[test]
+fn replace_tuple_index() {
+    assert_ssr_transform(
+        "$a.0 ==>> $a.first()",
+        "fn f() { let x = p.0; let y = p.field; let z = p.1; }",
+        "fn f() { let x = p.first(); let y = p.field; let z = p.1; }",
+    );
+    let code = "fn f() { let x = p.0; let y = p.field; }";
+    assert_no_match("$a.0", "fn f() { let y = p.field; }");
+    assert_matches("$a.$i", code, &["p.0", "p.field"]);
+    assert_matches("$a.${i:index}", code, &["p.0"]);
+}
+
+#[test]
+fn replace_with_minimal_diff() {
+    let (db, file_id) = single_file("fn f() { foo(1); }");
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo($a) ==>> foo($a, new_arg)".parse().unwrap());
+    match_finder
+        .set_replace_options(ReplaceOptions { minimal_diff: true, ..ReplaceOptions::default() });
+    let edit = match_finder.edits_for_file(file_id).unwrap().unwrap();
+    let indels: Vec<_> = edit.iter().map(|indel| (indel.delete, indel.insert.as_str())).collect();
+    assert_eq!(indels, vec![(TextRange::empty(14.into()), ", new_arg")]);
+    let mut after = db.file_text(file_id).to_string();
+    edit.apply(&mut after);
+    assert_eq!(after, "fn f() { foo(1, new_arg); }");
+}
+
+#[test]
+fn replace_try_of_method_call() {
+    assert_ssr_transform(
+        "$e.lock()? ==>> $e.lock().unwrap()",
+        "fn f() { let g = self.state.lock()?; let h = m.lock(); }",
+        "fn f() { let g = self.state.lock().unwrap(); let h = m.lock(); }",
+    );
+    assert_matches("$e.lock()?", "fn f() { a.b().lock()?.c()?; }", &["a.b().lock()?"]);
+}
+
+#[test]
+fn replace_cfg_feature_name() {
+    assert_ssr_transform(
+        r#"#[cfg(feature = $f)] ==>> #[cfg(feature = "new")]"#,
+        r#"#[cfg(feature = "old")] fn f() {} #[cfg(test)] fn g() {}"#,
+        r#"#[cfg(feature = "new")] fn f() {} #[cfg(test)] fn g() {}"#,
+    );
+    assert_ssr_transform(
+        r#"#[cfg(feature = $f)] ==>> #[cfg(all(feature = $f, test))]"#,
+        r#"#[cfg(test)] #[cfg(feature = "x")] struct S;"#,
+        r#"#[cfg(test)] #[cfg(all(feature = "x", test))] struct S;"#,
+    );
+}
+
+#[test]
+fn replace_self_constructor() {
+    assert_ssr_transform(
+        "Self::new() ==>> Self::default()",
+        "struct S; impl S { fn f() -> Self { Self::new() } fn g() -> Self { S::new() } }",
+        "struct S; impl S { fn f() -> Self { Self::default() } fn g() -> Self { S::new() } }",
+    );
+}
+
+#[test]
+fn match_method_call_on_self() {
+    let code = "struct S; impl S { fn f(&self) { self.g(); other.g(); } fn g(&self) {} }";
+    assert_matches("self.$m()", code, &["self.g()"]);
+    // A placeholder in receiver position matches `self` like any other expression.
+    assert_matches("$a.g()", code, &["self.g()", "other.g()"]);
+}
+
+#[test]
+fn fuzz_apply_never_panics() {
+    use crate::fuzz_apply;
+    assert_eq!(
+        fuzz_apply("foo($a) ==>> bar($a)", "fn f() { foo(1); }"),
+        Ok("fn f() { bar(1); }".to_owned())
+    );
+    assert_eq!(fuzz_apply("foo($a) ==>> bar($a)", ""), Ok("".to_owned()));
+    let bad_rules =
+        ["", "==>>", "$", "${", "$a ==>> $b", "foo($a) ==>> bar($b)", "$a ==>> ", "\u{0}"];
+    for rule in &bad_rules {
+        assert!(fuzz_apply(rule, "fn f() { foo(1); }").is_err(), "{:?} should be an error", rule);
+    }
+    let sources = [
+        "fn f() { foo(",
+        "}}}{{{",
+        "macro_rules! m { ($a:expr) => { foo($a) } } fn f() { m!(foo(1)); }",
+        "fn f() { foo(\"\u{1F600}\"); }",
+    ];
+    for source in &sources {
+        assert!(
+            fuzz_apply("foo($a) ==>> bar($a)", source).is_ok(),
+            "{:?} should be handled",
+            source
+        );
+    }
+}
+
+#[test]
+fn match_fn_ignoring_unmentioned_qualifiers() {
+    let code = "const fn f1() {} async fn f2() {} fn f3() {} const async fn f4() {}";
+    assert_matches(
+        "fn $n() {}",
+        code,
+        &["const fn f1() {}", "async fn f2() {}", "fn f3() {}", "const async fn f4() {}"],
+    );
+    assert_matches("async fn $n() {}", code, &["async fn f2() {}", "const async fn f4() {}"]);
+    assert_ssr_transform(
+        "fn $n() {} ==>> fn $n() -> i32 { 0 }",
+        "const fn f1() {}",
+        "const fn f1() -> i32 { 0 }",
+    );
+    assert_ssr_transform(
+        "async fn $n() {} ==>> unsafe fn $n() {}",
+        "const async fn f4() {}",
+        "const unsafe fn f4() {}",
+    );
+    // `$qualifiers` puts the qualifiers somewhere else, here after `pub`.
+    assert_ssr_transform(
+        "fn $n() {} ==>> pub $qualifiers fn $n() {}",
+        "const async fn f4() {} fn f3() {}",
+        "pub const async fn f4() {} pub fn f3() {}",
+    );
+}
+
+#[test]
+fn apply_rule_to_items_lazily() {
+    use ra_syntax::ast::ModuleItemOwner;
+    let rule: SsrRule = "foo($a) ==>> bar($a)".parse().unwrap();
+    let source = "fn a() { foo(1); }\nstruct S;\nfn b() { foo(foo(2)); }";
+    let file = ast::SourceFile::parse(source).tree();
+    let mut items = crate::apply_to_items(&rule, file.items());
+    assert_eq!(items.next(), Some(Ok("fn a() { bar(1); }".to_owned())));
+    let rest: Vec<String> = items.map(|item| item.unwrap()).collect();
+    assert_eq!(
+        format!("fn a() {{ bar(1); }}\n{}", rest.join("\n")),
+        crate::fuzz_apply("foo($a) ==>> bar($a)", source).unwrap()
+    );
+}
+
+#[test]
+fn match_ignoring_redundant_parens() {
+    let options = MatchOptions { ignore_parens: true, ..MatchOptions::default() };
+    let code = "fn f() { foo((x + y)); foo(x + y); }";
+    assert_matches("foo($a + $b)", code, &["foo(x + y)"]);
+    assert_matches_with_options(
+        "foo($a + $b)",
+        options.clone(),
+        code,
+        &["foo((x + y))", "foo(x + y)"],
+    );
+    // Parentheses in the pattern.
+    assert_matches_with_options(
+        "foo(($a + $b))",
+        options.clone(),
+        code,
+        &["foo((x + y))", "foo(x + y)"],
+    );
+    assert_matches_with_options("(($a + $b))", options.clone(), code, &["(x + y)", "x + y"]);
+    // Placeholders keep the parentheses around what they matched.
+    let (db, file_id) = single_file("fn f() { foo((x + y)); }");
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.set_match_options(options);
+    match_finder.add_rule("foo($a) ==>> $a * 2".parse().unwrap());
+    let mut after = db.file_text(file_id).to_string();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "fn f() { (x + y) * 2; }");
+}
+
+#[test]
+fn rename_macro_rules_definition() {
+    assert_ssr_transform(
+        "macro_rules! old_name { $body } ==>> macro_rules! new_name { $body }",
+        "macro_rules! old_name { ($a:expr) => { $a + 1 }; } macro_rules! other { () => {} }",
+        "macro_rules! new_name { ($a:expr) => { $a + 1 }; } macro_rules! other { () => {} }",
+    );
+    assert_matches(
+        "macro_rules! $name { $body }",
+        "macro_rules! m1 { () => {}; } fn f() { m1!(); }",
+        &["macro_rules! m1 { () => {}; }"],
+    );
+}
+
+#[test]
+fn match_chain_wildcard() {
+    let code = "fn f() { x.a().b().unwrap(); y.unwrap(); z.a().expect(\"\"); }";
+    assert_matches("$r.**.unwrap()", code, &["x.a().b().unwrap()", "y.unwrap()"]);
+    assert_ssr_transform(
+        "$r.**.unwrap() ==>> check($r)",
+        "fn f() { x.a().b().unwrap(); }",
+        "fn f() { check(x); }",
+    );
+    assert_matches(
+        "foo().**.unwrap()",
+        "fn f() { foo().a().unwrap(); bar().unwrap(); }",
+        &["foo().a().unwrap()"],
+    );
+}
+
+#[test]
+fn match_size_limit() {
+    let code = "fn f() { foo(1); foo(some_long_function_name(with, many, arguments)); }";
+    assert_matches_with_options(
+        "foo($a)",
+        MatchOptions { max_match_size: Some(10), ..MatchOptions::default() },
+        code,
+        &["foo(1)"],
+    );
+}
+
+#[test]
+fn add_send_bound_to_boxed_trait_object() {
+    assert_ssr_transform(
+        "Box<dyn $t> ==>> Box<dyn $t + Send>",
+        "fn f(a: Box<dyn Fn()>, b: &dyn Fn(), c: Box<i32>) {}",
+        "fn f(a: Box<dyn Fn() + Send>, b: &dyn Fn(), c: Box<i32>) {}",
+    );
+    assert_no_match("Box<dyn $t>", "fn f(b: &dyn Fn(), c: Box<i32>) {}");
+    // The placeholder captures all of the existing bounds.
+    assert_ssr_transform(
+        "Box<dyn $t> ==>> Box<dyn $t + Send>",
+        "fn f(a: Box<dyn Fn() + Sync>) {}",
+        "fn f(a: Box<dyn Fn() + Sync + Send>) {}",
+    );
+}
+
+#[test]
+fn match_string_literals_ignoring_whitespace() {
+    let code = r#"fn f() { query("SELECT *   FROM t"); query("SELECT * FROM u"); }"#;
+    assert_matches(r#"query("SELECT * FROM t")"#, code, &[]);
+    assert_matches_with_options(
+        r#"query("SELECT * FROM t")"#,
+        MatchOptions { collapse_string_whitespace: true, ..MatchOptions::default() },
+        code,
+        &[r#"query("SELECT *   FROM t")"#],
+    );
+}
+
+#[test]
+fn match_numeric_literals_ignoring_formatting() {
+    let code = "fn f() { from_millis(1000); from_millis(1_000); from_millis(1000u64); \
+                from_millis(0x3e8); from_millis(999); from_millis(2.5f32); }";
+    assert_matches("from_millis(1000)", code, &["from_millis(1000)"]);
+    let options = MatchOptions { normalize_numeric_literals: true, ..MatchOptions::default() };
+    assert_matches_with_options(
+        "from_millis(1000)",
+        options.clone(),
+        code,
+        &["from_millis(1000)", "from_millis(1_000)", "from_millis(1000u64)"],
+    );
+    assert_matches_with_options(
+        "from_millis(2.5)",
+        options.clone(),
+        code,
+        &["from_millis(2.5f32)"],
+    );
+    assert_matches_with_options(
+        "from_millis(1_000)",
+        MatchOptions { normalize_numeric_base: true, ..options },
+        code,
+        &["from_millis(1000)", "from_millis(1_000)", "from_millis(1000u64)", "from_millis(0x3e8)"],
+    );
+    // Placeholders are unaffected, so repeated uses must match the same text.
+    assert_matches_with_options(
+        "add($a, $a)",
+        MatchOptions { normalize_numeric_literals: true, ..MatchOptions::default() },
+        "fn f() { add(1, 1u8); add(2, 2); }",
+        &["add(2, 2)"],
+    );
+}
+
+#[test]
+fn match_method_with_self_sized_bound() {
+    let code = "trait T { fn a(&self) where Self: Sized; fn b(&self); fn c(self) -> Self where Self: Sized { self } }";
+    assert_matches("fn $n(&self) where Self: Sized;", code, &["fn a(&self) where Self: Sized;"]);
+    assert_ssr_transform(
+        "fn $n(&self) where Self: Sized; ==>> fn $n(&self);",
+        code,
+        "trait T { fn a(&self); fn b(&self); fn c(self) -> Self where Self: Sized { self } }",
+    );
+}
+
+#[test]
+fn validate_replacements_reports_invalid_output() {
+    let (db, file_id) = single_file("fn f() { foo(1); baz(2); }");
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap());
+    match_finder.add_rule("baz($a) ==>> Vec<$a>".parse().unwrap());
+    let matches = match_finder.find_matches_of_kind(file_id, PatternKind::Expr);
+    let errors = match_finder.validate_all_replacements(&matches);
+    assert_eq!(
+        errors,
+        vec![(
+            TextRange::new(17.into(), 23.into()),
+            SsrError::new("Replacement `Vec<2>` is not a valid Expr")
+        )]
+    );
+}
+
+#[test]
+fn desugar_impl_trait_argument() {
+    let rule = "fn $n($a: impl $t) { $body:* } ==>> fn $n<T: $t>($a: T) { $body:* }";
+    assert_ssr_transform(
+        rule,
+        "fn show(x: impl Display) { println!(\"{}\", x); }",
+        "fn show<T: Display>(x: T) { println!(\"{}\", x); }",
+    );
+    assert_no_match(
+        "fn $n($a: impl $t) { $body:* }",
+        "fn show<T: Display>(x: T) { println!(\"{}\", x); }",
+    );
+}
+
+#[test]
+fn match_curried_closure() {
+    let code = "fn f() { let add = |a| |b| a + b; let id = |x| x; }";
+    assert_matches("|$a| |$b| $body", code, &["|a| |b| a + b"]);
+    assert_ssr_transform(
+        "|$a| |$b| $body ==>> |$a, $b| $body",
+        code,
+        "fn f() { let add = |a, b| a + b; let id = |x| x; }",
+    );
+}
+
+#[test]
+fn diff_matches_between_rule_versions() {
+    let (db, file_id) = single_file("fn f() { foo(1); foo(2, 3); bar(4); }");
+    let find = |rule: &str| {
+        let mut match_finder = MatchFinder::new(&db);
+        match_finder.add_rule(rule.parse().unwrap());
+        match_finder.find_matches_of_kind(file_id, PatternKind::Expr)
+    };
+    let diff =
+        crate::diff_matches(&find("foo($a) ==>> baz($a)"), &find("foo($a, $b) ==>> baz($a)"));
+    assert_eq!(
+        diff,
+        crate::MatchDiff {
+            added: vec![TextRange::new(17.into(), 26.into())],
+            removed: vec![TextRange::new(9.into(), 15.into())],
+            changed: vec![],
+        }
+    );
+    let diff = crate::diff_matches(&find("bar($a) ==>> baz($a)"), &find("bar($a) ==>> qux($a)"));
+    assert_eq!(diff.changed, vec![TextRange::new(28.into(), 34.into())]);
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+}
+
+#[test]
+fn unified_diff_of_replacements() {
+    let code = "fn f() {\n    foo(1);\n    foo(2);\n    a();\n    b();\n    c();\n    d();\n    e();\n    \
+                g();\n    h();\n    foo(3);\n}\n";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap());
+    let matches = match_finder.find_matches_in_file(file_id);
+    let diff = crate::unified_diff(code, &matches, &ReplaceOptions::default()).unwrap();
+    assert_eq!(
+        diff,
+        "@@ -1,6 +1,6 @@\n fn f() {\n-    foo(1);\n+    bar(1);\n-    foo(2);\n+    bar(2);\n     a();\n     \
+         b();\n     c();\n@@ -8,5 +8,5 @@\n     e();\n     g();\n     h();\n-    foo(3);\n+    bar(3);\n \
+         }\n"
+    );
+    assert_eq!(
+        crate::unified_diff(code, &SsrMatches::default(), &ReplaceOptions::default()),
+        Ok(String::new())
+    );
+}
+
+#[test]
+fn replace_while_true_with_loop() {
+    assert_ssr_transform(
+        "while true { $body:* } ==>> loop { $body:* }",
+        "fn f() { while true { a(); b(); } while x { c(); } }",
+        "fn f() { loop { a(); b(); } while x { c(); } }",
+    );
+    assert_matches(
+        "${l:any_loop}",
+        "fn f() { loop {} while x { break; } for i in v {} if x {} }",
+        &["loop {}", "while x { break; }", "for i in v {}"],
+    );
+}
+
+#[test]
+fn match_function_call_ignoring_turbofish() {
+    let code = "fn f() { foo(x); foo::<u32>(y); let v: Vec<u32> = Vec::new(); }";
+    assert_matches("foo($a)", code, &["foo(x)"]);
+    assert_matches_with_options(
+        "foo($a)",
+        MatchOptions { ignore_turbofish: true, ..MatchOptions::default() },
+        code,
+        &["foo(x)", "foo::<u32>(y)"],
+    );
+    // Types with generic arguments aren't turbofish, so still need to match exactly.
+    assert_matches_with_options(
+        "Vec",
+        MatchOptions { ignore_turbofish: true, ..MatchOptions::default() },
+        code,
+        &["Vec"],
+    );
+    assert_ssr_transform(
+        "foo::<$t>($a) ==>> bar::<$t>($a)",
+        code,
+        "fn f() { foo(x); bar::<u32>(y); let v: Vec<u32> = Vec::new(); }",
+    );
+}
+
+#[test]
+fn placeholder_capture_is_reproduced_exactly() {
+    assert_ssr_transform(
+        "foo($a) ==>> bar($a)",
+        "fn f() { foo({\n    // comment\n    x  +   y /* trailing */\n}); }",
+        "fn f() { bar({\n    // comment\n    x  +   y /* trailing */\n}); }",
+    );
+    assert_ssr_transform(
+        "foo($a:*) ==>> bar($a:*)",
+        "fn f() { foo(x, /* c */ y,\n  z); }",
+        "fn f() { bar(x, /* c */ y,\n  z); }",
+    );
+    assert_ssr_transform(
+        "m!($a) ==>> n!($a)",
+        "macro_rules! m { ($e:expr) => { $e } } macro_rules! n { ($e:expr) => { $e } } fn f() { m!(x  /* c */  + y); }",
+        "macro_rules! m { ($e:expr) => { $e } } macro_rules! n { ($e:expr) => { $e } } fn f() { n!(x  /* c */  + y); }",
+    );
+}
+
+#[test]
+fn match_try_on_option_or_result() {
+    let code = r#"
+        enum Option<T> { Some(T), None }
+        enum Result<T, E> { Ok(T), Err(E) }
+        fn opt() -> Option<i32> { Option::None }
+        fn res() -> Result<i32, ()> { Result::Err(()) }
+        fn f() -> Option<i32> { let a = opt()?; let b = res()?; let c = unknown()?; Option::None }
+        "#;
+    assert_matches("${e:is_option}?", code, &["opt()?", "unknown()?"]);
+    assert_matches("${e:is_result}?", code, &["res()?", "unknown()?"]);
+}
+
+#[test]
+fn match_identifier_case_conventions() {
+    let code = "fn foo_bar() {} fn FooBar() {} fn _private() {}";
+    assert_matches("fn ${f:is_snake_case}() {}", code, &["fn foo_bar() {}", "fn _private() {}"]);
+    assert_matches("fn ${f:is_upper_camel_case}() {}", code, &["fn FooBar() {}"]);
+    assert_ssr_transform(
+        "struct ${s:is_snake_case}; ==>> struct $s:pascal;",
+        "struct my_struct; struct Other;",
+        "struct MyStruct; struct Other;",
+    );
+}
+
+#[test]
+fn replace_repr_attribute() {
+    assert_ssr_transform(
+        "#[repr($r)] struct $n { $f:* } ==>> #[repr(transparent)] struct $n { $f:* }",
+        "#[repr(C)] struct Foo { a: i32 } struct Bar { b: i32 }",
+        "#[repr(transparent)] struct Foo { a: i32 } struct Bar { b: i32 }",
+    );
+    assert_ssr_transform(
+        "#[repr($r)] struct $n { $f:* } ==>> #[repr($r, align(8))] struct $n { $f:* }",
+        "#[repr(C)] struct Foo { a: i32, b: u8 }",
+        "#[repr(C, align(8))] struct Foo { a: i32, b: u8 }",
+    );
+    assert_matches(
+        "#[repr($r)] struct $n { $f:* }",
+        "#[repr(C)] struct Foo { a: i32, b: u8 } struct Bar { b: i32 } #[derive(Debug)] struct Baz {}",
+        &["#[repr(C)] struct Foo { a: i32, b: u8 }"],
+    );
+}
+
+#[test]
+fn match_attribute_placeholder() {
+    let code = "#[test] fn a() {} #[cfg(test)] fn b() {} #[doc = \"x\"] fn c() {} fn d() {}";
+    assert_matches(
+        "#[$attr] fn $n() {}",
+        code,
+        &["#[test] fn a() {}", "#[cfg(test)] fn b() {}", "#[doc = \"x\"] fn c() {}"],
+    );
+    assert_ssr_transform(
+        "#[$attr] fn $n() {} ==>> #[$attr] #[inline] fn $n() {}",
+        "#[cfg(test)] fn b() {} fn d() {}",
+        "#[cfg(test)] #[inline] fn b() {} fn d() {}",
+    );
+}
+
+#[test]
+fn replace_doc_comments() {
+    let code = "/** Old */ fn a() {} /** Other */ fn b() {}";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder
+        .set_match_options(MatchOptions { match_doc_comments: true, ..MatchOptions::default() });
+    match_finder.add_rule("/** Old */ fn $n() {} ==>> /** New */ fn $n() {}".parse().unwrap());
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "/** New */ fn a() {} /** Other */ fn b() {}");
+    // Without the option, doc comments are ignored and kept at the end of the replacement.
+    assert_matches("fn $n() {}", code, &["/** Old */ fn a() {}", "/** Other */ fn b() {}"]);
+}
+
+#[test]
+fn match_anonymous_placeholders() {
+    assert_matches("foo($_, $_)", "fn f() {foo(1, 2); foo(3, 3);}", &["foo(1, 2)", "foo(3, 3)"]);
+    assert_ssr_transform("foo($a, $_) ==>> bar($a)", "fn f() {foo(1, 2)}", "fn f() {bar(1)}");
+    let pattern: SsrPattern = "foo($_, $a, $_)".parse().unwrap();
+    let file = ast::SourceFile::parse("fn f() { foo(1, 2, 3); }").tree();
+    let bound: Vec<Vec<String>> = crate::matches(&pattern, file.syntax())
+        .map(|m| m.placeholder_text().into_iter().map(|(name, text)| name + "=" + &text).collect())
+        .collect();
+    assert_eq!(bound, vec![vec!["a=2".to_owned()]]);
+    assert_eq!(
+        parse_error_text("foo($_) ==>> bar($_)"),
+        "Parse error: The anonymous placeholder `$_` can't be used in a replacement"
+    );
+}
+
+#[test]
+fn match_negative_constraint() {
+    let code = r#"fn f() {foo().unwrap(); Ok(1).unwrap(); "x".unwrap(); Err(2).unwrap();}"#;
+    assert_matches(
+        "${r:not(Ok($_))}.unwrap()",
+        code,
+        &["foo().unwrap()", r#""x".unwrap()"#, "Err(2).unwrap()"],
+    );
+    assert_matches(
+        "${r:not($_:literal)}.unwrap()",
+        code,
+        &["foo().unwrap()", "Ok(1).unwrap()", "Err(2).unwrap()"],
+    );
+}
+
+#[test]
+fn update_matches_after_edit() {
+    fn summary(matches: &SsrMatches) -> Vec<(TextRange, String)> {
+        let mut result = Vec::new();
+        for m in &matches.matches {
+            result.push((m.range, m.matched_text()));
+            for p in m.placeholder_values.values() {
+                result.extend(summary(&p.inner_matches));
+            }
+        }
+        result.sort_by_key(|(range, _)| (range.start(), range.end()));
+        result
+    }
+    let mut code = "fn f() {\n    foo(1);\n    baz(2);\n}\nfn g() { foo(foo(3)); }".to_owned();
+    let (mut db, file_id) = single_file(&code);
+    let rule: SsrRule = "foo($a) ==>> bar($a)".parse().unwrap();
+    let mut matches = {
+        let mut match_finder = MatchFinder::new(&db);
+        match_finder.add_rule(rule.clone());
+        match_finder.find_matches_in_file(file_id)
+    };
+    // Each edit is a replacement of the first occurrence of some text.
+    for (old, new) in &[("baz", "foo"), ("foo(1)", "fob(1)"), ("fn g", "const X: i32 = 1;\nfn g")] {
+        let start = TextSize::from(code.find(old).unwrap() as u32);
+        let edit = TextEdit::replace(TextRange::at(start, TextSize::of(*old)), (*new).to_owned());
+        edit.apply(&mut code);
+        let mut change = ra_ide_db::change::AnalysisChange::new();
+        change.change_file(file_id, Some(std::sync::Arc::new(code.clone())));
+        db.apply_change(change);
+        let mut match_finder = MatchFinder::new(&db);
+        match_finder.add_rule(rule.clone());
+        matches = match_finder.update_matches(file_id, matches, &edit);
+        assert_eq!(summary(&matches), summary(&match_finder.find_matches_in_file(file_id)));
+    }
+    assert_eq!(
+        summary(&matches).into_iter().map(|(_, text)| text).collect::<Vec<_>>(),
+        vec!["foo(2)", "foo(foo(3))", "foo(3)"]
+    );
+
+    // Code that's too deep for a full search is too deep when updating too.
+    let errors = |matches: &SsrMatches| -> Vec<Option<TextRange>> {
+        matches.errors().iter().map(|e| e.range()).collect()
+    };
+    let mut code = "fn f() { foo(1); }\nfn g() { { { foo(2); } } }".to_owned();
+    let (mut db, file_id) = single_file(&code);
+    let options = MatchOptions { max_depth: Some(5), ..MatchOptions::default() };
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule(rule.clone());
+    match_finder.set_match_options(options.clone());
+    let matches = match_finder.find_matches_in_file(file_id);
+    let start = TextSize::from(code.find("2").unwrap() as u32);
+    let edit = TextEdit::replace(TextRange::at(start, 1.into()), "3".to_owned());
+    edit.apply(&mut code);
+    let mut change = ra_ide_db::change::AnalysisChange::new();
+    change.change_file(file_id, Some(std::sync::Arc::new(code.clone())));
+    db.apply_change(change);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule(rule);
+    match_finder.set_match_options(options);
+    let updated = match_finder.update_matches(file_id, matches, &edit);
+    let searched = match_finder.find_matches_in_file(file_id);
+    assert_eq!(summary(&updated), summary(&searched));
+    assert_eq!(errors(&updated), errors(&searched));
+    assert!(!errors(&updated).is_empty());
+}
+
+#[test]
+fn match_in_macro_arguments() {
+    let code = r#"fn f() { println!("{}", foo(1)); let v = vec![foo(2), x]; other!(foo(3)); }"#;
+    assert_matches("foo($a)", code, &[]);
+    let options = MatchOptions { match_in_macro_arguments: true, ..MatchOptions::default() };
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.set_match_options(options.clone());
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap());
+    let matches = match_finder.find_matches_in_file(file_id);
+    assert!(matches.matches.iter().all(|m| m.is_in_macro_arguments()));
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(
+        after,
+        r#"fn f() { println!("{}", bar(1)); let v = vec![bar(2), x]; other!(foo(3)); }"#
+    );
+    assert_matches_with_options(
+        "foo($a)",
+        options,
+        "fn f() { assert_eq!(foo(foo(1)), 2); vec![foo(4); 3]; }",
+        &["foo(1)", "foo(foo(1))"],
+    );
+}
+
+#[test]
+fn apply_rule_pipeline() {
+    let pipeline = crate::RulePipeline(vec![
+        "foo($a) ==>> bar($a, 0)".parse().unwrap(),
+        "bar($a, 0) ==>> baz($a)".parse().unwrap(),
+    ]);
+    assert_eq!(
+        pipeline.apply("fn f() { foo(1); bar(2, 3); }"),
+        Ok("fn f() { baz(1); bar(2, 3); }".to_owned())
+    );
+    let pipeline = crate::RulePipeline(vec![
+        "foo($a) ==>> bar($a)".parse().unwrap(),
+        "bar($a) ==>> Vec<$a>".parse().unwrap(),
+    ]);
+    assert_eq!(
+        pipeline.apply("fn f() { foo(1); }"),
+        Err(SsrError::new("Stage 2 failed: output has syntax errors"))
+    );
+}
+
+#[test]
+fn collect_arms_of_matched_match() {
+    let rule: SsrRule = "match $e { $arms:* } ==>> ()".parse().unwrap();
+    let file = ast::SourceFile::parse(
+        "fn f(x: Option<i32>) -> i32 { match x { Some(1) => 10, Some(n) => n, None => { 0 } } }",
+    )
+    .tree();
+    assert_eq!(
+        crate::collect_match_arms(&rule, file.syntax()),
+        vec![
+            ("Some(1)".to_owned(), "10".to_owned()),
+            ("Some(n)".to_owned(), "n".to_owned()),
+            ("None".to_owned(), "{ 0 }".to_owned()),
+        ]
+    );
+
+    // Matches nested within the placeholder of another match are included, as are matches within
+    // a node that isn't a whole file.
+    let file = ast::SourceFile::parse(
+        "fn f(x: Option<i32>) -> i32 { match x { Some(n) => match n { 1 => 2, _ => 3 }, None => 0 } }",
+    )
+    .tree();
+    let body = file.syntax().descendants().find_map(ast::BlockExpr::cast).unwrap();
+    assert_eq!(
+        crate::collect_match_arms(&rule, body.syntax()),
+        vec![
+            ("Some(n)".to_owned(), "match n { 1 => 2, _ => 3 }".to_owned()),
+            ("None".to_owned(), "0".to_owned()),
+            ("1".to_owned(), "2".to_owned()),
+            ("_".to_owned(), "3".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn replace_array_type_with_vec() {
+    assert_ssr_transform(
+        "[$t; $n] ==>> Vec<$t>",
+        "fn f(a: [u8; 4], b: &[u8], c: [i32; N * 2]) {}",
+        "fn f(a: Vec<u8>, b: &[u8], c: Vec<i32>) {}",
+    );
+    assert_no_match("[$t; $n]", "fn f(b: &[u8]) {}");
+}
+
+#[test]
+fn match_only_within_call() {
+    let code = r#"
+        macro_rules! assert { ($e:expr) => { if !$e { panic() } } }
+        fn f() {
+            assert!(x.is_empty());
+            let y = x.is_empty();
+            check(x.is_empty());
+        }
+        "#;
+    let options =
+        |name: &str| MatchOptions { within_call: Some(name.to_owned()), ..MatchOptions::default() };
+    assert_matches_with_options("$a.is_empty()", options("assert"), code, &["x.is_empty()"]);
+    assert_matches_with_options("$a.is_empty()", options("check"), code, &["x.is_empty()"]);
+    assert_matches_with_options("$a.is_empty()", options("other"), code, &[]);
+    assert_matches("$a.is_empty()", code, &["x.is_empty()", "x.is_empty()", "x.is_empty()"]);
+}
+
+#[test]
+fn match_only_items_with_attribute() {
+    let code = "#[test] fn a() {x();} fn b() {y();}\n#[test]\n#[ignore]\nfn c() {z();}";
+    let options =
+        || MatchOptions { required_attribute: Some("test".to_owned()), ..MatchOptions::default() };
+    assert_matches_with_options(
+        "fn $n() { $body:block }",
+        options(),
+        code,
+        &["#[test] fn a() {x();}", "#[test]\n#[ignore]\nfn c() {z();}"],
+    );
+
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.set_match_options(options());
+    match_finder
+        .add_rule("fn $n() { $body:block } ==>> fn $n() { run(|| $body) }".parse().unwrap());
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(
+        after,
+        "#[test] fn a() { run(|| {x();}) } fn b() {y();}\n#[test]\n#[ignore]\nfn c() { run(|| {z();}) }"
+    );
+}
+
+#[test]
+fn replace_default_calls_with_inferred_type() {
+    let template = r#"trait Default { fn default() -> Self; }
+struct Foo;
+impl Default for Foo { fn default() -> Self { Foo } }
+struct Bar<T>(T);
+impl<T> Default for Bar<T> { fn default() -> Self { Bar(todo()) } }
+impl Default for &str { fn default() -> Self { "" } }
+mod m {
+    struct Hidden;
+    impl crate::Default for Hidden { fn default() -> Self { Hidden } }
+    pub fn take(_: Hidden) {}
+}
+fn f() {
+    let a: Foo = A;
+    let b = B;
+    let c: Bar<i32> = C;
+    let d = D;
+    let e: &str = E;
+    m::take(F);
+}
+"#;
+    let code = template
+        .replace("A;", "Default::default();")
+        .replace("B;", "Foo::default();")
+        .replace("C;", "Default::default();")
+        .replace("D;", "Default::default();")
+        .replace("E;", "Default::default();")
+        .replace("F)", "Default::default())");
+    // Where the type is unknown, as for `d`, or can't be named, as for `Hidden`, there's no match.
+    let expected = template
+        .replace("A;", "Foo::new();")
+        .replace("B;", "Foo::new();")
+        .replace("C;", "<Bar<i32>>::new();")
+        .replace("D;", "Default::default();")
+        .replace("E;", "<&str>::new();")
+        .replace("F)", "Default::default())");
+    assert_matches(
+        "$t::default()",
+        &code,
+        &["Default::default()", "Foo::default()", "Default::default()", "Default::default()"],
+    );
+    assert_ssr_transform("$t::default() ==>> $t::new()", &code, &expected);
+}
+
+#[test]
+fn for_each_match_streams_matches_in_order() {
+    let code = "fn f() { foo(1); bar(foo(2)); foo(foo(3)) }";
+    let node = ra_syntax::SourceFile::parse(code).tree().syntax().clone();
+    let rule: SsrRule = "foo($a) ==>> bar($a)".parse().unwrap();
+    let mut seen = Vec::new();
+    crate::for_each_match(&rule, &node, |m| seen.push((m.range().start(), m.matched_text())));
+    assert_eq!(
+        seen,
+        vec![
+            (9.into(), "foo(1)".to_owned()),
+            (21.into(), "foo(2)".to_owned()),
+            (30.into(), "foo(foo(3))".to_owned()),
+            (34.into(), "foo(3)".to_owned()),
+        ]
+    );
+    assert_eq!(crate::count_matches(&rule, &node), seen.len());
+
+    // A node that isn't a whole file is searched in its original context.
+    let call = node.descendants().filter_map(ast::CallExpr::cast).nth(1).unwrap();
+    assert_eq!(call.syntax().text(), "bar(foo(2))");
+    assert_eq!(crate::count_matches(&rule, call.syntax()), 1);
+}
+
+#[test]
+fn match_inferred_cast() {
+    let code = "fn f(x: u8) { let a: u32 = x as _; let b = x as u32; }";
+    assert_matches("$e as _", code, &["x as _"]);
+    assert_no_match("$e as _", "fn f(x: u8) { let b = x as u32; }");
+    assert_ssr_transform(
+        "$e as _ ==>> $e.into()",
+        code,
+        "fn f(x: u8) { let a: u32 = x.into(); let b = x as u32; }",
+    );
+}
+
+#[test]
+fn match_alternative_patterns() {
+    assert_ssr_transform(
+        "foo($a) | bar($a) ==>> baz($a)",
+        "fn f() { foo(x); bar(y); qux(z); }",
+        "fn f() { baz(x); baz(y); qux(z); }",
+    );
+    // Closures and `||` aren't alternation.
+    assert_ssr_transform(
+        "|$a| $b || true ==>> |$a| true",
+        "fn f() { let c = |x| x || true; }",
+        "fn f() { let c = |x| true; }",
+    );
+}
+
+#[test]
+fn alternatives_must_define_the_same_placeholders() {
+    assert_eq!(
+        parse_error_text("foo($a) | bar($b) ==>> baz($a)"),
+        "Parse error: Alternatives of a search pattern must define the same placeholders"
+    );
+    assert_eq!(
+        parse_error_text("foo($a) | ==>> baz($a)"),
+        "Parse error: Search pattern has an empty alternative"
+    );
+}
+
+#[test]
+fn replace_unit_variant() {
+    // A unit variant matches both where it's used as a value and where it's used in a pattern,
+    // since both refer to the same variant. Its definition isn't matched.
+    assert_ssr_transform(
+        "None ==>> Option::None",
+        "enum Option<T> { Some(T), None } use Option::None;
+        fn f(x: Option<i32>) -> Option<i32> { match x { None => {} _ => {} } None }",
+        "enum Option<T> { Some(T), None } use Option::None;
+        fn f(x: Option<i32>) -> Option<i32> { match x { Option::None => {} _ => {} } Option::None }",
+    );
+    assert_matches("Unit", "struct Unit; fn f() { let Unit = Unit; }", &["Unit", "Unit"]);
+}
+
+#[test]
+fn placeholder_not_contains_constraint() {
+    let pattern = "foo(${a:not_contains(bar())})";
+    assert_matches(pattern, "fn f() { foo(x + 1); foo(bar() + 1); }", &["foo(x + 1)"]);
+    assert_no_match(pattern, "fn f() { foo(bar()); foo(-bar()); }");
+    // Only calls with no arguments are excluded, since that's what the constraint's pattern matches.
+    assert_matches(pattern, "fn f() { foo(bar(1)); }", &["foo(bar(1))"]);
+    assert_matches(
+        "foo(${a:not_contains(bar($x))})",
+        "fn f() { foo(bar(1)); foo(baz(1)); }",
+        &["foo(baz(1))"],
+    );
+}
+
+#[test]
+fn replace_two_call_method_chain() {
+    assert_ssr_transform(
+        "$e.iter().cloned() ==>> $e.iter().copied()",
+        "fn f() { let a = v.iter().cloned(); let b = w.x.iter().cloned().sum(); let c = v.iter(); }",
+        "fn f() { let a = v.iter().copied(); let b = w.x.iter().copied().sum(); let c = v.iter(); }",
+    );
+    assert_no_match("$e.iter().cloned()", "fn f() { let c = v.iter(); let d = v.cloned(); }");
+}
+
+#[test]
+fn match_extern_abi_functions() {
+    let code = r#"extern "C" fn a(x: i32) {} fn b(x: i32) {} extern "system" fn c(x: i32) {}"#;
+    assert_matches(r#"extern "C" fn $n($p:*) {}"#, code, &[r#"extern "C" fn a(x: i32) {}"#]);
+    assert_no_match(r#"extern "C" fn $n($p:*) {}"#, "fn b(x: i32) {}");
+    assert_matches("fn $n($p:*) {}", code, &["fn b(x: i32) {}"]);
+    assert_ssr_transform(
+        r#"extern "C" fn $n($p:*) {} ==>> extern "system" fn $n($p:*) {}"#,
+        code,
+        r#"extern "system" fn a(x: i32) {} fn b(x: i32) {} extern "system" fn c(x: i32) {}"#,
+    );
+}
+
+#[test]
+fn alpha_equivalent_rules() {
+    fn equivalent(a: &str, b: &str) -> bool {
+        let a: SsrRule = a.parse().unwrap();
+        a.is_alpha_equivalent(&b.parse().unwrap())
+    }
+    assert!(equivalent("foo($a) ==>> bar($a)", "foo($x) ==>> bar($x)"));
+    assert!(equivalent("foo($a, $b) ==>> bar($b, $a)", "foo( $x,$y ) ==>> bar($y, $x)"));
+    assert!(!equivalent("foo($a) ==>> bar($a)", "foo($a) ==>> baz($a)"));
+    assert!(!equivalent("foo($a, $b) ==>> bar($a)", "foo($x, $y) ==>> bar($y)"));
+    assert!(!equivalent("foo($a, $b) ==>> bar($a, $b)", "foo($x, $y) ==>> bar($y, $x)"));
+    assert!(!equivalent("foo($a:*) ==>> bar($a:*)", "foo($a) ==>> bar($a)"));
+}
+
+#[test]
+fn skip_replacements_that_call_missing_methods() {
+    let code = r#"struct S { len: usize }
+impl S { fn a(&self) {} fn b(&self) {} }
+trait T { fn t(&self); }
+impl T for S { fn t(&self) {} }
+fn f(s: S) { s.a(); }"#;
+    let edit_with_rule = |rule: &str| {
+        let (db, file_id) = single_file(code);
+        let mut match_finder = MatchFinder::new(&db);
+        match_finder.set_match_options(MatchOptions {
+            check_template_methods: true,
+            ..MatchOptions::default()
+        });
+        match_finder.add_rule(rule.parse().unwrap());
+        match_finder.edits_for_file(file_id).unwrap().map(|edit| {
+            let mut after = code.to_string();
+            edit.apply(&mut after);
+            after
+        })
+    };
+    assert_eq!(edit_with_rule("$r.a() ==>> $r.missing()"), None);
+    assert_eq!(edit_with_rule("$r.a() ==>> $r.b()"), Some(code.replace("s.a()", "s.b()")));
+    assert_eq!(edit_with_rule("$r.a() ==>> $r.t()"), Some(code.replace("s.a()", "s.t()")));
+    // Field accesses aren't method calls, so aren't checked.
+    assert_eq!(edit_with_rule("$r.a() ==>> $r.len"), Some(code.replace("s.a()", "s.len")));
+    assert_eq!(edit_with_rule("$r.a() ==>> $r.len()"), None);
+    // Without the option, the rewrite is applied regardless.
+    assert_ssr_transform("$r.a() ==>> $r.missing()", code, &code.replace("s.a()", "s.missing()"));
+}
+
+#[test]
+fn placeholder_kinds() {
+    let code = r#"fn f() { log("hi"); log(x); log(a::b); log(1 + 2); }"#;
+    assert_matches("log($msg:literal)", code, &[r#"log("hi")"#]);
+    assert_matches("log($msg:ident)", code, &["log(x)"]);
+    assert_matches("log($msg:path)", code, &["log(x)", "log(a::b)"]);
+    assert_matches("log($msg:expr)", code, &[r#"log("hi")"#, "log(x)", "log(a::b)", "log(1 + 2)"]);
+    assert_matches("let $p:pat = $v;", "fn f() { let (a, b) = c; }", &["let (a, b) = c;"]);
+    assert_matches("Vec<$t:ty>", "fn f(a: Vec<i32>) {}", &["Vec<i32>"]);
+    assert_ssr_transform(
+        "log($msg:literal) ==>> info($msg)",
+        code,
+        r#"fn f() { info("hi"); log(x); log(a::b); log(1 + 2); }"#,
+    );
+    // A space after the colon means that what follows isn't a placeholder kind.
+    assert_matches("fn $n($a: i32) {}", "fn f(x: i32) {}", &["fn f(x: i32) {}"]);
+}
+
+#[test]
+fn parser_unknown_placeholder_kind() {
+    assert_eq!(
+        parse_error_text("foo($a:bar) ==>> ()"),
+        "Parse error: Unknown placeholder kind `bar`. Expected one of expr, ident, path, ty, pat, \
+         literal or block. Put a space after the `:` if `bar` is a type"
+    );
+}
+
+#[test]
+fn parser_unknown_placeholder_transform() {
+    assert_eq!(
+        parse_error_text("foo($a) ==>> bar($a:camel)"),
+        "Parse error: Unknown placeholder transform `camel`. Expected one of upper, lower or pascal"
+    );
+}
+
+#[test]
+fn replace_with_placeholder_transforms() {
+    assert_ssr_transform("get($a) ==>> $a:upper", "fn f() {get(some_Name)}", "fn f() {SOME_NAME}");
+    assert_ssr_transform("get($a) ==>> $a:lower", "fn f() {get(some_Name)}", "fn f() {some_name}");
+    assert_ssr_transform(
+        "get($a) ==>> $a:pascal::new()",
+        "fn f() {get(foo_bar)}",
+        "fn f() {FooBar::new()}",
+    );
+}
+
+#[test]
+fn apply_rule_set_in_one_pass() {
+    let rules: SsrRules = "
+        // Prefer the more specific rule.
+        foo(1) ==>> one()
+
+        foo($a) ==>> bar($a)
+        baz($a) ==>> qux($a)
+        "
+    .parse()
+    .unwrap();
+    assert_eq!(rules.0.len(), 3);
+    let (db, file_id) = single_file("fn f() { foo(1); foo(2); baz(foo(1)); }");
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rules(rules);
+    let matches = match_finder.find_matches_in_file(file_id).flattened();
+    let rule_indexes: Vec<_> = matches.matches.iter().map(|m| m.rule_index).collect();
+    assert_eq!(rule_indexes, vec![0, 1, 0, 2]);
+    let mut after = "fn f() { foo(1); foo(2); baz(foo(1)); }".to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "fn f() { one(); bar(2); qux(one()); }");
+}
+
+#[test]
+fn rule_set_parse_error_reports_line() {
+    let error = "foo($a) ==>> bar($a)\nfoo($a) ==>> bar($b)".parse::<SsrRules>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parse error: Line 2: Replacement contains undefined placeholders: $b"
+    );
+}
+
+#[test]
+fn overlapping_matches_prefer_earlier_rule() {
+    let code = "fn f() { foo(1); foo(2); }";
+    let apply = |rules: &str| {
+        let (db, file_id) = single_file(code);
+        let mut match_finder = MatchFinder::new(&db);
+        match_finder.add_rules(rules.parse().unwrap());
+        let mut after = code.to_owned();
+        match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+        after
+    };
+    assert_eq!(apply("foo($a) ==>> bar($a)\nfoo(1) ==>> one()"), "fn f() { bar(1); bar(2); }");
+    assert_eq!(apply("foo(1) ==>> one()\nfoo($a) ==>> bar($a)"), "fn f() { one(); bar(2); }");
+}
+
+#[test]
+fn replace_tokens_in_non_rust_macro() {
+    let code = "macro_rules! query { ($($t:tt)*) => {} }
+fn f() { query!(SELECT name, age FROM users WHERE id = 1); query!(SELECT * FROM posts); }";
+    let rule = "SELECT $cols FROM users WHERE id = $v ==>> SELECT $cols FROM accounts WHERE account_id = $v";
+    assert!(rule.parse::<SsrRule>().is_err());
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule(SsrRule::parse_tokens(rule).unwrap());
+    let matches = match_finder.find_matches_in_file(file_id);
+    let matched: Vec<String> = matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["SELECT name, age FROM users WHERE id = 1"]);
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(
+        after,
+        "macro_rules! query { ($($t:tt)*) => {} }
+fn f() { query!(SELECT name, age FROM accounts WHERE account_id = 1); query!(SELECT * FROM posts); }"
+    );
+}
+
+#[test]
+fn rule_required_literals() {
+    fn required(rule: &str) -> Vec<SmolStr> {
+        rule.parse::<SsrRule>().unwrap().required_literals()
+    }
+    assert_eq!(required("Box::new($a) ==>> Box::from($a)"), vec!["Box", "new"]);
+    assert_eq!(required("$r.**.unwrap() ==>> $r.expect()"), vec!["unwrap"]);
+    assert_eq!(required("let $a = foo($b); ==>> ()"), vec!["let", "foo"]);
+    assert_eq!(required("foo(bar($a)) | bar(foo($a)) ==>> ()"), vec!["foo", "bar"]);
+    assert_eq!(required("foo($a) | bar($a) ==>> ()"), Vec::<SmolStr>::new());
+}
+
+#[test]
+fn replace_call_with_any_number_of_args() {
+    assert_ssr_transform(
+        "foo($args:*) ==>> bar($args:*)",
+        "fn f() { foo(1, 2, 3); foo(); foo(x); }",
+        "fn f() { bar(1, 2, 3); bar(); bar(x); }",
+    );
+}
+
+#[test]
+fn parser_multi_placeholder_outside_list() {
+    let error = "Parse error: `$a:*` can only be used where a list of things is allowed, such as \
+                 the arguments of a call";
+    assert_eq!(parse_error_text("$a:* ==>> ()"), error);
+    assert_eq!(parse_error_text("$a:* + 1 ==>> ()"), error);
+    assert_eq!(parse_error_text("foo($b).$a:* ==>> ()"), error);
+}
+
+#[test]
+fn parse_many_multi_placeholders() {
+    // The shape of each multi-placeholder is picked from the code around it, so we don't need to
+    // try every combination.
+    let calls: Vec<String> = (0..41).map(|i| format!("f($a{}:*)", i)).collect();
+    let rule = format!("({}) ==>> ()", calls.join(", "));
+    assert!(rule.parse::<SsrRule>().is_ok());
+    let arms: Vec<String> = (0..12).map(|i| format!("match $e{} {{ $arms{}:* }}", i, i)).collect();
+    let rule = format!("({}) ==>> ()", arms.join(", "));
+    assert!(rule.parse::<SsrRule>().is_ok());
+    // Where it can't be told, there's a limit on how many combinations we try.
+    let literals: Vec<String> = (0..7).map(|i| format!("S {{ x: 1, $f{}:* }}", i)).collect();
+    assert_eq!(
+        parse_error_text(&format!("({}) ==>> ()", literals.join(", "))),
+        "Parse error: Pattern has too many multi-placeholders whose kind can't be told from the \
+         code around them"
+    );
+}
+
+#[test]
+fn build_rule_from_parts() {
+    let rule = SsrRuleBuilder::new()
+        .search("foo($a)")
+        .replace("bar($a)")
+        .constraint("a", PlaceholderConstraint::NotContains("baz()".to_owned()))
+        .build()
+        .unwrap();
+    let code = "fn f() { foo(1); foo(baz()); }";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule(rule);
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "fn f() { bar(1); foo(baz()); }");
+
+    // Constraints that change how the pattern is parsed work too.
+    let rule = SsrRuleBuilder::new()
+        .search("fn $n<$lt>() {}")
+        .replace("fn $n<$lt: 'static>() {}")
+        .constraint("lt", PlaceholderConstraint::Lifetime)
+        .build();
+    assert!(rule.is_ok());
+
+    let rule = SsrRuleBuilder::new()
+        .search("foo($a)")
+        .replace("bar($a)")
+        .constraint("a", PlaceholderConstraint::Not("Ok($_)".to_owned()))
+        .constraint("a", PlaceholderConstraint::IsSnakeCase)
+        .build()
+        .unwrap();
+    let code = "fn f() { foo(x_y); foo(Ok(x_y)); foo(XY); }";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule(rule);
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "fn f() { bar(x_y); foo(Ok(x_y)); foo(XY); }");
+}
+
+#[test]
+fn builder_errors() {
+    let error = |builder: SsrRuleBuilder| builder.build().unwrap_err().to_string();
+    assert_eq!(
+        error(SsrRuleBuilder::new().replace("bar()")),
+        "Parse error: No search pattern was given"
+    );
+    assert_eq!(
+        error(SsrRuleBuilder::new().search("foo()")),
+        "Parse error: No replacement template was given"
+    );
+    assert_eq!(
+        error(SsrRuleBuilder::new().search("foo($a)").replace("bar($b)")),
+        "Parse error: Replacement contains undefined placeholders: $b"
+    );
+    assert_eq!(
+        error(
+            SsrRuleBuilder::new()
+                .search("foo($a)")
+                .replace("bar($a)")
+                .constraint("x", PlaceholderConstraint::AnyLoop)
+        ),
+        "Parse error: Constraint given for `$x`, which isn't in the search pattern"
+    );
+}
+
+#[test]
+fn replace_await_try() {
+    let code =
+        "async fn f() -> Result<(), E> { let a = foo().await?; let b = bar().await; Ok(()) }";
+    assert_ssr_transform(
+        "$e.await? ==>> $e.await.map_err(wrap)?",
+        code,
+        "async fn f() -> Result<(), E> { let a = foo().await.map_err(wrap)?; let b = bar().await; Ok(()) }",
+    );
+    assert_matches("$e.await?", code, &["foo().await?"]);
+}
+
+#[test]
+fn replace_default_type_parameter() {
+    assert_ssr_transform(
+        "struct $n<$t = $d>($f); ==>> struct $n<$t = i64>($f);",
+        "struct A<T = i32>(T); struct B<T>(T);",
+        "struct A<T = i64>(T); struct B<T>(T);",
+    );
+    assert_matches(
+        "struct $n<$t = $d>($f);",
+        "struct B<T>(T); struct C<T = u8>(T);",
+        &["struct C<T = u8>(T);"],
+    );
+}
+
+#[cfg(feature = "proc-macro2")]
+#[test]
+fn replace_in_token_stream() {
+    let rule: SsrRule = "foo($a) ==>> bar($a, 1)".parse().unwrap();
+    let input: proc_macro2::TokenStream = "fn f() { foo(x); baz(y); }".parse().unwrap();
+    let output = crate::apply_to_token_stream(&rule, input).unwrap();
+    let expected: proc_macro2::TokenStream = "fn f() { bar(x, 1); baz(y); }".parse().unwrap();
+    assert_eq!(output.to_string(), expected.to_string());
+}
+
+#[test]
+fn placeholder_text_constraint() {
+    let code = "fn f() { a.get_x(); a.set_x(1); a.forget_x(); a.GET_Y(); }";
+    assert_matches("$o.$m~^get_()", code, &["a.get_x()"]);
+    assert_matches(r#"$o.$m~"(?i)^get_"()"#, code, &["a.get_x()", "a.GET_Y()"]);
+    assert_matches("$o.$m~_x$()", code, &["a.get_x()", "a.forget_x()"]);
+    assert_matches("$o.$m~get()", code, &["a.get_x()", "a.forget_x()"]);
+    assert_ssr_transform(
+        "$o.$m~^get_() ==>> $o.$m().unwrap()",
+        "fn f() { a.get_x(); a.x(); }",
+        "fn f() { a.get_x().unwrap(); a.x(); }",
+    );
+    assert_eq!(
+        parse_error_text("foo($a~) ==>> ()"),
+        "Parse error: Placeholder text constraint `~` must be followed by a pattern"
+    );
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn placeholder_regex_constraint() {
+    let code = "fn f() { a.get_x(); a.get_1(); }";
+    assert_matches(r#"$o.$m~"^get_[a-z]+$"()"#, code, &["a.get_x()"]);
+    assert!(
+        parse_error_text(r#"foo($a~"[") ==>> ()"#).starts_with("Parse error: Invalid regex `[`")
+    );
+}
+
+#[cfg(not(feature = "regex"))]
+#[test]
+fn placeholder_regex_constraint_needs_feature() {
+    assert_eq!(
+        parse_error_text(r#"$o.$m~"^get_[a-z]+$"() ==>> ()"#),
+        "Parse error: Text constraint `^get_[a-z]+$` uses regex syntax, which needs the `regex` \
+         feature"
+    );
+}
+
+#[test]
+fn replace_multi_line_template_with_indentation() {
+    assert_ssr_transform(
+        "foo($a) ==>> if $a {\n    bar();\n}",
+        "fn f() {\n    if x {\n        foo(y);\n    }\n}",
+        "fn f() {\n    if x {\n        if y {\n            bar();\n        };\n    }\n}",
+    );
+    // The second and later lines of a multi-line placeholder value keep their indentation
+    // relative to its first line.
+    assert_ssr_transform(
+        "foo($a) ==>> {\n    let c = $a;\n    bar(c)\n}",
+        "fn f() {\n    if x {\n        foo(|| {\n            1\n        });\n    }\n}",
+        "fn f() {\n    if x {\n        {\n            let c = || {\n                1\n            };\n            bar(c)\n        };\n    }\n}",
+    );
+}
+
+#[test]
+fn replace_inner_attribute() {
+    let code = "#![allow(dead_code)]\n#[allow(dead_code)] fn f() {} mod m { #![allow(unused)] }";
+    assert_ssr_transform(
+        "#![allow($a)] ==>> #![warn($a)]",
+        code,
+        "#![warn(dead_code)]\n#[allow(dead_code)] fn f() {} mod m { #![warn(unused)] }",
+    );
+    assert_matches("#![allow($a)]", code, &["#![allow(dead_code)]", "#![allow(unused)]"]);
+    assert_matches("#[allow($a)]", code, &["#[allow(dead_code)]"]);
+}
+
+#[test]
+fn search_without_template() {
+    let pattern: SsrPattern = "foo($a, $b)".parse().unwrap();
+    let file = ast::SourceFile::parse("fn f() { foo(1, foo(2, x)); bar(3, 4); }").tree();
+    let found: Vec<(TextRange, String, String, String)> = crate::matches(&pattern, file.syntax())
+        .map(|m| {
+            let placeholders = m.placeholder_text();
+            (m.range(), m.matched_text(), placeholders["a"].clone(), placeholders["b"].clone())
+        })
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (
+                TextRange::new(9.into(), 26.into()),
+                "foo(1, foo(2, x))".to_owned(),
+                "1".to_owned(),
+                "foo(2, x)".to_owned()
+            ),
+            (
+                TextRange::new(16.into(), 25.into()),
+                "foo(2, x)".to_owned(),
+                "2".to_owned(),
+                "x".to_owned()
+            ),
+        ]
+    );
+
+    // A node that isn't a whole file is searched in its original context.
+    let file = ast::SourceFile::parse("fn f() { bar(foo(1, 2)); }").tree();
+    let call = file.syntax().descendants().find_map(ast::CallExpr::cast).unwrap();
+    let found: Vec<(TextRange, String)> =
+        crate::matches(&pattern, call.syntax()).map(|m| (m.range(), m.matched_text())).collect();
+    assert_eq!(found, vec![(TextRange::new(13.into(), 22.into()), "foo(1, 2)".to_owned())]);
+}
+
+#[test]
+fn replace_whole_function_body() {
+    assert_ssr_transform(
+        "fn $n() { $body:block } ==>> fn $n() { catch_unwind(|| $body) }",
+        "fn foo() { let x = 1; bar(x) } fn baz(a: i32) {}",
+        "fn foo() { catch_unwind(|| { let x = 1; bar(x) }) } fn baz(a: i32) {}",
+    );
+    assert_matches("fn $n() { $body:block }", "fn foo() {}", &["fn foo() {}"]);
+    assert_matches(
+        "let $x = $b:block;",
+        "fn f() { let a = { 1 }; let b = 2; }",
+        &["let a = { 1 };"],
+    );
+}
+
+#[test]
+fn rule_unused_pattern_placeholders() {
+    fn unused(rule: &str) -> Vec<SmolStr> {
+        rule.parse::<SsrRule>().unwrap().unused_pattern_placeholders()
+    }
+    assert_eq!(unused("foo($a, $b) ==>> bar($a)"), vec!["b"]);
+    assert_eq!(unused("foo($a, $b) ==>> bar($b, $a)"), Vec::<SmolStr>::new());
+    assert_eq!(unused("foo($a, $b, $c) ==>> ()"), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn match_with_exact_whitespace() {
+    let code = "fn f() {\n    let a = [\n        1,\n        2\n    ];\n    let b = [3, 4];\n}";
+    let file = ast::SourceFile::parse(code).tree();
+    let matched = |pattern: &SsrPattern| -> Vec<String> {
+        crate::matches(pattern, file.syntax()).map(|m| m.matched_text()).collect()
+    };
+    let exact = PatternOptions { exact_whitespace: true, ..PatternOptions::default() };
+    let multi_line = SsrPattern::from_str_with_options("[\n$a,\n$b\n]", &exact).unwrap();
+    assert_eq!(matched(&multi_line), vec!["[\n        1,\n        2\n    ]"]);
+    let single_line = SsrPattern::from_str_with_options("[$a, $b]", &exact).unwrap();
+    assert_eq!(matched(&single_line), vec!["[3, 4]"]);
+    // By default, whitespace is ignored.
+    let default: SsrPattern = "[\n$a,\n$b\n]".parse().unwrap();
+    assert_eq!(matched(&default).len(), 2);
+}
+
+#[test]
+fn match_repeated_placeholder() {
+    let code = "fn f() { let a = x == x; let b = x == y; let c = foo(1) == foo( 1 ); }";
+    assert_ssr_transform(
+        "$a == $a ==>> true",
+        code,
+        "fn f() { let a = true; let b = x == y; let c = true; }",
+    );
+    assert_matches("foo($a, $a)", "fn f() { foo(1, 1); foo(1, 2); }", &["foo(1, 1)"]);
+    assert_matches(
+        "(&${l:lifetime} i32, &$l i32)",
+        "fn f(x: (&'a i32, &'a i32), y: (&'a i32, &'b i32)) {}",
+        &["(&'a i32, &'a i32)"],
+    );
+    assert_eq!(
+        parse_error_text("foo($a:*, $a) ==>> ()"),
+        "Parse error: Multi-placeholder `$a` can't be repeated"
+    );
+    assert_eq!(
+        parse_error_text("foo($a, $a:expr) ==>> ()"),
+        "Parse error: Constraints for `$a` must be given where it first appears"
+    );
+}
+
+#[test]
+fn match_slice_pattern_with_subslice_binding() {
+    let code = "fn f(v: &[i32]) { match v { [a, rest @ ..] => {} [a, b] => {} _ => {} } }";
+    assert_matches("[$first, $rest @ ..]", code, &["[a, rest @ ..]"]);
+    assert_matches("[$first, $second]", code, &["[a, b]"]);
+    assert_ssr_transform(
+        "[$first, $rest @ ..] ==>> [$rest @ .., $first]",
+        code,
+        "fn f(v: &[i32]) { match v { [rest @ .., a] => {} [a, b] => {} _ => {} } }",
+    );
+}
+
+#[test]
+fn replace_in_source_text() {
+    let rule: SsrRule = "foo($a) ==>> bar($a)".parse().unwrap();
+    let source = "fn f() { foo(1); foo(foo(2)); baz(3); }";
+    assert_eq!(crate::replace(&rule, source), "fn f() { bar(1); bar(bar(2)); baz(3); }");
+    assert_eq!(
+        crate::try_replace(&rule, source),
+        Ok(("fn f() { bar(1); bar(bar(2)); baz(3); }".to_owned(), 2))
+    );
+    assert_eq!(crate::try_replace(&rule, "fn f() {}"), Ok(("fn f() {}".to_owned(), 0)));
+}
+
+#[test]
+fn rule_match_count() {
+    let source = "fn f() { foo(1); foo(2); bar(foo(3)); }";
+    assert_eq!(crate::assert_match_count("foo($a) ==>> baz($a)", source, 3), Ok(()));
+    assert_eq!(crate::assert_match_count("qux($a) ==>> baz($a)", source, 0), Ok(()));
+    let error = crate::assert_match_count("bar($a) ==>> baz($a)", source, 2).unwrap_err();
+    assert_eq!(error.kind(), SsrErrorKind::MatchCountMismatch);
+    assert_eq!(error.to_string(), "Expected 2 matches of `bar($a) ==>> baz($a)`, but found 1");
+    assert!(crate::assert_match_count("foo($a)", source, 3).is_err());
+}
+
+#[test]
+fn replace_err_constructor() {
+    let code = "fn f() -> Result<(), E> { if a { return Err(e1); } g(Ok(x)); Err(make()) }";
+    assert_ssr_transform(
+        "Err($e) ==>> Err($e.into())",
+        code,
+        "fn f() -> Result<(), E> { if a { return Err(e1.into()); } g(Ok(x)); Err(make().into()) }",
+    );
+    assert_matches("Err($e)", code, &["Err(e1)", "Err(make())"]);
+    assert_matches("Ok(())", "fn f() { return Ok(()); g(Ok(x)); }", &["Ok(())"]);
+    assert_matches(
+        "return Ok(());",
+        "fn f() { return Ok(()); return Ok(x); }",
+        &["return Ok(());"],
+    );
+}
+
+#[test]
+fn match_within_module() {
+    let (db, file_id) = single_file(
+        "fn foo() {} fn a() { foo(); } mod util { fn b() { foo(); } mod fmt { fn c() { foo(); } } } mod other { fn d() { foo(); } }",
+    );
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_search_pattern("foo()".parse().unwrap());
+    let paths = |path: &str| -> Vec<String> {
+        let mut matches = match_finder.find_matches_in_file(file_id);
+        matches.retain_within_module(path);
+        matches.matches.iter().map(|m| m.module_path().join("::")).collect()
+    };
+    assert_eq!(paths("crate"), vec!["", "util", "util::fmt", "other"]);
+    assert_eq!(paths("util"), vec!["util", "util::fmt"]);
+    assert_eq!(paths("crate::util::fmt"), vec!["util::fmt"]);
+    assert_eq!(paths("fmt"), Vec::<String>::new());
+}
+
+#[test]
+fn trace_near_miss() {
+    let file =
+        ast::SourceFile::parse("fn f() { foo(1, 2); bar(1, 3); x.foo(); foo(1, 3); }").tree();
+    let call = |text: &str| {
+        file.syntax()
+            .descendants()
+            .find(|n| ast::Expr::can_cast(n.kind()) && n.text() == text)
+            .unwrap()
+    };
+    let rule: SsrRule = "foo($a, 3) ==>> bar($a)".parse().unwrap();
+    assert_eq!(crate::trace_match(&rule, &call("foo(1, 3)")), crate::MatchTrace::Matched);
+    let diverged_kind = |text: &str| match crate::trace_match(&rule, &call(text)) {
+        crate::MatchTrace::Diverged { kind, reason } => (kind, reason),
+        crate::MatchTrace::Matched => panic!("`{}` unexpectedly matched", text),
+    };
+    assert_eq!(
+        diverged_kind("foo(1, 2)"),
+        (
+            crate::MismatchKind::Token,
+            "Pattern wanted token '3' (INT_NUMBER), but code had token '2' (INT_NUMBER)".to_owned()
+        )
+    );
+    assert_eq!(diverged_kind("bar(1, 3)").0, crate::MismatchKind::Token);
+    assert_eq!(diverged_kind("x.foo()").0, crate::MismatchKind::Kind);
+}
+
+#[test]
+fn replace_statements_in_block() {
+    // With `block_statements`, a placeholder that's all there is within a block matches all of the
+    // block's statements.
+    let options = PatternOptions { block_statements: true, ..PatternOptions::default() };
+    let find = |pattern: &str, code: &str| -> Vec<String> {
+        let (db, file_id) = single_file(code);
+        let mut match_finder = MatchFinder::new(&db);
+        let rule = format!("{} ==>> ()", pattern);
+        match_finder.add_rule(SsrRule::from_str_with_options(&rule, &options).unwrap());
+        match_finder
+            .find_matches_in_file(file_id)
+            .flattened()
+            .matches
+            .iter()
+            .map(|m| m.matched_text())
+            .collect()
+    };
+    let code = "fn f() { if x { a(); let y = 1;\n    b(y); } }";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    let rule = SsrRule::from_str_with_options("if $c { $body } ==>> if !$c { $body }", &options);
+    match_finder.add_rule(rule.unwrap());
+    let mut after = code.to_owned();
+    match_finder.edits_for_file(file_id).unwrap().unwrap().apply(&mut after);
+    assert_eq!(after, "fn f() { if !x { a(); let y = 1;\n    b(y); } }");
+    assert_eq!(find("if $c { $body }", "fn f() { if x {} }"), vec!["if x {}"]);
+    // Placeholders with constraints still match just a single expression.
+    assert_eq!(
+        find("if $c { ${body:not(a())} }", "fn f() { if x { 1 } if x { a(); 1 } }"),
+        vec!["if x { 1 }"]
+    );
+    // Without it, the placeholder only matches a block that's just an expression.
+    assert_matches("if $c { $body }", "fn f() { if x { 1 } if y { a(); b(); } }", &["if x { 1 }"]);
+}
+
+#[test]
+fn replace_use_declarations() {
+    assert_ssr_transform(
+        "use foo::bar::$x; ==>> use foo::baz::$x;",
+        "use foo::bar::Thing;\nuse foo::bar::a::b;\nuse foo::bar::{c, d};\nuse foo::bar::e as f;\n\
+         use foo::bar::*;\nuse foo::other::g;\nuse foo::bar;\n",
+        "use foo::baz::Thing;\nuse foo::baz::a::b;\nuse foo::baz::{c, d};\nuse foo::baz::e as f;\n\
+         use foo::baz::*;\nuse foo::other::g;\nuse foo::bar;\n",
+    );
+    assert_ssr_transform(
+        "use foo::{$x:*}; ==>> use bar::{$x:*};",
+        "use foo::{a, b::c};\nuse foo::d;\n",
+        "use bar::{a, b::c};\nuse foo::d;\n",
+    );
+    // A placeholder with a constraint still only matches a single segment.
+    assert_matches(
+        "use foo::$x:ident;",
+        "use foo::a;\nuse foo::a::b;\nuse foo::{c, d};\n",
+        &["use foo::a;"],
+    );
+}
+
+#[test]
+fn search_deeply_nested_code() {
+    // The parser needs more stack than tests get by default for code this deep, but searching it
+    // must stop at the depth limit rather than going all the way down.
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(search_deeply_nested_code_in_thread)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn search_deeply_nested_code_in_thread() {
+    // `foo(2)` is 10,000 binary expressions deep, which is too deep to search.
+    let code = format!("fn f() {{ foo(1); bar(foo(2){}); }}", " + 1".repeat(10_000));
+    let (db, file_id) = single_file(&code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo($a) ==>> baz($a)".parse().unwrap());
+    let matches = match_finder.find_matches_in_file(file_id);
+    let matched: Vec<String> = matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["foo(1)"]);
+    assert!(!matches.errors().is_empty());
+    assert!(matches.errors().iter().all(|e| e.kind() == SsrErrorKind::TooDeep));
+    // Errors found while searching aren't parse errors.
+    assert!(matches.errors().iter().all(|e| !e.to_string().starts_with("Parse error")));
+
+    match_finder.set_match_options(MatchOptions { max_depth: Some(2), ..MatchOptions::default() });
+    let matches = match_finder.find_matches_in_file(file_id);
+    assert!(matches.matches.is_empty());
+    assert!(!matches.errors().is_empty());
+
+    let (db, file_id) = single_file("fn f() { foo(1); bar(foo(2) + 1 + 1); }");
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo($a) ==>> baz($a)".parse().unwrap());
+    let matches = match_finder.find_matches_in_file(file_id);
+    assert_eq!(matches.matches.len(), 2);
+    assert!(matches.errors().is_empty());
+
+    // Code that's shallow enough to search, but too deep to match against the pattern.
+    let code = "const C: i32 = foo(((((1)))));";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("foo(((((1))))) ==>> 1".parse().unwrap());
+    match_finder.set_match_options(MatchOptions { max_depth: Some(4), ..MatchOptions::default() });
+    let matches = match_finder.find_matches_in_file(file_id);
+    assert!(matches.matches.is_empty());
+    let errors: Vec<(SsrErrorKind, &str, Option<TextRange>)> =
+        matches.errors().iter().map(|e| (e.kind(), e.message(), e.range())).collect();
+    assert!(errors.contains(&(
+        SsrErrorKind::TooDeep,
+        "Code is nested more than 4 levels deep, so wasn't fully matched",
+        Some(TextRange::new(15.into(), 29.into()))
+    )));
+}
+
+#[test]
+fn replace_with_computed_substitutions() {
+    let code = "fn f() { a(deg(180.0)); b(deg(x)); }";
+    let (db, file_id) = single_file(code);
+    let mut match_finder = MatchFinder::new(&db);
+    match_finder.add_rule("deg($x) ==>> rad($x)".parse().unwrap());
+    let edit = match_finder
+        .edits_for_file_with(file_id, |name, m| {
+            let degrees: f64 = m.placeholder_text()[name].parse().ok()?;
+            Some(format!("{}_f64", degrees.to_radians()))
+        })
+        .unwrap()
+        .unwrap();
+    let mut after = code.to_string();
+    edit.apply(&mut after);
+    assert_eq!(after, "fn f() { a(rad(3.141592653589793_f64)); b(rad(x)); }");
+}
+
+#[test]
+fn search_several_files() {
+    use ra_db::fixture::WithFixture;
+    use ra_db::SourceDatabase;
+    let db = ra_ide_db::RootDatabase::with_files(
+        r#"
+        //- /lib.rs
+        mod b; mod c;
+        fn f() { foo(1); foo(foo(2)); }
+        //- /b.rs
+        fn g() { bar(3); }
+        //- /c.rs
+        macro_rules! m { ($e:expr) => { $e } }
+        fn h() { m!(foo(4)); }
+        "#,
+    );
+    let files: Vec<(FileId, SyntaxNode)> =
+        (0..3).map(|i| (FileId(i), db.parse(FileId(i)).tree().syntax().clone())).collect();
+    let rule: SsrRule = "foo($a) ==>> baz($a)".parse().unwrap();
+    let results = crate::search_files(&db, &rule, &files);
+    let matched = |file_id: u32| -> Vec<String> {
+        results[&FileId(file_id)].matches.iter().map(|m| m.matched_text()).collect()
+    };
+    assert_eq!(results.len(), 3);
+    assert_eq!(matched(0), vec!["foo(1)", "foo(foo(2))"]);
+    assert_eq!(
+        results[&FileId(0)].matches[1]
+            .placeholder_values
+            .values()
+            .next()
+            .unwrap()
+            .inner_matches
+            .matches
+            .len(),
+        1
+    );
+    assert!(matched(1).is_empty());
+    assert_eq!(matched(2), vec!["foo(4)"]);
+}
+
+#[test]
+fn replace_method_call_receiver_chain() {
+    // A placeholder for the receiver matches the whole of the chain before the last call.
+    assert_ssr_transform(
+        "$chain.bar() ==>> $chain.baz()",
+        "fn f() { a.b().c().bar(); x.bar().bar(); }",
+        "fn f() { a.b().c().baz(); x.baz().baz(); }",
+    );
+    // Values that would otherwise bind differently become parenthesized receivers.
+    assert_ssr_transform(
+        "foo($a) ==>> $a.baz()?",
+        "fn f() { foo(x + y); foo(-x); foo(&x); foo(x.y()); foo((x + y)); }",
+        "fn f() { (x + y).baz()?; (-x).baz()?; (&x).baz()?; x.y().baz()?; (x + y).baz()?; }",
+    );
+    assert_ssr_transform(
+        "foo($a) ==>> bar($a)",
+        "fn f() { foo(x + y); }",
+        "fn f() { bar(x + y); }",
+    );
+}
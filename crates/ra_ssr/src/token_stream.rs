@@ -0,0 +1,16 @@
+//! Applying rules to a `proc_macro2::TokenStream`, so that they can be used from within procedural
+//! macros.
+
+use crate::{apply_to_source, SsrError, SsrRule};
+use proc_macro2::TokenStream;
+
+/// Applies `rule` to `input`, which is converted to source text and treated as the only file in its
+/// crate. It should therefore be a sequence of items, such as the input to a derive or attribute
+/// macro. The rewritten source is converted back into tokens. Spans aren't preserved, so all of the
+/// output tokens have the call site span.
+pub fn apply_to_token_stream(rule: &SsrRule, input: TokenStream) -> Result<TokenStream, SsrError> {
+    let output = apply_to_source(rule.clone(), &input.to_string())?;
+    output
+        .parse()
+        .map_err(|e| SsrError::new(format!("Failed to convert output to tokens: {:?}", e)))
+}
@@ -26,6 +26,9 @@ impl FileSet {
         self.files.insert(path.clone(), file_id);
         self.paths.insert(file_id, path);
     }
+    pub fn path_for_file(&self, file: &FileId) -> Option<&VfsPath> {
+        self.paths.get(file)
+    }
     pub fn iter(&self) -> impl Iterator<Item = FileId> + '_ {
         self.paths.keys().copied()
     }